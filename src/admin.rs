@@ -0,0 +1,1004 @@
+use crate::auth::{hash_key, ApiKey};
+use crate::config::Config;
+use crate::error::{json_body, AppError};
+use crate::gcp::{
+    bq_rest_request, bq_rows_to, dataset_and_table, default_location, handle_bq_query_req,
+    handle_bq_script_req, table_schema_fields, QueryOptions, QueryParameter,
+    NEXT_PAGE_TOKEN_HEADER, PARAMETER_MODE_NAMED, PARAMETER_MODE_POSITIONAL,
+};
+use crate::health::check_config;
+use crate::mtls::require_client_certificate;
+use crate::quota::{quota_state, QuotaState};
+use crate::router::Params;
+use crate::rows::records_response;
+use crate::stats;
+use crate::token::gcp_access_token_request;
+use fastly::http::{Method, StatusCode};
+use fastly::kv_store::{KVStore, KVStoreError};
+use fastly::{Error, Request, Response};
+use log::error;
+use rand::RngCore;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+pub const ADMIN_KEY_HEADER: &str = "X-Admin-Key";
+pub const API_KEY_STORE: &str = "api_keys";
+
+// Managed API keys live in the `api_keys` KV Store. Each key has a record under
+// `key/{name}` and an index entry `sha256/{hash}` pointing back at the name, so
+// lookups by the presented key never need the plaintext.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ApiKeyRecord {
+    pub name: String,
+    pub key_sha256: String,
+    pub tier: Option<String>,
+    pub dma_ids: Option<Vec<i64>>,
+    pub created_at: i64,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct CreateKeyReq {
+    name: String,
+    tier: Option<String>,
+    dma_ids: Option<Vec<i64>>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct CreateDatasetReq {
+    dataset_id: Option<String>,
+    location: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct CreateTableReq {
+    table_id: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+struct GrantReq {
+    member: String,
+    role: Option<String>,
+}
+
+// Role granted by POST /admin/tables/{id}/iam/grant unless another is given.
+const DEFAULT_GRANT_ROLE: &str = "roles/bigquery.dataViewer";
+
+#[derive(serde::Deserialize, Debug)]
+struct LoadReq {
+    source_uris: Vec<String>,
+    table_id: Option<String>,
+    write_disposition: Option<String>,
+}
+
+// BigQuery load source format for a file extension.
+fn source_format(uri: &str) -> Option<&'static str> {
+    let extension = uri.rsplit('.').next()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "csv" => Some("CSV"),
+        "json" | "jsonl" | "ndjson" => Some("NEWLINE_DELIMITED_JSON"),
+        "avro" => Some("AVRO"),
+        "parquet" => Some("PARQUET"),
+        "orc" => Some("ORC"),
+        _ => None,
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct ExportReq {
+    format: Option<String>,
+    table_id: Option<String>,
+    query: Option<String>,
+}
+
+// JSON body of POST /admin/query, for scripts with query parameters.
+#[derive(serde::Deserialize, Debug)]
+struct QueryReq {
+    query: String,
+    #[serde(default)]
+    params: Vec<QueryParameter>,
+    // NAMED (default) or POSITIONAL.
+    parameter_mode: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct RefreshReq {
+    view: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+struct SnapshotReq {
+    table_id: Option<String>,
+    snapshot_id: Option<String>,
+    expiration_hours: Option<i64>,
+}
+
+const MAX_KEY_NAME_LEN: usize = 64;
+
+// Names are part of KV keys and end up in logs and job labels.
+fn valid_key_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_KEY_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+}
+
+fn generate_key() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+pub fn open_key_store() -> Result<Option<KVStore>, Error> {
+    Ok(KVStore::open(API_KEY_STORE)?)
+}
+
+fn key_store() -> Result<KVStore, Error> {
+    match open_key_store() {
+        Ok(Some(x)) => Ok(x),
+        Ok(None) => {
+            let msg = format!("KV Store `{}` is not linked to this service", API_KEY_STORE);
+            Err(AppError::Config(msg).into())
+        }
+        Err(e) => {
+            let msg = format!("KV Store `{}` open error: {}", API_KEY_STORE, e);
+            Err(AppError::Config(msg).into())
+        }
+    }
+}
+
+pub fn lookup_record(store: &KVStore, key_sha256: &str) -> Result<Option<ApiKeyRecord>, Error> {
+    let name = match store.lookup(&format!("sha256/{}", key_sha256)) {
+        Ok(mut x) => String::from_utf8(x.take_body_bytes())?,
+        Err(KVStoreError::ItemNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    load_record(store, &name)
+}
+
+fn load_record(store: &KVStore, name: &str) -> Result<Option<ApiKeyRecord>, Error> {
+    match store.lookup(&format!("key/{}", name)) {
+        Ok(mut x) => Ok(Some(serde_json::from_slice(&x.take_body_bytes())?)),
+        Err(KVStoreError::ItemNotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn list_records(store: &KVStore) -> Result<Vec<ApiKeyRecord>, Error> {
+    let mut records = Vec::new();
+    for page in store.build_list().prefix("key/").iter() {
+        for entry in page?.into_keys() {
+            if let Some(record) = load_record(store, entry.trim_start_matches("key/"))? {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn save_record(store: &KVStore, record: &ApiKeyRecord) -> Result<(), Error> {
+    store.insert(
+        &format!("key/{}", record.name),
+        serde_json::to_string(record)?,
+    )?;
+    store.insert(
+        &format!("sha256/{}", record.key_sha256),
+        record.name.to_string(),
+    )?;
+    Ok(())
+}
+
+// Issued keys are only ever returned once, in the create/rotate response.
+fn issued_key_response(record: &ApiKeyRecord, key: &str) -> Result<Response, Error> {
+    let body = serde_json::json!({
+        "name": record.name,
+        "key": key,
+        "tier": record.tier,
+        "dma_ids": record.dma_ids,
+    });
+    Ok(Response::from_status(StatusCode::CREATED).with_body_json(&body)?)
+}
+
+pub fn authorize_admin(tomlfile: &Config, req: &Request) -> Result<(), Error> {
+    require_client_certificate(tomlfile, req)?;
+    let admin = match &tomlfile.admin {
+        Some(x) => x,
+        None => return Err(AppError::Forbidden("Admin API is not configured".to_string()).into()),
+    };
+    let presented = req.get_header_str(ADMIN_KEY_HEADER).unwrap_or("");
+    if !hash_key(presented).eq_ignore_ascii_case(&admin.key_sha256) {
+        let msg = format!("Missing or invalid {} header", ADMIN_KEY_HEADER);
+        return Err(AppError::Unauthorized(msg).into());
+    }
+    Ok(())
+}
+
+// Routes:
+//   POST   /admin/keys              create a key
+//   GET    /admin/keys              list keys
+//   POST   /admin/keys/{name}/rotate issue a new key, revoking the old one
+//   DELETE /admin/keys/{name}       revoke a key
+pub fn handle_keys_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let store = key_store()?;
+
+    match (req.get_method(), params.get("name")) {
+        (&Method::POST, None) => {
+            let create: CreateKeyReq = json_body(req)?;
+            if !valid_key_name(&create.name) {
+                let msg = format!(
+                    "API key name `{}` must be 1 to {} letters, digits, `_`, `.` or `-`",
+                    create.name, MAX_KEY_NAME_LEN
+                );
+                return Err(AppError::BadRequest(msg).into());
+            }
+            if load_record(&store, &create.name)?.is_some() {
+                let msg = format!("API key `{}` already exists", create.name);
+                error!("{}", msg);
+                return Ok(Response::from(AppError::Conflict(msg)));
+            }
+            let key = generate_key();
+            let record = ApiKeyRecord {
+                name: create.name,
+                key_sha256: hash_key(&key),
+                tier: create.tier,
+                dma_ids: create.dma_ids,
+                created_at: OffsetDateTime::now_utc().unix_timestamp(),
+            };
+            save_record(&store, &record)?;
+            issued_key_response(&record, &key)
+        }
+        (&Method::GET, None) => {
+            let keys: Vec<serde_json::Value> = list_records(&store)?
+                .into_iter()
+                .map(|record| {
+                    serde_json::json!({
+                        "name": record.name,
+                        "tier": record.tier,
+                        "dma_ids": record.dma_ids,
+                        "created_at": record.created_at,
+                    })
+                })
+                .collect();
+            Ok(Response::from_status(StatusCode::OK).with_body_json(&keys)?)
+        }
+        (&Method::POST, Some(name)) => {
+            let mut record = match load_record(&store, name)? {
+                Some(x) => x,
+                None => {
+                    let msg = format!("API key `{}` does not exist", name);
+                    return Err(AppError::NotFound(msg).into());
+                }
+            };
+            store.delete(&format!("sha256/{}", record.key_sha256))?;
+            let key = generate_key();
+            record.key_sha256 = hash_key(&key);
+            record.created_at = OffsetDateTime::now_utc().unix_timestamp();
+            save_record(&store, &record)?;
+            issued_key_response(&record, &key)
+        }
+        (&Method::DELETE, Some(name)) => {
+            let record = match load_record(&store, name)? {
+                Some(x) => x,
+                None => {
+                    let msg = format!("API key `{}` does not exist", name);
+                    return Err(AppError::NotFound(msg).into());
+                }
+            };
+            store.delete(&format!("sha256/{}", record.key_sha256))?;
+            store.delete(&format!("key/{}", record.name))?;
+            Ok(Response::from_status(StatusCode::NO_CONTENT))
+        }
+        (method, _) => {
+            let msg = format!("No route for {} {}", method, req.get_path());
+            Err(AppError::NotFound(msg).into())
+        }
+    }
+}
+
+// Relays a BigQuery admin call's status and JSON body to the client.
+fn bq_admin_response(mut bqresp: Response) -> Response {
+    let status = bqresp.get_status();
+    let body = bqresp.take_body();
+    Response::from_status(status)
+        .with_header("Content-Type", "application/json")
+        .with_body(body)
+}
+
+// Routes:
+//   POST   /admin/datasets       create a dataset, by default the configured one
+//   GET    /admin/datasets       list datasets of the project
+//   DELETE /admin/datasets/{id}  delete a dataset; `?delete_contents=true` also
+//                                drops its tables
+pub fn handle_datasets_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+
+    match (req.get_method(), params.get("id")) {
+        (&Method::POST, None) => {
+            let create = if req.has_body() {
+                json_body::<CreateDatasetReq>(req)?
+            } else {
+                CreateDatasetReq::default()
+            };
+            let dataset_id = match create.dataset_id {
+                Some(x) => x,
+                None => dataset_and_table(&tomlfile)?.0.to_string(),
+            };
+            let body = serde_json::json!({
+                "datasetReference": {
+                    "projectId": tomlfile.bigquery.projectid,
+                    "datasetId": dataset_id,
+                },
+                "location": create
+                    .location
+                    .unwrap_or_else(|| default_location(&tomlfile).to_string()),
+                "description": create.description,
+            });
+            let bqresp = bq_rest_request(&tomlfile, Method::POST, "datasets", Some(&body))?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::GET, None) => {
+            let bqresp = bq_rest_request(&tomlfile, Method::GET, "datasets?all=true", None)?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::DELETE, Some(dataset_id)) => {
+            let delete_contents = req.get_query_parameter("delete_contents") == Some("true");
+            let resource = format!(
+                "datasets/{}?deleteContents={}",
+                urlencoding::encode(dataset_id),
+                delete_contents
+            );
+            let bqresp = bq_rest_request(&tomlfile, Method::DELETE, &resource, None)?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (method, _) => {
+            let msg = format!("No route for {} {}", method, req.get_path());
+            Err(AppError::NotFound(msg).into())
+        }
+    }
+}
+
+// `datasets/{dataset}/tables/{table}` for a `table` or `dataset.table` id;
+// bare table ids live in the configured dataset.
+fn table_resource(tomlfile: &Config, id: &str) -> Result<String, Error> {
+    let (dataset_id, table_id) = match id.split_once('.') {
+        Some(x) => x,
+        None => (dataset_and_table(tomlfile)?.0, id),
+    };
+    Ok(format!(
+        "datasets/{}/tables/{}",
+        urlencoding::encode(dataset_id),
+        urlencoding::encode(table_id)
+    ))
+}
+
+// Routes:
+//   POST   /admin/tables       create a table with the configured schema, by
+//                              default the configured table
+//   PATCH  /admin/tables/{id}  replace a table's schema with the configured one;
+//                              BigQuery only allows adding columns and relaxing
+//                              REQUIRED to NULLABLE
+//   DELETE /admin/tables/{id}  delete a table
+//   GET    /admin/tables/{id}/iam        the table's IAM policy
+//   PUT    /admin/tables/{id}/iam        replace it with the policy in the body
+//   POST   /admin/tables/{id}/iam/grant  add `member` to `role` (default
+//                                        roles/bigquery.dataViewer)
+pub fn handle_tables_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+
+    match (req.get_method(), params.get("id")) {
+        (&Method::POST, None) => {
+            let create = if req.has_body() {
+                json_body::<CreateTableReq>(req)?
+            } else {
+                CreateTableReq::default()
+            };
+            let (dataset_id, default_table_id) = dataset_and_table(&tomlfile)?;
+            let mut dataset_id = dataset_id.to_string();
+            let table_id = match create.table_id.as_deref().map(|x| x.split_once('.')) {
+                Some(Some((dataset, table))) => {
+                    dataset_id = dataset.to_string();
+                    table.to_string()
+                }
+                Some(None) => create.table_id.unwrap_or_default(),
+                None => default_table_id.to_string(),
+            };
+            let body = serde_json::json!({
+                "tableReference": {
+                    "projectId": tomlfile.bigquery.projectid,
+                    "datasetId": dataset_id,
+                    "tableId": table_id,
+                },
+                "description": create.description,
+                "schema": { "fields": table_schema_fields(&tomlfile)? },
+            });
+            let resource = format!("datasets/{}/tables", urlencoding::encode(&dataset_id));
+            let bqresp = bq_rest_request(&tomlfile, Method::POST, &resource, Some(&body))?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::PATCH, Some(id)) => {
+            let body = serde_json::json!({
+                "schema": { "fields": table_schema_fields(&tomlfile)? },
+            });
+            let resource = table_resource(&tomlfile, id)?;
+            let bqresp = bq_rest_request(&tomlfile, Method::PATCH, &resource, Some(&body))?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::DELETE, Some(id)) => {
+            let resource = table_resource(&tomlfile, id)?;
+            let bqresp = bq_rest_request(&tomlfile, Method::DELETE, &resource, None)?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::GET, Some(id)) => {
+            let resource = format!("{}:getIamPolicy", table_resource(&tomlfile, id)?);
+            let bqresp = bq_rest_request(&tomlfile, Method::POST, &resource, None)?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::PUT, Some(id)) => {
+            let policy = json_body::<serde_json::Value>(req)?;
+            let body = serde_json::json!({ "policy": policy });
+            let resource = format!("{}:setIamPolicy", table_resource(&tomlfile, id)?);
+            let bqresp = bq_rest_request(&tomlfile, Method::POST, &resource, Some(&body))?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (&Method::POST, Some(id)) => {
+            let grant = json_body::<GrantReq>(req)?;
+            let role = grant.role.as_deref().unwrap_or(DEFAULT_GRANT_ROLE);
+            let resource = table_resource(&tomlfile, id)?;
+            let mut bqresp = bq_rest_request(
+                &tomlfile,
+                Method::POST,
+                &format!("{}:getIamPolicy", resource),
+                None,
+            )?;
+            if !bqresp.get_status().is_success() {
+                return Ok(bq_admin_response(bqresp));
+            }
+            // The policy keeps its etag, so a concurrent change makes
+            // setIamPolicy fail instead of being overwritten.
+            let mut policy = bqresp.take_body_json::<serde_json::Value>()?;
+            let mut bindings = policy["bindings"].as_array().cloned().unwrap_or_default();
+            match bindings.iter_mut().find(|x| x["role"] == role) {
+                Some(binding) => {
+                    let mut members = binding["members"].as_array().cloned().unwrap_or_default();
+                    if !members.iter().any(|x| *x == grant.member.as_str()) {
+                        members.push(serde_json::Value::from(grant.member.as_str()));
+                    }
+                    binding["members"] = serde_json::Value::from(members);
+                }
+                None => bindings.push(serde_json::json!({
+                    "role": role,
+                    "members": [grant.member],
+                })),
+            }
+            policy["bindings"] = serde_json::Value::from(bindings);
+            let body = serde_json::json!({ "policy": policy });
+            let bqresp = bq_rest_request(
+                &tomlfile,
+                Method::POST,
+                &format!("{}:setIamPolicy", resource),
+                Some(&body),
+            )?;
+            Ok(bq_admin_response(bqresp))
+        }
+        (method, _) => {
+            let msg = format!("No route for {} {}", method, req.get_path());
+            Err(AppError::NotFound(msg).into())
+        }
+    }
+}
+
+// POST /admin/query: runs the SQL script in the body, e.g. several statements
+// separated by `;`. A JSON body, `{"query": .., "params": [..]}`, binds query
+// parameters in BigQuery's layout, including ARRAY and STRUCT ones, by name or
+// with `"parameter_mode": "POSITIONAL"` to `?` placeholders in order.
+// Responds with the last SELECT's rows and the status of every statement.
+pub fn handle_query_req(req: &mut Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let is_json = req
+        .get_content_type()
+        .is_some_and(|x| x.essence_str() == "application/json");
+    let (script, params, parameter_mode) = if is_json {
+        match req.take_body_json::<QueryReq>() {
+            Ok(x) => (x.query, x.params, x.parameter_mode),
+            Err(e) => {
+                let msg = format!("Request body is not a valid query: {}", e);
+                error!("{}", msg);
+                return Ok(Response::from(AppError::BadRequest(msg)));
+            }
+        }
+    } else {
+        (req.take_body_str(), Vec::new(), None)
+    };
+    let positional_params = match parameter_mode.as_deref().map(|x| x.to_ascii_uppercase()) {
+        None => false,
+        Some(x) if x == PARAMETER_MODE_NAMED => false,
+        Some(x) if x == PARAMETER_MODE_POSITIONAL => true,
+        Some(x) => {
+            let msg = format!(
+                "Unsupported parameter_mode `{}`, use NAMED or POSITIONAL",
+                x
+            );
+            error!("{}", msg);
+            return Ok(Response::from(AppError::BadRequest(msg)));
+        }
+    };
+    let options = QueryOptions {
+        positional_params,
+        ..Default::default()
+    };
+    if script.trim().is_empty() {
+        let msg = "Request body must contain the SQL to run";
+        error!("{}", msg);
+        return Ok(Response::from(AppError::BadRequest(msg.to_string())));
+    }
+    let bqresp_json = handle_bq_script_req(&tomlfile, &script, &params, &options)?;
+    if bqresp_json["jobComplete"] == false {
+        let body = serde_json::json!({
+            "jobComplete": false,
+            "jobReference": bqresp_json["jobReference"],
+        });
+        return Ok(Response::from_status(StatusCode::ACCEPTED).with_body_json(&body)?);
+    }
+    let body = serde_json::json!({
+        "jobComplete": true,
+        "rows": bq_rows_to::<serde_json::Value>(&bqresp_json)?,
+        "statements": bqresp_json["statements"],
+    });
+    Ok(Response::from_status(StatusCode::OK).with_body_json(&body)?)
+}
+
+// POST /admin/load: starts a load job for files already in GCS, e.g.
+// {"source_uris": ["gs://bucket/backfill/*.csv"]}. The source format follows
+// from the file extension and must be the same for every URI; rows are
+// appended to the configured table unless `table_id`/`write_disposition` say
+// otherwise. Responds with the created job.
+pub fn handle_load_req(req: &mut Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let load: LoadReq = json_body(req)?;
+    let formats: Vec<Option<&str>> = load.source_uris.iter().map(|x| source_format(x)).collect();
+    let format = match formats.first() {
+        Some(Some(x))
+            if !load.source_uris.iter().any(|x| !x.starts_with("gs://"))
+                && formats.iter().all(|f| f == &Some(*x)) =>
+        {
+            *x
+        }
+        _ => {
+            let msg = "`source_uris` must be gs:// URIs sharing one of the extensions .csv, .json, .jsonl, .ndjson, .avro, .parquet or .orc";
+            error!("{}", msg);
+            return Ok(Response::from(AppError::BadRequest(msg.to_string())));
+        }
+    };
+    let (dataset_id, default_table_id) = dataset_and_table(&tomlfile)?;
+    let (dataset_id, table_id) = match load.table_id.as_deref() {
+        Some(x) => x.split_once('.').unwrap_or((dataset_id, x)),
+        None => (dataset_id, default_table_id),
+    };
+    let mut configuration = serde_json::json!({
+        "sourceUris": load.source_uris,
+        "sourceFormat": format,
+        "destinationTable": {
+            "projectId": tomlfile.bigquery.projectid,
+            "datasetId": dataset_id,
+            "tableId": table_id,
+        },
+        "writeDisposition": load.write_disposition.unwrap_or_else(|| "WRITE_APPEND".to_string()),
+    });
+    if format == "CSV" {
+        configuration["skipLeadingRows"] = serde_json::Value::from(1);
+    }
+    let body = serde_json::json!({
+        "jobReference": { "location": default_location(&tomlfile) },
+        "configuration": { "load": configuration },
+    });
+    let bqresp = bq_rest_request(&tomlfile, Method::POST, "jobs", Some(&body))?;
+    Ok(bq_admin_response(bqresp))
+}
+
+// POST /admin/export: writes the configured table (or `table_id`) or the
+// result of `query` to the `[export]` bucket as CSV (default) or Avro. The job
+// runs asynchronously; the response carries its reference and the destination
+// URI pattern, which BigQuery shards into one or more files.
+pub fn handle_export_req(req: &mut Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let export_config = match &tomlfile.export {
+        Some(x) => x,
+        None => {
+            let msg = "Exports are not configured, add an [export] section";
+            error!("{}", msg);
+            return Ok(Response::from(AppError::NotFound(msg.to_string())));
+        }
+    };
+    let export = if req.has_body() {
+        json_body::<ExportReq>(req)?
+    } else {
+        ExportReq::default()
+    };
+    let (format, extension) = match export.format.as_deref().map(|x| x.to_ascii_uppercase()) {
+        None => ("CSV", "csv"),
+        Some(x) if x == "CSV" => ("CSV", "csv"),
+        Some(x) if x == "AVRO" => ("AVRO", "avro"),
+        Some(x) => {
+            let msg = format!("Unsupported export format `{}`, use CSV or AVRO", x);
+            error!("{}", msg);
+            return Ok(Response::from(AppError::BadRequest(msg)));
+        }
+    };
+    let (dataset_id, default_table_id) = dataset_and_table(&tomlfile)?;
+    let (dataset_id, table_id) = match export.table_id.as_deref() {
+        Some(x) => x.split_once('.').unwrap_or((dataset_id, x)),
+        None => (dataset_id, default_table_id),
+    };
+    let name = if export.query.is_some() {
+        "query"
+    } else {
+        table_id
+    };
+    let destination_uri = format!(
+        "gs://{}/{}{}-{}-*.{}",
+        export_config.bucket,
+        export_config.prefix.as_deref().unwrap_or(""),
+        name,
+        OffsetDateTime::now_utc().unix_timestamp(),
+        extension
+    );
+    let configuration = match &export.query {
+        // EXPORT DATA is the only way to extract a query result without a
+        // staging table.
+        Some(query) => serde_json::json!({
+            "query": {
+                "query": format!(
+                    "EXPORT DATA OPTIONS (uri = '{}', format = '{}'{}) AS {}",
+                    destination_uri,
+                    format,
+                    if format == "CSV" { ", header = true" } else { "" },
+                    query
+                ),
+                "useLegacySql": false,
+            }
+        }),
+        None => serde_json::json!({
+            "extract": {
+                "sourceTable": {
+                    "projectId": tomlfile.bigquery.projectid,
+                    "datasetId": dataset_id,
+                    "tableId": table_id,
+                },
+                "destinationUris": [destination_uri],
+                "destinationFormat": format,
+            }
+        }),
+    };
+    let body = serde_json::json!({
+        "jobReference": { "location": default_location(&tomlfile) },
+        "configuration": configuration,
+    });
+    let mut bqresp = bq_rest_request(&tomlfile, Method::POST, "jobs", Some(&body))?;
+    if !bqresp.get_status().is_success() {
+        return Ok(bq_admin_response(bqresp));
+    }
+    let job = bqresp.take_body_json::<serde_json::Value>()?;
+    let body = serde_json::json!({
+        "jobReference": job["jobReference"],
+        "status": job["status"],
+        "destination_uris": [destination_uri],
+    });
+    let records = [body.clone()];
+    records_response(req.get_header_str("Accept"), &body, &records)
+}
+
+// POST /admin/refresh: refreshes the configured materialized view (or `view`,
+// as "dataset.view") with BQ.REFRESH_MATERIALIZED_VIEW, so GETs served from it
+// see current data. Answers 202 with the job if the refresh outlasts the query
+// timeout.
+pub fn handle_refresh_req(req: &mut Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let refresh = if req.has_body() {
+        json_body::<RefreshReq>(req)?
+    } else {
+        RefreshReq::default()
+    };
+    let view = match refresh
+        .view
+        .as_deref()
+        .or(tomlfile.bigquery.materialized_view.as_deref())
+    {
+        Some(x) => format!("{}.{}", tomlfile.bigquery.projectid, x),
+        None => {
+            let msg = "No materialized view given, set materialized_view under [bigquery]";
+            error!("{}", msg);
+            return Ok(Response::from(AppError::NotFound(msg.to_string())));
+        }
+    };
+    let params = [QueryParameter::new("view", "STRING", &view)];
+    let options = QueryOptions {
+        use_legacy_sql: Some(false),
+        ..Default::default()
+    };
+    let bqresp_json = handle_bq_query_req(
+        &tomlfile,
+        "CALL BQ.REFRESH_MATERIALIZED_VIEW(@view)",
+        &params,
+        &options,
+    )?;
+    let body = serde_json::json!({
+        "view": view,
+        "jobComplete": bqresp_json["jobComplete"],
+        "jobReference": bqresp_json["jobReference"],
+    });
+    let status = if bqresp_json["jobComplete"] == false {
+        StatusCode::ACCEPTED
+    } else {
+        StatusCode::OK
+    };
+    Ok(Response::from_status(status).with_body_json(&body)?)
+}
+
+// POST /admin/snapshot: copies the configured table (or `table_id`) into a
+// read-only snapshot, `{table}_snapshot_{unix time}` unless `snapshot_id` is
+// given, so it can be restored after a destructive DELETE or load. Snapshots
+// expire after `expiration_hours` when set.
+pub fn handle_snapshot_req(req: &mut Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let snapshot = if req.has_body() {
+        json_body::<SnapshotReq>(req)?
+    } else {
+        SnapshotReq::default()
+    };
+    let (dataset_id, default_table_id) = dataset_and_table(&tomlfile)?;
+    let (dataset_id, table_id) = match snapshot.table_id.as_deref() {
+        Some(x) => x.split_once('.').unwrap_or((dataset_id, x)),
+        None => (dataset_id, default_table_id),
+    };
+    let now = OffsetDateTime::now_utc();
+    let snapshot_id = match snapshot.snapshot_id {
+        Some(x) => x,
+        None => format!("{}_snapshot_{}", table_id, now.unix_timestamp()),
+    };
+    let mut copy = serde_json::json!({
+        "sourceTable": {
+            "projectId": tomlfile.bigquery.projectid,
+            "datasetId": dataset_id,
+            "tableId": table_id,
+        },
+        "destinationTable": {
+            "projectId": tomlfile.bigquery.projectid,
+            "datasetId": dataset_id,
+            "tableId": snapshot_id,
+        },
+        "operationType": "SNAPSHOT",
+        "writeDisposition": "WRITE_EMPTY",
+    });
+    if let Some(hours) = snapshot.expiration_hours {
+        let expiration = (now + time::Duration::hours(hours)).format(&Rfc3339)?;
+        copy["destinationExpirationTime"] = serde_json::Value::from(expiration);
+    }
+    let body = serde_json::json!({
+        "jobReference": { "location": default_location(&tomlfile) },
+        "configuration": { "copy": copy },
+    });
+    let mut bqresp = bq_rest_request(&tomlfile, Method::POST, "jobs", Some(&body))?;
+    if !bqresp.get_status().is_success() {
+        return Ok(bq_admin_response(bqresp));
+    }
+    let job = bqresp.take_body_json::<serde_json::Value>()?;
+    let body = serde_json::json!({
+        "jobReference": job["jobReference"],
+        "status": job["status"],
+        "snapshot": format!("{}.{}", dataset_id, snapshot_id),
+    });
+    Ok(Response::from_status(StatusCode::OK).with_body_json(&body)?)
+}
+
+// Milliseconds since the epoch for an RFC 3339 timestamp or a number of ms.
+fn creation_time_ms(name: &str, value: &str) -> Result<u64, String> {
+    if let Ok(ms) = value.parse::<u64>() {
+        return Ok(ms);
+    }
+    match OffsetDateTime::parse(value, &Rfc3339) {
+        Ok(x) => Ok((x.unix_timestamp_nanos() / 1_000_000).max(0) as u64),
+        Err(e) => Err(format!(
+            "`{}`: {} is neither RFC 3339 nor milliseconds: {}",
+            name, value, e
+        )),
+    }
+}
+
+// GET /admin/jobs: jobs.list for the project, newest first. Filters:
+// `state=done|pending|running` (comma separated), `min_creation_time` and
+// `max_creation_time` (RFC 3339 or epoch ms), `max_results` and `page_token`.
+// Each job is summarized; `next_page_token`, also sent as X-Next-Page-Token
+// for CSV and NDJSON listings, continues the listing.
+pub fn handle_jobs_req(req: &Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+    let mut resource = "jobs?projection=full&allUsers=true".to_string();
+    if let Some(states) = req.get_query_parameter("state") {
+        for state in states.split(',') {
+            let state = state.trim().to_ascii_lowercase();
+            if !["done", "pending", "running"].contains(&state.as_str()) {
+                let msg = format!("`state`: {} is not one of done, pending, running", state);
+                error!("{}", msg);
+                return Ok(Response::from(AppError::BadRequest(msg)));
+            }
+            resource.push_str(&format!("&stateFilter={}", state));
+        }
+    }
+    for (name, bq_name) in [
+        ("min_creation_time", "minCreationTime"),
+        ("max_creation_time", "maxCreationTime"),
+    ] {
+        if let Some(value) = req.get_query_parameter(name) {
+            match creation_time_ms(name, value) {
+                Ok(ms) => resource.push_str(&format!("&{}={}", bq_name, ms)),
+                Err(msg) => {
+                    error!("{}", msg);
+                    return Ok(Response::from(AppError::BadRequest(msg)));
+                }
+            }
+        }
+    }
+    if let Some(x) = req.get_query_parameter("max_results") {
+        match x.parse::<u32>() {
+            Ok(n) => resource.push_str(&format!("&maxResults={}", n)),
+            Err(e) => {
+                let msg = format!("`max_results`: {} is not valid: {}", x, e);
+                error!("{}", msg);
+                return Ok(Response::from(AppError::BadRequest(msg)));
+            }
+        }
+    }
+    if let Some(x) = req.get_query_parameter("page_token") {
+        resource.push_str(&format!("&pageToken={}", urlencoding::encode(x)));
+    }
+    let mut bqresp = bq_rest_request(&tomlfile, Method::GET, &resource, None)?;
+    if !bqresp.get_status().is_success() {
+        return Ok(bq_admin_response(bqresp));
+    }
+    let listing = bqresp.take_body_json::<serde_json::Value>()?;
+    let jobs: Vec<serde_json::Value> = listing["jobs"]
+        .as_array()
+        .map(|x| x.as_slice())
+        .unwrap_or(&[])
+        .iter()
+        .map(|job| {
+            let configuration = &job["configuration"];
+            serde_json::json!({
+                "job_id": job["jobReference"]["jobId"],
+                "location": job["jobReference"]["location"],
+                "type": configuration["jobType"],
+                "state": job["state"],
+                "user": job["user_email"],
+                "creation_time": job["statistics"]["creationTime"],
+                "end_time": job["statistics"]["endTime"],
+                "total_bytes_processed": job["statistics"]["totalBytesProcessed"],
+                "statement_type": job["statistics"]["query"]["statementType"],
+                "query": configuration["query"]["query"],
+                "labels": configuration["labels"],
+                "error": job["errorResult"],
+            })
+        })
+        .collect();
+    let body = serde_json::json!({
+        "jobs": jobs,
+        "next_page_token": listing["nextPageToken"],
+    });
+    let mut resp = records_response(req.get_header_str("Accept"), &body, &jobs)?;
+    if let Some(x) = listing["nextPageToken"].as_str() {
+        resp.set_header(NEXT_PAGE_TOKEN_HEADER, x);
+    }
+    Ok(resp)
+}
+
+// GET /admin/status: one document with readiness checks, quota consumption of
+// every known key, edge and token cache hit rates over the last minute, the
+// last server errors when `[admin] kv_store` is set, and the optional features
+// that are enabled. There are no circuit breakers to report: BigQuery calls
+// are retried with backoff on every request instead of being cut off.
+pub fn handle_status_req(req: &Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    authorize_admin(&tomlfile, req)?;
+
+    let mut checks = serde_json::Map::new();
+    let config_check = match check_config(&tomlfile) {
+        Ok(()) => serde_json::json!({ "ok": true }),
+        Err(e) => serde_json::json!({ "ok": false, "error": e }),
+    };
+    checks.insert("config".to_string(), config_check);
+    let token_check = match gcp_access_token_request(&tomlfile, &tomlfile.bigquery.scopes()) {
+        Ok(_) => serde_json::json!({ "ok": true }),
+        Err(e) => serde_json::json!({ "ok": false, "error": e.to_string() }),
+    };
+    checks.insert("access_token".to_string(), token_check);
+    let (kv_check, managed) = match open_key_store() {
+        Ok(Some(store)) => match list_records(&store) {
+            Ok(x) => (serde_json::json!({ "ok": true }), x),
+            Err(e) => (
+                serde_json::json!({ "ok": false, "error": e.to_string() }),
+                Vec::new(),
+            ),
+        },
+        Ok(None) => (
+            serde_json::json!({ "ok": true, "linked": false }),
+            Vec::new(),
+        ),
+        Err(e) => (
+            serde_json::json!({ "ok": false, "error": e.to_string() }),
+            Vec::new(),
+        ),
+    };
+    checks.insert("api_key_store".to_string(), kv_check);
+
+    let mut keys: Vec<ApiKey> = Vec::new();
+    if let Some(api_keys) = &tomlfile.api_keys {
+        for (name, key) in api_keys {
+            keys.push(ApiKey {
+                name: name.to_string(),
+                tier: key.tier.clone(),
+                dma_ids: key.dma_ids.clone(),
+            });
+        }
+    }
+    for record in managed {
+        keys.push(ApiKey {
+            name: record.name,
+            tier: record.tier,
+            dma_ids: record.dma_ids,
+        });
+    }
+    let quotas: Vec<QuotaState> = keys
+        .iter()
+        .filter_map(|key| quota_state(&tomlfile, key))
+        .collect();
+
+    let healthy = checks.values().all(|check| check["ok"] == true);
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "degraded" },
+        "checks": checks,
+        "quotas": quotas,
+        "caches": {
+            "edge_cache": stats::cache_stats(stats::EDGE_CACHE),
+            "token_cache": stats::cache_stats(stats::TOKEN_CACHE),
+        },
+        "recent_errors": stats::recent_errors(&tomlfile),
+        "features": {
+            "tee_routes": tomlfile.tee.as_ref().map(|tee| &tee.routes),
+            "api_keys": tomlfile.api_keys.is_some(),
+            "tiers": tomlfile.tiers.as_ref().map(|tiers| tiers.keys().collect::<Vec<_>>()),
+        },
+    });
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(Response::from_status(status).with_body_json(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_names_are_short_and_plain() {
+        assert!(valid_key_name("dashboard"));
+        assert!(valid_key_name("team-a.reports_v2"));
+        assert!(valid_key_name(&"a".repeat(MAX_KEY_NAME_LEN)));
+        assert!(!valid_key_name(""));
+        assert!(!valid_key_name(&"a".repeat(MAX_KEY_NAME_LEN + 1)));
+        assert!(!valid_key_name("team/a"));
+        assert!(!valid_key_name("team a"));
+        assert!(!valid_key_name("schlüssel"));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::admin::{lookup_record, open_key_store};
+use crate::config::Config;
+use crate::error::AppError;
+use fastly::secret_store::SecretStore;
+use fastly::{Error, Request};
+use std::cell::RefCell;
+
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+thread_local! {
+    // The caller's key once `authenticate` has resolved it, so the routing
+    // middleware and the handler share one lookup per request.
+    static CALLER: RefCell<Option<Option<ApiKey>>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub tier: Option<String>,
+    // Values of the table's `scope_column` the key is limited to.
+    pub dma_ids: Option<Vec<i64>>,
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(hmac_sha256::Hash::hash(key.as_bytes()))
+}
+
+// A key held in the `[auth]` Secret Store: the secret is named after the key's
+// hex SHA-256 and holds either this record as JSON or just the key's name.
+#[derive(serde::Deserialize, Debug)]
+struct SecretKeyRecord {
+    name: String,
+    tier: Option<String>,
+    dma_ids: Option<Vec<i64>>,
+}
+
+fn lookup_secret(store_name: &str, key_sha256: &str) -> Result<Option<ApiKey>, Error> {
+    let secret = match SecretStore::open(store_name)?.try_get(&key_sha256.to_ascii_lowercase())? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let plaintext = String::from_utf8(secret.try_plaintext()?.to_vec())?;
+    let record = match serde_json::from_str::<SecretKeyRecord>(&plaintext) {
+        Ok(x) => x,
+        Err(_) => SecretKeyRecord {
+            name: plaintext.trim().to_string(),
+            tier: None,
+            dma_ids: None,
+        },
+    };
+    Ok(Some(ApiKey {
+        name: record.name,
+        tier: record.tier,
+        dma_ids: record.dma_ids,
+    }))
+}
+
+// Routing middleware for the public routes: rejects a missing key with 401
+// and an unknown one with 403 before any BigQuery work happens.
+pub fn require_api_key(req: &Request) -> Result<(), Error> {
+    authenticate(&Config::load(), req)?;
+    Ok(())
+}
+
+// Resolves the caller's API key from `[api_keys]` in config, then from the
+// `[auth]` Secret Store, then from the managed keys in the KV Store. When none
+// of `[api_keys]`, `[auth]` and `[admin]` is configured the service stays open
+// and every request is unscoped.
+pub fn authenticate(tomlfile: &Config, req: &Request) -> Result<Option<ApiKey>, Error> {
+    if let Some(x) = CALLER.with(|x| x.borrow().clone()) {
+        return Ok(x);
+    }
+    let caller = resolve_api_key(tomlfile, req)?;
+    CALLER.with(|x| *x.borrow_mut() = Some(caller.clone()));
+    Ok(caller)
+}
+
+fn resolve_api_key(tomlfile: &Config, req: &Request) -> Result<Option<ApiKey>, AppError> {
+    if tomlfile.api_keys.is_none() && tomlfile.auth.is_none() && tomlfile.admin.is_none() {
+        return Ok(None);
+    }
+    let presented = match req.get_header_str(API_KEY_HEADER) {
+        Some(x) => x,
+        None => {
+            let msg = format!("Missing {} header", API_KEY_HEADER);
+            return Err(AppError::Unauthorized(msg));
+        }
+    };
+    let presented_hash = hash_key(presented);
+    if let Some(api_keys) = &tomlfile.api_keys {
+        if let Some((name, key)) = api_keys
+            .iter()
+            .find(|(_, key)| key.key_sha256.eq_ignore_ascii_case(&presented_hash))
+        {
+            return Ok(Some(ApiKey {
+                name: name.to_string(),
+                tier: key.tier.clone(),
+                dma_ids: key.dma_ids.clone(),
+            }));
+        }
+    }
+    if let Some(store_name) = tomlfile
+        .auth
+        .as_ref()
+        .and_then(|x| x.secret_store.as_deref())
+    {
+        match lookup_secret(store_name, &presented_hash) {
+            Ok(Some(x)) => return Ok(Some(x)),
+            Ok(None) => {}
+            Err(e) => {
+                let msg = format!("API key lookup error: {}", e);
+                return Err(AppError::Upstream(msg));
+            }
+        }
+    }
+    let managed = match open_key_store() {
+        Ok(Some(store)) => lookup_record(&store, &presented_hash),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    match managed {
+        Ok(Some(record)) => Ok(Some(ApiKey {
+            name: record.name,
+            tier: record.tier,
+            dma_ids: record.dma_ids,
+        })),
+        Ok(None) => Err(AppError::Forbidden("API key is not valid".to_string())),
+        Err(e) => {
+            let msg = format!("API key lookup error: {}", e);
+            Err(AppError::Upstream(msg))
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use crate::error::{error_response, AppError};
+use crate::gcp::TABLE_ROUTE_PREFIX;
+use crate::router::Router;
+use crate::stream;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{mime, Error, Request, Response};
+use serde::Deserialize;
+
+pub const BATCH_ROUTE: &str = "/batch";
+const DEFAULT_TABLE_ROUTE: &str = "/api/v1/top_rising_terms";
+const MAX_BATCH_OPERATIONS: usize = 25;
+
+// POST /batch runs several table operations in one request, so a dashboard
+// needs one round trip instead of one per call:
+// `[{"op": "get", "params": {"dma_id": 819}}, {"op": "insert", "rows": [..]},
+//   {"op": "delete", "table": "sales", "params": {"week": "2024-05-05"}}]`.
+// Each runs, in order, as the request to the table route it stands for, with
+// the batch's credentials: `get` and `delete` take `params` as the query
+// string, `insert` posts `rows`. Without `table` they address the configured
+// table, with it the `[tables]` entry of that name. A failing operation does
+// not stop the others; each gets its own `status` and `body`, along with the
+// `id` it was given, if any. Going through the table routes, each operation
+// is also metered against the key's quota like a request of its own.
+#[derive(Deserialize)]
+struct BatchOperation {
+    id: Option<serde_json::Value>,
+    op: String,
+    table: Option<String>,
+    #[serde(default)]
+    params: serde_json::Map<String, serde_json::Value>,
+    rows: Option<serde_json::Value>,
+}
+
+fn method(op: &str) -> Option<Method> {
+    match op {
+        "get" => Some(Method::GET),
+        "insert" => Some(Method::POST),
+        "delete" => Some(Method::DELETE),
+        _ => None,
+    }
+}
+
+// The operation as a request to its table route, carrying the batch's headers.
+fn sub_request(req: &Request, operation: &BatchOperation) -> Result<Request, Error> {
+    let mut sub = req.clone_without_body();
+    // Checked before anything runs.
+    sub.set_method(method(&operation.op).unwrap_or(Method::GET));
+    match &operation.table {
+        Some(x) => sub.set_path(&format!("{}{}", TABLE_ROUTE_PREFIX, x)),
+        None => sub.set_path(DEFAULT_TABLE_ROUTE),
+    }
+    let params: Vec<(&str, String)> = operation
+        .params
+        .iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(x) => (key.as_str(), x.clone()),
+            x => (key.as_str(), x.to_string()),
+        })
+        .collect();
+    sub.set_query(&params)?;
+    sub.remove_header(header::CONTENT_LENGTH);
+    sub.remove_header(header::IF_NONE_MATCH);
+    sub.set_header(header::ACCEPT, "application/json");
+    if let Some(rows) = &operation.rows {
+        sub.set_body_json(rows)?;
+    }
+    Ok(sub)
+}
+
+fn result(operation: &BatchOperation, mut resp: Response) -> serde_json::Value {
+    let status = resp.get_status().as_u16();
+    let is_json = resp
+        .get_content_type()
+        .is_some_and(|x| x.essence_str() == mime::APPLICATION_JSON.essence_str());
+    let text = resp.take_body_str();
+    let body = match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(x) if is_json => x,
+        _ if text.is_empty() => serde_json::Value::Null,
+        _ => serde_json::Value::from(text),
+    };
+    let mut result = serde_json::json!({ "op": operation.op, "status": status, "body": body });
+    if let Some(id) = &operation.id {
+        result["id"] = id.clone();
+    }
+    result
+}
+
+// Runs the operations through `router`. Results are collected whole, so the
+// operations' rows are never streamed.
+pub fn handle_batch_req(req: &mut Request, router: &Router) -> Result<Response, Error> {
+    let operations = match req.take_body_json::<Vec<BatchOperation>>() {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Request body is not a batch of operations: {}", e);
+            return Err(AppError::BadRequest(msg).into());
+        }
+    };
+    if operations.is_empty() || operations.len() > MAX_BATCH_OPERATIONS {
+        let msg = format!(
+            "A batch holds 1 to {} operations, not {}",
+            MAX_BATCH_OPERATIONS,
+            operations.len()
+        );
+        return Err(AppError::BadRequest(msg).into());
+    }
+    for (i, operation) in operations.iter().enumerate() {
+        if method(&operation.op).is_none() {
+            let msg = format!(
+                "operation {}: `{}` is not get, insert or delete",
+                i, operation.op
+            );
+            return Err(AppError::BadRequest(msg).into());
+        }
+        if operation.op == "insert" && operation.rows.is_none() {
+            let msg = format!("operation {}: insert needs `rows`", i);
+            return Err(AppError::BadRequest(msg).into());
+        }
+        // `table` becomes a path segment, so it must not reach other routes,
+        // e.g. `x/upsert` or `../admin/keys`.
+        if operation.table.as_deref().is_some_and(|x| x.contains('/')) {
+            let msg = format!("operation {}: `table` is a table name, not a path", i);
+            return Err(AppError::BadRequest(msg).into());
+        }
+    }
+
+    let mut results = Vec::with_capacity(operations.len());
+    for operation in &operations {
+        let mut sub = sub_request(req, operation)?;
+        let resp = match stream::buffered(|| router.dispatch(&mut sub)) {
+            Ok(x) => x,
+            Err(e) => error_response(e),
+        };
+        results.push(result(operation, resp));
+    }
+    let body = serde_json::json!({ "results": results });
+    Ok(Response::from_status(StatusCode::OK).with_body_json(&body)?)
+}
@@ -0,0 +1,39 @@
+use crate::config::Config;
+use crate::error::AppError;
+use fastly::http::header;
+use fastly::{Error, Request};
+use std::io::Read;
+
+const DEFAULT_MAX_BODY_BYTES: u64 = 8 * 1024 * 1024;
+
+// Routing middleware: a body over `[limits] max_body_bytes` gets 413 before any
+// handler parses it, so an oversized batch insert cannot exhaust the instance's
+// memory or turn into an enormous SQL statement. A Content-Length over the
+// limit is refused unread; otherwise at most one byte more than the limit is
+// read and the body is put back for the handler.
+pub fn check_body_size(tomlfile: &Config, req: &mut Request) -> Result<(), Error> {
+    let limit = tomlfile
+        .limits
+        .as_ref()
+        .and_then(|x| x.max_body_bytes)
+        .unwrap_or(DEFAULT_MAX_BODY_BYTES);
+    let declared = req
+        .get_header_str(header::CONTENT_LENGTH)
+        .and_then(|x| x.parse::<u64>().ok());
+    if declared.is_some_and(|x| x > limit) || (declared.is_none() && too_large(req, limit)?) {
+        let msg = format!("Request body is larger than {} bytes", limit);
+        return Err(AppError::PayloadTooLarge(msg).into());
+    }
+    Ok(())
+}
+
+fn too_large(req: &mut Request, limit: u64) -> Result<bool, Error> {
+    if !req.has_body() {
+        return Ok(false);
+    }
+    let mut body = Vec::new();
+    req.take_body().take(limit + 1).read_to_end(&mut body)?;
+    let too_large = body.len() as u64 > limit;
+    req.set_body(body);
+    Ok(too_large)
+}
@@ -0,0 +1,65 @@
+use crate::config::Config;
+use crate::stream::is_streamed;
+use fastly::http::header;
+use fastly::Response;
+
+// Asks Fastly to gzip or Brotli compress the response on its way out.
+const COMPRESS_HINT_HEADER: &str = "x-compress-hint";
+const DEFAULT_MIN_BYTES: u64 = 1024;
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "application/json",
+    "application/x-ndjson",
+    "text/csv",
+    "text/plain",
+];
+
+// With `[compression]`, JSON, NDJSON, CSV and text responses of at least
+// `min_bytes` are flagged for Fastly's dynamic compression, which encodes them
+// as the client's Accept-Encoding allows. Bodies of unknown length, such as
+// streamed results, are always flagged.
+fn accepts_compression(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|x| {
+        let mut parts = x.split(';');
+        let coding = parts.next().unwrap_or("").trim();
+        let q = parts
+            .filter_map(|x| x.trim().strip_prefix("q="))
+            .find_map(|x| x.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("br")) && q > 0.0
+    })
+}
+
+fn is_compressible(resp: &Response) -> bool {
+    resp.get_header_str(header::CONTENT_TYPE)
+        .and_then(|x| x.split(';').next())
+        .is_some_and(|x| {
+            COMPRESSIBLE_TYPES
+                .iter()
+                .any(|t| x.trim().eq_ignore_ascii_case(t))
+        })
+}
+
+// Flags `resp` for compression; `accept_encoding` is the request's header.
+pub fn apply_compression(tomlfile: &Config, accept_encoding: Option<&str>, resp: &mut Response) {
+    let compression = match &tomlfile.compression {
+        Some(x) => x,
+        None => return,
+    };
+    if resp.contains_header(header::CONTENT_ENCODING) || !is_compressible(resp) {
+        return;
+    }
+    resp.append_header(header::VARY, "Accept-Encoding");
+    if !accepts_compression(accept_encoding.unwrap_or("")) {
+        return;
+    }
+    let min_bytes = compression.min_bytes.unwrap_or(DEFAULT_MIN_BYTES);
+    let large = is_streamed()
+        || match resp.try_get_body_mut().map(|x| x.known_length()) {
+            Some(Some(x)) => x >= min_bytes,
+            Some(None) => true,
+            None => false,
+        };
+    if large {
+        resp.set_header(COMPRESS_HINT_HEADER, "on");
+    }
+}
@@ -1,18 +1,202 @@
-use serde::Deserialize;
+use crate::error::AppError;
+use crate::storage_write::ColumnType;
+use fastly::config_store::ConfigStore;
+use fastly::secret_store::SecretStore;
+use jwt_simple::algorithms::RS256KeyPair;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
     pub gcp: GcpConfiguration,
     pub bigquery: BqConfiguration,
+    pub tee: Option<TeeConfiguration>,
+    pub api_keys: Option<HashMap<String, ApiKeyConfiguration>>,
+    pub admin: Option<AdminConfiguration>,
+    pub tiers: Option<HashMap<String, TierConfiguration>>,
+    pub targets: Option<Vec<TargetConfiguration>>,
+    pub ingest: Option<IngestConfiguration>,
+    pub export: Option<ExportConfiguration>,
+    pub aggregates: Option<HashMap<String, AggregateConfiguration>>,
+    pub token_cache: Option<TokenCacheConfiguration>,
+    pub credentials: Option<HashMap<String, CredentialConfiguration>>,
+    pub auth: Option<AuthConfiguration>,
+    pub oidc: Option<OidcConfiguration>,
+    pub webhook: Option<WebhookConfiguration>,
+    pub mtls: Option<MtlsConfiguration>,
+    pub rate_limit: Option<RateLimitConfiguration>,
+    pub tables: Option<HashMap<String, TableConfiguration>>,
+    pub cors: Option<CorsConfiguration>,
+    pub compression: Option<CompressionConfiguration>,
+    pub cache: Option<CacheConfiguration>,
+    pub health: Option<HealthConfiguration>,
+    pub limits: Option<LimitsConfiguration>,
+    // Why the profile and environment overrides could not be applied, for
+    // `validate` to report.
+    #[serde(skip)]
+    pub override_error: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct BqConfiguration {
+    #[serde(default)]
     pub service_account_email: String,
+    // PEM private key of the service account. Leave it out when the key lives
+    // in the Secret Store below; it is only used when that store lacks it.
+    #[serde(default)]
     pub service_account_key: String,
+    // `private_key_id` of that key, sent as the JWT `kid` header.
+    pub service_account_key_id: Option<String>,
+    // More keys for zero-downtime rotation, tried in order after the one
+    // above whenever Google rejects a token request.
+    pub service_account_keys: Option<Vec<ServiceAccountKeyConfiguration>>,
+    // Fastly Secret Store holding the private key under
+    // `service_account_key_secret` (default "service_account_key").
+    pub secret_store: Option<String>,
+    pub service_account_key_secret: Option<String>,
+    // Secret in that store that page and job tokens are signed with. Without
+    // it they are signed with a key derived from the service account key.
+    pub page_token_secret: Option<String>,
+    // Workspace user the service account acts as through domain-wide
+    // delegation; sets the `sub` claim of the token request.
+    pub subject: Option<String>,
+    // Workload identity federation instead of a service account key: the
+    // fields of an `external_account` credential configuration file.
+    pub external_account: Option<ExternalAccountConfiguration>,
+    // Names of `[credentials.<name>]` accounts that GET routes and writes use
+    // instead of the account above, e.g. a read-only "reader" and a "writer".
+    pub read_credentials: Option<String>,
+    pub write_credentials: Option<String>,
+    // Forward the caller's own Google OAuth access token, from
+    // `Authorization: Bearer`, to BigQuery instead of using the account above,
+    // so BigQuery applies the end user's permissions.
+    pub oauth_passthrough: Option<bool>,
+    // That token, set per request by gcp::load_config.
+    #[serde(skip)]
+    pub user_access_token: Option<String>,
+    // Fastly Config Store whose `projectid`, `dataset_tableid`, `scope` and
+    // `aud` entries override the values in this file, so they can change
+    // without a rebuild.
+    pub config_store: Option<String>,
     pub scope: String,
+    // Scopes the BigQuery token covers besides `scope`, e.g. Cloud Storage for
+    // the export features.
+    pub additional_scopes: Option<Vec<String>>,
     pub projectid: String,
     pub dataset_tableid: String,
+    // BigQuery REST host, e.g. a regional endpoint such as
+    // "bigquery.europe-west1.rep.googleapis.com" (default
+    // "bigquery.googleapis.com"), and the Fastly backend pointing at it
+    // (default "bigquery").
+    pub endpoint: Option<String>,
+    pub backend: Option<String>,
+    // Location jobs run in, e.g. "US" (default) or "EU". Must match the dataset.
+    pub location: Option<String>,
+    // Run SELECTs as legacy SQL; requests can flip it with `legacy_sql=true|false`.
+    pub use_legacy_sql: Option<bool>,
+    // Let BigQuery answer from its results cache (BigQuery's default is true);
+    // requests can flip it with `cache=true|false`.
+    pub use_query_cache: Option<bool>,
+    // "dml" (default) runs an INSERT job, "insert_all" uses the streaming API
+    // and "storage_write" the Storage Write API default stream.
+    pub insert_mode: Option<String>,
+    // "sync" (default) uses jobs.query, "job" submits via jobs.insert and polls
+    // for up to job_poll_budget_ms before answering 202 with a job token.
+    pub query_mode: Option<String>,
+    pub job_poll_budget_ms: Option<u32>,
+    // "rest" (default) pages rows through jobs.getQueryResults, "storage_read"
+    // runs SELECTs as jobs and reads their results through the Storage Read
+    // API. Requests can pick one with `read=rest|storage_read`.
+    pub read_mode: Option<String>,
+    // Stream unpaged GET results to the client as they are read instead of
+    // building the whole body first. REST reads then follow every page of the
+    // result, stream_page_rows rows (default 10000) at a time, and send no
+    // X-Next-Page-Token; the compact layout is still built in full.
+    pub stream_results: Option<bool>,
+    pub stream_page_rows: Option<u32>,
+    // How long jobs.query waits for a result before answering jobComplete=false.
+    pub query_timeout_ms: Option<u32>,
+    // Attempts per BigQuery call (default 3) and the base of the exponential,
+    // jittered backoff between them (default 250ms).
+    pub retry_attempts: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    // Dry-run every GET query first and report its estimated scan in the
+    // X-BQ-Estimated-Bytes header. Costs one extra (free) BigQuery call.
+    pub estimate_bytes: Option<bool>,
+    // Send a strong ETag with GET results and answer a matching If-None-Match
+    // with 304 before querying. Costs one tables.get per GET.
+    pub etags: Option<bool>,
+    // Upper bound on bytes billed per query; BigQuery fails larger queries.
+    pub maximum_bytes_billed: Option<u64>,
+    // Columns of the table, which validate insert bodies and build INSERTs.
+    // Defaults to the example TopRisingTerms columns.
+    pub columns: Option<Vec<ColumnConfiguration>>,
+    // Table layout used by the `/admin/tables` routes. Defaults to `columns`;
+    // without `columns` its fields validate insert bodies.
+    pub schema: Option<Vec<SchemaFieldConfiguration>>,
+    // Validate insert bodies against the table's own schema, fetched with
+    // tables.get and cached for schema_cache_secs (default 300) in the
+    // `schema_cache` KV Store when one is named. `columns` still wins.
+    pub fetch_schema: Option<bool>,
+    pub schema_cache: Option<String>,
+    pub schema_cache_secs: Option<u64>,
+    // Materialized view refreshed by POST /admin/refresh, as "dataset.view".
+    pub materialized_view: Option<String>,
+    // Columns identifying a row for upserts.
+    pub key_columns: Option<Vec<String>>,
+    // INT64 column API keys with `dma_ids` are scoped on (default `dma_id`).
+    pub scope_column: Option<String>,
+    // DATE column `from`/`to` bound. Reads that pass neither start at the
+    // current week; without one there is no window and `from`/`to` are a 400.
+    pub date_column: Option<String>,
+    // DATE column the table is partitioned on. Reads that do not bound it get
+    // `partition_column >= CURRENT_DATE() - partition_lookback_days`; with
+    // require_partition_filter (set it to match the table's
+    // requirePartitionFilter) reads that still don't are rejected with a 400
+    // instead of failing in BigQuery.
+    pub partition_column: Option<String>,
+    pub partition_lookback_days: Option<u32>,
+    pub require_partition_filter: Option<bool>,
+    // Row limit of GET queries without `limit` (default 1000) and the largest
+    // `limit` accepted (default 10000). Storage Read queries have no default.
+    pub default_limit: Option<u64>,
+    pub max_limit: Option<u64>,
+    // Columns the GET routes may filter on, with their operators: "eq"
+    // (`?col=`), "gte" (`?col_gte=`), "lte" (`?col_lte=`) and "in"
+    // (`?col_in=a,b`).
+    pub filters: Option<HashMap<String, Vec<String>>>,
+    // Columns the GET routes may filter on with any of those operators, and
+    // the only columns `sort` may name. Without allowed_sort_columns any
+    // column may be sorted on.
+    pub allowed_filter_columns: Option<Vec<String>>,
+    pub allowed_sort_columns: Option<Vec<String>>,
+    // Labels attached to every query job, for cost attribution.
+    pub labels: Option<HashMap<String, String>>,
+}
+
+// `type` is STRING, INT64, FLOAT64, BOOL, DATE or GEOGRAPHY (or BigQuery's
+// legacy names for them). Inserted rows must set a `required` column.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ColumnConfiguration {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub required: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct SchemaFieldConfiguration {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,13 +204,959 @@ pub struct GcpConfiguration {
     pub alg: String,
     pub aud: String,
     pub grant_type: String,
+    // Use a self-signed JWT as the Bearer token instead of exchanging it for
+    // an access token at `aud`.
+    pub self_signed_jwt: Option<bool>,
+}
+
+// Usage "tee": a summary of every query hit on the listed routes is written to
+// Pub/Sub and/or GCS so data-product consumption can be analysed later.
+#[derive(Debug, Deserialize)]
+pub struct TeeConfiguration {
+    pub routes: Vec<String>,
+    pub scope: String,
+    pub pubsub_topic: Option<String>,
+    pub gcs_bucket: Option<String>,
+}
+
+// Keys are stored as hex SHA-256 digests. `dma_ids` scopes a key to a market:
+// reads are filtered to those values of `[bigquery] scope_column` and writes
+// outside them are rejected.
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyConfiguration {
+    pub key_sha256: String,
+    pub tier: Option<String>,
+    pub dma_ids: Option<Vec<i64>>,
+}
+
+// API keys kept in a Secret Store: each secret is named after a key's hex
+// SHA-256. Configuring it makes the `X-Api-Key` header mandatory.
+#[derive(Debug, Deserialize)]
+pub struct AuthConfiguration {
+    pub secret_store: Option<String>,
+}
+
+// Requires a Google-signed ID token for `audience` on the mutating routes.
+// Keys come from `jwks_url` (default Google's) through `backend` (default
+// "google_certs") and are cached in `kv_store`, or without one in each
+// instance's memory. `issuers` defaults to Google's
+// and `allowed_emails`, if set, limits which accounts may call.
+#[derive(Debug, Deserialize)]
+pub struct OidcConfiguration {
+    pub audience: String,
+    pub issuers: Option<Vec<String>>,
+    pub allowed_emails: Option<Vec<String>>,
+    pub jwks_url: Option<String>,
+    pub backend: Option<String>,
+    pub kv_store: Option<String>,
+}
+
+// HMAC-SHA256 signed POSTs with the shared `secret` in `secret_store`. A valid
+// signature stands in for the `[oidc]` ID token; with `required` every POST
+// must be signed. Signatures are accepted for `tolerance_secs` (default 300).
+#[derive(Debug, Deserialize)]
+pub struct WebhookConfiguration {
+    pub secret_store: String,
+    pub secret: String,
+    pub required: Option<bool>,
+    pub tolerance_secs: Option<u64>,
+    pub signature_header: Option<String>,
+    pub timestamp_header: Option<String>,
+}
+
+// Client certificates allowed to insert and call the `/admin` routes, by
+// SHA-256 fingerprint or RFC 2253 subject.
+#[derive(Debug, Deserialize)]
+pub struct MtlsConfiguration {
+    pub allowed_fingerprints: Option<Vec<String>>,
+    pub allowed_subjects: Option<Vec<String>>,
+}
+
+// Enables the `/admin` routes, authorized by the `X-Admin-Key` header. With a
+// `kv_store`, the last server errors are kept there for `/admin/status`.
+#[derive(Debug, Deserialize)]
+pub struct AdminConfiguration {
+    pub key_sha256: String,
+    pub kv_store: Option<String>,
+}
+
+// Per-minute budgets for API keys of a tier. Clients get X-Quota-* headers and
+// a Warning header at 80%, then 429 (requests) or 402 (bytes) at the limit.
+#[derive(Debug, Deserialize)]
+pub struct TierConfiguration {
+    pub requests_per_minute: Option<u64>,
+    pub bytes_per_minute: Option<u64>,
+}
+
+// Per-minute request budgets per API key, or per client IP without one. Over
+// budget requests get 429 with Retry-After.
+#[derive(Debug, Deserialize)]
+pub struct RateLimitConfiguration {
+    pub get_per_minute: Option<u64>,
+    pub post_per_minute: Option<u64>,
+}
+
+// Cross-origin access for browser dashboards. `allowed_origins` may hold "*".
+// Methods default to GET and HEAD, headers to Content-Type, Authorization and
+// X-Api-Key, and browsers cache preflight answers for max_age_secs (default
+// 600). `expose_headers` lists response headers scripts may read.
+#[derive(Debug, Deserialize)]
+pub struct CorsConfiguration {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Option<Vec<String>>,
+    pub allowed_headers: Option<Vec<String>>,
+    pub expose_headers: Option<Vec<String>>,
+    pub max_age_secs: Option<u64>,
+}
+
+// Fastly gzip or Brotli compresses JSON, NDJSON, CSV and text responses of at
+// least min_bytes (default 1024) for clients that accept it.
+#[derive(Debug, Deserialize)]
+pub struct CompressionConfiguration {
+    pub min_bytes: Option<u64>,
+}
+
+// GET results cached at the edge for ttl_secs (default 60) and purged by
+// writes to their table.
+#[derive(Debug, Deserialize)]
+pub struct CacheConfiguration {
+    pub ttl_secs: Option<u64>,
+}
+
+// GET /healthz also runs `SELECT 1` with check_query, proving BigQuery answers
+// queries at the cost of one query per check.
+#[derive(Debug, Deserialize)]
+pub struct HealthConfiguration {
+    pub check_query: Option<bool>,
+}
+
+// Request bodies larger than max_body_bytes (default 8 MiB) get 413 before
+// they are read.
+#[derive(Debug, Deserialize)]
+pub struct LimitsConfiguration {
+    pub max_body_bytes: Option<u64>,
+}
+
+// A table reachable at `/bq/{projectid}/{dataset}/{table}`. Jobs for it run
+// in its own project, so the service account needs access there too.
+#[derive(Debug, Deserialize)]
+pub struct TargetConfiguration {
+    pub projectid: String,
+    pub dataset_tableid: String,
+    // The table's `date_column`; `[bigquery] date_column` is not inherited.
+    pub date_column: Option<String>,
+}
+
+// A table reachable by its logical name at `/t/{name}`. `operations` limits
+// what callers may do with it: "read", "insert", "upsert", "update" and
+// "delete" (default all).
+#[derive(Debug, Deserialize)]
+pub struct TableConfiguration {
+    pub projectid: String,
+    pub dataset_tableid: String,
+    pub operations: Option<Vec<String>>,
+    // The table's columns, when they differ from `[bigquery] columns`.
+    pub columns: Option<Vec<ColumnConfiguration>>,
+    // The table's `date_column`; `[bigquery] date_column` is not inherited.
+    pub date_column: Option<String>,
+    // The table's `scope_column`, when it differs from `[bigquery]`'s.
+    pub scope_column: Option<String>,
+}
+
+pub const TABLE_OPERATIONS: &[&str] = &["read", "insert", "upsert", "update", "delete"];
+
+// `POST /ingest/csv`: maps CSV header names to table columns.
+#[derive(Debug, Deserialize)]
+pub struct IngestConfiguration {
+    pub csv_columns: Option<HashMap<String, String>>,
+}
+
+// `POST /admin/export`: extract jobs write to gs://{bucket}/{prefix}...
+#[derive(Debug, Deserialize)]
+pub struct ExportConfiguration {
+    pub bucket: String,
+    pub prefix: Option<String>,
+}
+
+// A `GET /api/v1/top_rising_terms/aggregate?preset=` preset: rows grouped by
+// `group_by`, with one output column per measure, e.g.
+// `avg_score = "AVG(score)"`. Measures are trusted SQL from config.
+#[derive(Debug, Deserialize)]
+pub struct AggregateConfiguration {
+    pub group_by: Vec<String>,
+    pub measures: HashMap<String, String>,
+}
+
+const DEFAULT_SERVICE_ACCOUNT_KEY_SECRET: &str = "service_account_key";
+
+// A rotation key: the PEM `key` itself, or the name of the `secret` holding it
+// in the account's Secret Store.
+#[derive(Debug, Deserialize)]
+pub struct ServiceAccountKeyConfiguration {
+    pub key_id: String,
+    #[serde(default)]
+    pub key: String,
+    pub secret: Option<String>,
+}
+
+// A named account for `read_credentials` / `write_credentials`, configured
+// like the `[bigquery]` account.
+#[derive(Debug, Deserialize)]
+pub struct CredentialConfiguration {
+    #[serde(default)]
+    pub service_account_email: String,
+    #[serde(default)]
+    pub service_account_key: String,
+    pub service_account_key_id: Option<String>,
+    pub service_account_keys: Option<Vec<ServiceAccountKeyConfiguration>>,
+    pub secret_store: Option<String>,
+    pub service_account_key_secret: Option<String>,
+    pub subject: Option<String>,
+    pub external_account: Option<ExternalAccountConfiguration>,
+}
+
+// `type = "external_account"` credentials. The subject token is exchanged at
+// `token_url` through the `sts_backend` (default "sts"), then for a token of
+// the service account at `service_account_impersonation_url`, if given,
+// through the `impersonation_backend` (default "iamcredentials").
+#[derive(Debug, Deserialize)]
+pub struct ExternalAccountConfiguration {
+    pub audience: String,
+    pub subject_token_type: String,
+    pub token_url: Option<String>,
+    pub service_account_impersonation_url: Option<String>,
+    pub credential_source: CredentialSourceConfiguration,
+    pub sts_backend: Option<String>,
+    pub impersonation_backend: Option<String>,
+}
+
+// The subject token is fetched from `url` through `backend` (default
+// "subject_token"), as text or, with a json `format`, from one field of the
+// response. Alternatively it is read from `secret` in `secret_store`.
+#[derive(Debug, Deserialize)]
+pub struct CredentialSourceConfiguration {
+    pub url: Option<String>,
+    pub headers: Option<HashMap<String, String>>,
+    pub format: Option<CredentialSourceFormat>,
+    pub backend: Option<String>,
+    pub secret_store: Option<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CredentialSourceFormat {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub subject_token_field_name: Option<String>,
+}
+
+// Shares access tokens across instances through a KV Store. Cached tokens are
+// replaced once less than `refresh_margin_secs` (default 300) remain, by one
+// request at a time; without a valid token the others wait up to
+// `lock_wait_ms` (default 2000) for it.
+#[derive(Debug, Deserialize)]
+pub struct TokenCacheConfiguration {
+    pub kv_store: String,
+    pub refresh_margin_secs: Option<u64>,
+    pub lock_wait_ms: Option<u64>,
+    // Hex-encoded AES-256 key in `secret_store` that cached tokens are
+    // encrypted under.
+    pub secret_store: Option<String>,
+    pub encryption_key_secret: Option<String>,
+}
+
+impl BqConfiguration {
+    // Points the `[bigquery]` settings at a `[tables]` entry.
+    pub fn use_table(&mut self, table: &TableConfiguration) {
+        self.projectid = table.projectid.clone();
+        self.dataset_tableid = table.dataset_tableid.clone();
+        if let Some(x) = &table.columns {
+            self.columns = Some(x.clone());
+        }
+        self.date_column = table.date_column.clone();
+        if let Some(x) = &table.scope_column {
+            self.scope_column = Some(x.clone());
+        }
+    }
+
+    pub fn scopes(&self) -> Vec<&str> {
+        let mut scopes = vec![self.scope.as_str()];
+        scopes.extend(self.additional_scopes.iter().flatten().map(String::as_str));
+        scopes
+    }
+
+    // The service account's PEM private key: the Secret Store's copy when a
+    // store is configured and holds one, otherwise `service_account_key`.
+    pub fn credentials(&self) -> Result<String, String> {
+        if let Some(name) = &self.secret_store {
+            let secret_name = self
+                .service_account_key_secret
+                .as_deref()
+                .unwrap_or(DEFAULT_SERVICE_ACCOUNT_KEY_SECRET);
+            if let Some(key) = read_secret(name, secret_name)? {
+                return Ok(key);
+            }
+        }
+        if self.service_account_key.is_empty() {
+            return Err("No service account key configured".to_string());
+        }
+        // TOML keys are often pasted with escaped newlines.
+        Ok(self.service_account_key.replace("\\n", "\n"))
+    }
+
+    // The HMAC key page and job tokens are signed with.
+    pub fn page_token_key(&self) -> Result<Vec<u8>, String> {
+        if let Some(secret) = &self.page_token_secret {
+            let store = self
+                .secret_store
+                .as_deref()
+                .ok_or_else(|| "page_token_secret needs a secret_store".to_string())?;
+            return read_secret(store, secret)?
+                .map(String::into_bytes)
+                .ok_or_else(|| format!("Secret {} not found", secret));
+        }
+        if self.service_account_key.is_empty() {
+            return Err(
+                "Page tokens need a page_token_secret or a service account key".to_string(),
+            );
+        }
+        Ok(hmac_sha256::HMAC::mac(b"page tokens", self.service_account_key.as_bytes()).to_vec())
+    }
+
+    // Resolved once so gcp_access_token_request can sign with them; on
+    // failure a key stays empty and is skipped.
+    fn resolve_credentials(&mut self) {
+        if self.external_account.is_some() {
+            return;
+        }
+        if self.secret_store.is_some() || !self.service_account_key.is_empty() {
+            self.service_account_key = match self.credentials() {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("{}", e);
+                    String::new()
+                }
+            };
+        }
+        for key in self.service_account_keys.iter_mut().flatten() {
+            let resolved = match (&key.secret, &self.secret_store) {
+                (Some(secret), Some(store)) => read_secret(store, secret)
+                    .and_then(|x| x.ok_or_else(|| format!("Secret {} not found", secret))),
+                (Some(_), None) => Err("`secret` needs a secret_store".to_string()),
+                (None, _) => Ok(key.key.replace("\\n", "\n")),
+            };
+            key.key = match resolved {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("Service account key {}: {}", key.key_id, e);
+                    String::new()
+                }
+            };
+        }
+    }
+
+    // (key id, PEM key) of every usable key, in the order they are tried.
+    pub fn service_account_keys(&self) -> Vec<(Option<&str>, &str)> {
+        let mut keys = vec![(
+            self.service_account_key_id.as_deref(),
+            self.service_account_key.as_str(),
+        )];
+        for key in self.service_account_keys.iter().flatten() {
+            keys.push((Some(key.key_id.as_str()), key.key.as_str()));
+        }
+        keys.retain(|(_, key)| !key.is_empty());
+        keys
+    }
+}
+
+// The plaintext of `secret` in the Secret Store `store`, if it holds one.
+fn read_secret(store: &str, secret: &str) -> Result<Option<String>, String> {
+    let store = SecretStore::open(store)
+        .map_err(|e| format!("Opening Secret Store {} failed: {}", store, e))?;
+    let value = match store
+        .try_get(secret)
+        .map_err(|e| format!("Reading secret {} failed: {}", secret, e))?
+    {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let plaintext = value
+        .try_plaintext()
+        .map_err(|e| format!("Decrypting secret {} failed: {}", secret, e))?;
+    String::from_utf8(plaintext.to_vec())
+        .map(Some)
+        .map_err(|_| format!("Secret {} is not UTF-8", secret))
+}
+
+// Merges the Config Store's entries over the TOML defaults. A store that
+// cannot be opened leaves the defaults in place.
+fn apply_config_store(gcp: &mut GcpConfiguration, bigquery: &mut BqConfiguration) {
+    let name = match &bigquery.config_store {
+        Some(x) => x.clone(),
+        None => return,
+    };
+    let store = match ConfigStore::try_open(&name) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Opening Config Store {} failed: {}", name, e);
+            return;
+        }
+    };
+    let settings = [
+        ("projectid", &mut bigquery.projectid),
+        ("dataset_tableid", &mut bigquery.dataset_tableid),
+        ("scope", &mut bigquery.scope),
+        ("aud", &mut gcp.aud),
+    ];
+    for (key, value) in settings {
+        match store.try_get(key) {
+            Ok(Some(x)) => *value = x,
+            Ok(None) => {}
+            Err(e) => error!("Reading {} from Config Store {} failed: {}", key, name, e),
+        }
+    }
+}
+
+// `[env.<name>]` blocks hold per-environment settings in the format of the
+// rest of `config.toml`, e.g. `[env.prod.bigquery] projectid = ".."`. The
+// profile named by BQ_ENV is merged over the file, or else the one whose
+// `service_ids` lists the FASTLY_SERVICE_ID the package runs under, so the
+// same package picks its targets and limits on each service.
+const PROFILES: &str = "env";
+const PROFILE_ENV: &str = "BQ_ENV";
+const PROFILE_SERVICE_IDS: &str = "service_ids";
+
+fn selected_profile(profiles: &toml::value::Table) -> Option<String> {
+    if let Ok(x) = std::env::var(PROFILE_ENV) {
+        return Some(x);
+    }
+    let service_id = std::env::var("FASTLY_SERVICE_ID").ok()?;
+    profiles
+        .iter()
+        .find(|(_, profile)| {
+            profile
+                .get(PROFILE_SERVICE_IDS)
+                .and_then(toml::Value::as_array)
+                .is_some_and(|ids| ids.iter().any(|x| x.as_str() == Some(&service_id)))
+        })
+        .map(|(name, _)| name.clone())
+}
+
+fn apply_profile(config: &mut toml::Value) {
+    let mut profiles = match config.as_table_mut().and_then(|x| x.remove(PROFILES)) {
+        Some(toml::Value::Table(x)) => x,
+        _ => return,
+    };
+    let name = match selected_profile(&profiles) {
+        Some(x) => x,
+        None => return,
+    };
+    match profiles.remove(&name) {
+        Some(toml::Value::Table(mut profile)) => {
+            profile.remove(PROFILE_SERVICE_IDS);
+            merge(config, toml::Value::Table(profile));
+        }
+        _ => error!(
+            "{} is `{}`, but there is no [env.{}]",
+            PROFILE_ENV, name, name
+        ),
+    }
+}
+
+// Environment variables override `config.toml`, so one package can be promoted
+// between services. `BQ_CONFIG__<SECTION>__<FIELD>` sets any field, e.g.
+// `BQ_CONFIG__BIGQUERY__LOCATION=EU`; values are read as TOML where they parse,
+// so numbers, booleans and arrays work, and as strings otherwise. The aliases
+// below cover the common cases.
+const ENV_PREFIX: &str = "BQ_CONFIG__";
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("BQ_PROJECT_ID", "bigquery.projectid"),
+    ("BQ_DATASET_TABLE", "bigquery.dataset_tableid"),
+    ("BQ_LOCATION", "bigquery.location"),
+    ("BQ_SERVICE_ACCOUNT_EMAIL", "bigquery.service_account_email"),
+];
+
+fn env_value(raw: &str) -> toml::Value {
+    // Dates stay strings; the config has no datetime fields.
+    match toml::from_str::<toml::value::Table>(&format!("value = {}", raw)) {
+        Ok(mut x) => match x.remove("value") {
+            Some(toml::Value::Datetime(_)) | None => raw.into(),
+            Some(x) => x,
+        },
+        Err(_) => raw.into(),
+    }
+}
+
+fn set_path(config: &mut toml::Value, path: &[String], value: toml::Value) {
+    let (field, tables) = match path.split_last() {
+        Some(x) => x,
+        None => return,
+    };
+    let mut table = config;
+    for name in tables {
+        table = match table {
+            toml::Value::Table(x) => x
+                .entry(name.clone())
+                .or_insert_with(|| toml::Value::Table(Default::default())),
+            _ => return,
+        };
+    }
+    match table {
+        toml::Value::Table(x) => {
+            x.insert(field.clone(), value);
+        }
+        _ => error!("Cannot override {}: not a table", path.join(".")),
+    }
+}
+
+fn apply_env_overrides(config: &mut toml::Value) {
+    for (name, raw) in std::env::vars() {
+        let path: Vec<String> = if let Some(x) = name.strip_prefix(ENV_PREFIX) {
+            x.split("__").map(|x| x.to_ascii_lowercase()).collect()
+        } else if let Some((_, x)) = ENV_ALIASES.iter().find(|(alias, _)| *alias == name) {
+            x.split('.').map(str::to_string).collect()
+        } else {
+            continue;
+        };
+        set_path(config, &path, env_value(&raw));
+    }
+}
+
+// The `overrides` entry of the `[bigquery] config_store` holds TOML that is
+// merged over this file on every request, e.g. `[rate_limit]
+// get_per_minute = 100`, so limits, TTLs and the like can be tuned without a
+// redeploy. It is re-read at most every few seconds; tables merge key by key,
+// anything else replaces the file's value.
+const OVERRIDES_ENTRY: &str = "overrides";
+const OVERRIDES_CACHE_TTL: Duration = Duration::from_secs(5);
+
+thread_local! {
+    static OVERRIDES: RefCell<Option<(Instant, Option<toml::Value>)>> = const { RefCell::new(None) };
+}
+
+fn read_overrides(store_name: &str) -> Option<toml::Value> {
+    let store = match ConfigStore::try_open(store_name) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Opening Config Store {} failed: {}", store_name, e);
+            return None;
+        }
+    };
+    let raw = match store.try_get(OVERRIDES_ENTRY) {
+        Ok(x) => x?,
+        Err(e) => {
+            error!(
+                "Reading {} from Config Store {} failed: {}",
+                OVERRIDES_ENTRY, store_name, e
+            );
+            return None;
+        }
+    };
+    match toml::from_str(&raw) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            error!(
+                "Config Store {} entry {} is not TOML: {}",
+                store_name, OVERRIDES_ENTRY, e
+            );
+            None
+        }
+    }
+}
+
+fn store_overrides(config: &toml::Value) -> Option<toml::Value> {
+    let store_name = config.get("bigquery")?.get("config_store")?.as_str()?;
+    OVERRIDES.with(|x| {
+        let mut cached = x.borrow_mut();
+        if let Some((read_at, overrides)) = cached.as_ref() {
+            if read_at.elapsed() < OVERRIDES_CACHE_TTL {
+                return overrides.clone();
+            }
+        }
+        let overrides = read_overrides(store_name);
+        *cached = Some((Instant::now(), overrides.clone()));
+        overrides
+    })
+}
+
+fn merge(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(x) => merge(x, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+// A problem `Config::validate` found, naming the offending field.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{0} is empty")]
+    Empty(String),
+    #[error("{0} must be an http(s) URL, not `{1}`")]
+    NotUrl(String, String),
+    #[error("{0} `{1}` is not supported")]
+    Unsupported(String, String),
+    #[error("{0} must be `dataset.table`, not `{1}`")]
+    InvalidTableId(String, String),
+    #[error("{0} has no usable private key; set service_account_key or check its Secret Store")]
+    MissingKey(String),
+    #[error("{0} is not a PEM private key: {1}")]
+    InvalidKey(String, String),
+    #[error("{0} names `{1}`, but there is no [credentials.{1}]")]
+    UnknownCredentials(String, String),
+    #[error("the profile and environment overrides do not fit the configuration: {0}")]
+    InvalidOverride(String),
+}
+
+fn is_url(value: &str) -> bool {
+    ["https://", "http://"]
+        .iter()
+        .filter_map(|x| value.strip_prefix(x))
+        .any(|x| !x.is_empty() && !x.starts_with('/'))
+}
+
+fn check_not_empty(errors: &mut Vec<ConfigError>, field: &str, value: &str) {
+    if value.trim().is_empty() {
+        errors.push(ConfigError::Empty(field.to_string()));
+    }
+}
+
+fn check_url(errors: &mut Vec<ConfigError>, field: &str, value: &str) {
+    if !is_url(value) {
+        errors.push(ConfigError::NotUrl(field.to_string(), value.to_string()));
+    }
+}
+
+fn check_table(
+    errors: &mut Vec<ConfigError>,
+    prefix: &str,
+    projectid: &str,
+    dataset_tableid: &str,
+) {
+    check_not_empty(errors, &format!("{}.projectid", prefix), projectid);
+    match dataset_tableid.split_once('.') {
+        Some((dataset, table)) if !dataset.is_empty() && !table.is_empty() => {}
+        _ => errors.push(ConfigError::InvalidTableId(
+            format!("{}.dataset_tableid", prefix),
+            dataset_tableid.to_string(),
+        )),
+    }
 }
 
 impl Config {
+    // Checks the settings that would otherwise only fail deep inside a
+    // request, e.g. while signing the token request, and lists every problem
+    // found.
+    pub fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        if let Some(e) = &self.override_error {
+            errors.push(ConfigError::InvalidOverride(e.clone()));
+        }
+        if self.gcp.alg != "RS256" {
+            errors.push(ConfigError::Unsupported(
+                "gcp.alg".to_string(),
+                self.gcp.alg.clone(),
+            ));
+        }
+        check_url(&mut errors, "gcp.aud", &self.gcp.aud);
+        let bigquery = &self.bigquery;
+        check_table(
+            &mut errors,
+            "bigquery",
+            &bigquery.projectid,
+            &bigquery.dataset_tableid,
+        );
+        for scope in bigquery.scopes() {
+            check_url(&mut errors, "bigquery.scope", scope);
+        }
+        if let Some(account) = &bigquery.external_account {
+            check_not_empty(
+                &mut errors,
+                "bigquery.external_account.audience",
+                &account.audience,
+            );
+        } else if !bigquery.oauth_passthrough.unwrap_or(false) {
+            check_not_empty(
+                &mut errors,
+                "bigquery.service_account_email",
+                &bigquery.service_account_email,
+            );
+            let keys = bigquery.service_account_keys();
+            if keys.is_empty() {
+                errors.push(ConfigError::MissingKey("[bigquery]".to_string()));
+            }
+            for (key_id, key) in keys {
+                if let Err(e) = RS256KeyPair::from_pem(key) {
+                    let field = match key_id {
+                        Some(x) => format!("bigquery key {}", x),
+                        None => "bigquery.service_account_key".to_string(),
+                    };
+                    errors.push(ConfigError::InvalidKey(field, e.to_string()));
+                }
+            }
+        }
+        let references = [
+            ("bigquery.read_credentials", &bigquery.read_credentials),
+            ("bigquery.write_credentials", &bigquery.write_credentials),
+        ];
+        for (field, name) in references {
+            if let Some(name) = name {
+                if !self.credentials.iter().any(|x| x.contains_key(name)) {
+                    errors.push(ConfigError::UnknownCredentials(
+                        field.to_string(),
+                        name.clone(),
+                    ));
+                }
+            }
+        }
+        for (name, account) in self.credentials.iter().flatten() {
+            if account.external_account.is_none() {
+                check_not_empty(
+                    &mut errors,
+                    &format!("credentials.{}.service_account_email", name),
+                    &account.service_account_email,
+                );
+            }
+        }
+        let column_lists = std::iter::once(("bigquery.columns".to_string(), &bigquery.columns))
+            .chain(
+                self.tables
+                    .iter()
+                    .flatten()
+                    .map(|(name, x)| (format!("tables.{}.columns", name), &x.columns)),
+            );
+        for (field, columns) in column_lists {
+            for column in columns.iter().flatten() {
+                check_not_empty(&mut errors, &field, &column.name);
+                if ColumnType::parse(&column.kind).is_none() {
+                    errors.push(ConfigError::Unsupported(
+                        format!("{} `{}` type", field, column.name),
+                        column.kind.clone(),
+                    ));
+                }
+            }
+        }
+        for (i, target) in self.targets.iter().flatten().enumerate() {
+            check_table(
+                &mut errors,
+                &format!("targets[{}]", i),
+                &target.projectid,
+                &target.dataset_tableid,
+            );
+        }
+        for (name, table) in self.tables.iter().flatten() {
+            let prefix = format!("tables.{}", name);
+            check_table(
+                &mut errors,
+                &prefix,
+                &table.projectid,
+                &table.dataset_tableid,
+            );
+            for operation in table.operations.iter().flatten() {
+                if !TABLE_OPERATIONS.contains(&operation.as_str()) {
+                    errors.push(ConfigError::Unsupported(
+                        format!("{}.operations", prefix),
+                        operation.clone(),
+                    ));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // `config`, the file with the profile and environment overrides applied. If
+    // they don't fit, the file alone is used and the error kept, so `validate`
+    // answers every request with it instead of the service panicking.
+    fn parse(config: toml::Value, file: &toml::Value) -> (Self, Option<String>) {
+        match config.try_into() {
+            Ok(x) => (x, None),
+            Err(e) => {
+                let config = file.clone().try_into().unwrap();
+                (config, Some(e.to_string()))
+            }
+        }
+    }
+
     pub fn load() -> Self {
-        let config: Config = toml::from_str(include_str!("config.toml")).unwrap();
-        let gcp: GcpConfiguration = config.gcp;
-        let bigquery: BqConfiguration = config.bigquery;
-        Self { gcp, bigquery }
+        let file: toml::Value = toml::from_str(include_str!("config.toml")).unwrap();
+        let mut config = file.clone();
+        apply_profile(&mut config);
+        apply_env_overrides(&mut config);
+        let overridden = store_overrides(&config).map(|overrides| {
+            let mut x = config.clone();
+            merge(&mut x, overrides);
+            x
+        });
+        // A bad override is logged and ignored rather than taking the service
+        // down.
+        let (config, override_error): (Config, Option<String>) =
+            match overridden.map(|x| x.try_into::<Config>()) {
+                Some(Ok(x)) => (x, None),
+                Some(Err(e)) => {
+                    error!("Ignoring Config Store overrides: {}", e);
+                    Self::parse(config, &file)
+                }
+                None => Self::parse(config, &file),
+            };
+        let mut gcp: GcpConfiguration = config.gcp;
+        let mut bigquery: BqConfiguration = config.bigquery;
+        apply_config_store(&mut gcp, &mut bigquery);
+        bigquery.resolve_credentials();
+        let tee: Option<TeeConfiguration> = config.tee;
+        let api_keys: Option<HashMap<String, ApiKeyConfiguration>> = config.api_keys;
+        let admin: Option<AdminConfiguration> = config.admin;
+        let tiers: Option<HashMap<String, TierConfiguration>> = config.tiers;
+        let targets: Option<Vec<TargetConfiguration>> = config.targets;
+        let ingest: Option<IngestConfiguration> = config.ingest;
+        let export: Option<ExportConfiguration> = config.export;
+        let aggregates: Option<HashMap<String, AggregateConfiguration>> = config.aggregates;
+        let token_cache: Option<TokenCacheConfiguration> = config.token_cache;
+        let credentials: Option<HashMap<String, CredentialConfiguration>> = config.credentials;
+        let auth: Option<AuthConfiguration> = config.auth;
+        let oidc: Option<OidcConfiguration> = config.oidc;
+        let webhook: Option<WebhookConfiguration> = config.webhook;
+        let mtls: Option<MtlsConfiguration> = config.mtls;
+        let rate_limit: Option<RateLimitConfiguration> = config.rate_limit;
+        let tables: Option<HashMap<String, TableConfiguration>> = config.tables;
+        let cors: Option<CorsConfiguration> = config.cors;
+        let compression: Option<CompressionConfiguration> = config.compression;
+        let cache: Option<CacheConfiguration> = config.cache;
+        let health: Option<HealthConfiguration> = config.health;
+        let limits: Option<LimitsConfiguration> = config.limits;
+        Self {
+            gcp,
+            bigquery,
+            tee,
+            api_keys,
+            admin,
+            tiers,
+            targets,
+            ingest,
+            export,
+            aggregates,
+            token_cache,
+            credentials,
+            auth,
+            oidc,
+            webhook,
+            mtls,
+            rate_limit,
+            tables,
+            cors,
+            compression,
+            cache,
+            health,
+            limits,
+            override_error,
+        }
+    }
+
+    // Switches the `[bigquery]` account to `[credentials.<name>]`.
+    pub fn use_credentials(&mut self, name: &str) -> Result<(), AppError> {
+        let account = self
+            .credentials
+            .as_mut()
+            .and_then(|x| x.remove(name))
+            .ok_or_else(|| AppError::Config(format!("Credentials {} are not configured", name)))?;
+        self.bigquery.service_account_email = account.service_account_email;
+        self.bigquery.service_account_key = account.service_account_key;
+        self.bigquery.service_account_key_id = account.service_account_key_id;
+        self.bigquery.service_account_keys = account.service_account_keys;
+        self.bigquery.secret_store = account.secret_store;
+        self.bigquery.service_account_key_secret = account.service_account_key_secret;
+        self.bigquery.subject = account.subject;
+        self.bigquery.external_account = account.external_account;
+        self.bigquery.resolve_credentials();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file() -> toml::Value {
+        toml::from_str(include_str!("config.toml")).unwrap()
+    }
+
+    fn errors(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(x) => x.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn merge_keeps_unrelated_keys() {
+        let mut base: toml::Value = toml::from_str("[a]\nx = 1\ny = [1]\n[b]\nz = 1").unwrap();
+        let overlay: toml::Value = toml::from_str("[a]\ny = [2, 3]\nw = 4").unwrap();
+        merge(&mut base, overlay);
+        let expected: toml::Value =
+            toml::from_str("[a]\nx = 1\ny = [2, 3]\nw = 4\n[b]\nz = 1").unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn set_path_creates_tables() {
+        let mut config: toml::Value = toml::from_str("[a]\nx = 1").unwrap();
+        let path: Vec<String> = ["a", "b", "c"].iter().map(|x| x.to_string()).collect();
+        set_path(&mut config, &path, env_value("2"));
+        assert_eq!(config["a"]["x"].as_integer(), Some(1));
+        assert_eq!(config["a"]["b"]["c"].as_integer(), Some(2));
+    }
+
+    #[test]
+    fn env_values_are_toml_or_strings() {
+        assert_eq!(env_value("100"), toml::Value::Integer(100));
+        assert_eq!(env_value("true"), toml::Value::Boolean(true));
+        assert_eq!(env_value("[\"a\"]"), toml::Value::Array(vec!["a".into()]));
+        assert_eq!(env_value("2024-01-01"), toml::Value::from("2024-01-01"));
+        assert_eq!(env_value("my-project"), toml::Value::from("my-project"));
+    }
+
+    #[test]
+    fn bad_overrides_fall_back_to_the_file() {
+        let file = file();
+        let mut config = file.clone();
+        let path: Vec<String> = ["bigquery", "projectid"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        set_path(&mut config, &path, env_value("[1]"));
+        let (config, override_error) = Config::parse(config, &file);
+        assert_eq!(
+            config.bigquery.projectid,
+            file["bigquery"]["projectid"].as_str().unwrap()
+        );
+        let config = Config {
+            override_error,
+            ..config
+        };
+        assert!(errors(&config)[0].starts_with("the profile and environment overrides"));
+    }
+
+    #[test]
+    fn validate_names_broken_fields() {
+        let file = file();
+        let (mut config, _) = Config::parse(file.clone(), &file);
+        config.gcp.alg = "HS256".to_string();
+        config.bigquery.dataset_tableid = "table".to_string();
+        let errors = errors(&config);
+        assert!(errors.contains(&"gcp.alg `HS256` is not supported".to_string()));
+        assert!(errors.contains(
+            &"bigquery.dataset_tableid must be `dataset.table`, not `table`".to_string()
+        ));
     }
 }
@@ -0,0 +1,111 @@
+use crate::auth::API_KEY_HEADER;
+use crate::config::{Config, CorsConfiguration};
+use crate::error::AppError;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{Request, Response};
+use log::error;
+
+const DEFAULT_METHODS: &[&str] = &["GET", "HEAD"];
+const DEFAULT_HEADERS: &[&str] = &["Content-Type", "Authorization", API_KEY_HEADER];
+const DEFAULT_MAX_AGE_SECS: u64 = 600;
+
+// With `[cors]`, browsers on the listed origins may call the service. Preflight
+// OPTIONS requests are answered here, before any authentication, since
+// browsers send them without credentials; other responses get
+// Access-Control-Allow-Origin when the request's Origin is allowed.
+fn allowed_origin<'a>(cors: &CorsConfiguration, origin: &'a str) -> Option<&'a str> {
+    if cors.allowed_origins.iter().any(|x| x == "*") {
+        return Some("*");
+    }
+    cors.allowed_origins
+        .iter()
+        .any(|x| x.eq_ignore_ascii_case(origin))
+        .then_some(origin)
+}
+
+fn allowed_methods(cors: &CorsConfiguration) -> Vec<String> {
+    match &cors.allowed_methods {
+        Some(x) => x.iter().map(|x| x.to_ascii_uppercase()).collect(),
+        None => DEFAULT_METHODS.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+fn allowed_headers(cors: &CorsConfiguration) -> Vec<String> {
+    match &cors.allowed_headers {
+        Some(x) => x.clone(),
+        None => DEFAULT_HEADERS.iter().map(|x| x.to_string()).collect(),
+    }
+}
+
+fn forbidden(msg: String) -> Response {
+    error!("{}", msg);
+    Response::from(AppError::Forbidden(msg))
+}
+
+// Routing middleware: the answer to a CORS preflight, 204 with the allowed
+// methods and headers, or 403 when the origin, method or a header is not
+// allowed. Other requests get None.
+pub fn preflight(tomlfile: &Config, req: &Request) -> Option<Response> {
+    let cors = tomlfile.cors.as_ref()?;
+    if req.get_method() != Method::OPTIONS {
+        return None;
+    }
+    let origin = req.get_header_str(header::ORIGIN)?;
+    let method = req.get_header_str(header::ACCESS_CONTROL_REQUEST_METHOD)?;
+    let allow_origin = match allowed_origin(cors, origin) {
+        Some(x) => x.to_string(),
+        None => {
+            return Some(forbidden(format!(
+                "CORS origin `{}` is not allowed",
+                origin
+            )))
+        }
+    };
+    let methods = allowed_methods(cors);
+    if !methods.iter().any(|x| x.eq_ignore_ascii_case(method)) {
+        return Some(forbidden(format!(
+            "CORS method `{}` is not allowed",
+            method
+        )));
+    }
+    let headers = allowed_headers(cors);
+    let requested = req
+        .get_header_str(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .unwrap_or("");
+    for name in requested
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+    {
+        if !headers.iter().any(|x| x.eq_ignore_ascii_case(name)) {
+            return Some(forbidden(format!("CORS header `{}` is not allowed", name)));
+        }
+    }
+    let max_age = cors.max_age_secs.unwrap_or(DEFAULT_MAX_AGE_SECS);
+    Some(
+        Response::from_status(StatusCode::NO_CONTENT)
+            .with_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .with_header(header::ACCESS_CONTROL_ALLOW_METHODS, methods.join(", "))
+            .with_header(header::ACCESS_CONTROL_ALLOW_HEADERS, headers.join(", "))
+            .with_header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string())
+            .with_header(header::VARY, "Origin"),
+    )
+}
+
+// Adds the CORS headers for `origin`, the request's Origin, to a response.
+pub fn apply_headers(tomlfile: &Config, origin: Option<&str>, resp: &mut Response) {
+    let cors = match &tomlfile.cors {
+        Some(x) => x,
+        None => return,
+    };
+    // Responses differ by Origin, so caches must key on it.
+    resp.append_header(header::VARY, "Origin");
+    let allow_origin = match origin.and_then(|x| allowed_origin(cors, x)) {
+        Some(x) => x.to_string(),
+        None => return,
+    };
+    resp.set_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+    if let Some(x) = &cors.expose_headers {
+        resp.set_header(header::ACCESS_CONTROL_EXPOSE_HEADERS, x.join(", "));
+    }
+}
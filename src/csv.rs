@@ -0,0 +1,102 @@
+// Minimal RFC 4180 CSV: comma separated, `"` quoting with `""` escapes, LF or
+// CRLF line endings.
+
+// Appends one record, quoting fields that contain a comma, quote or line
+// break, and terminates it with CRLF.
+pub fn write_record<S: AsRef<str>>(out: &mut String, fields: &[S]) {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let field = field.as_ref();
+        if field.contains([',', '"', '\n', '\r']) {
+            out.push('"');
+            out.push_str(&field.replace('"', "\"\""));
+            out.push('"');
+        } else {
+            out.push_str(field);
+        }
+    }
+    out.push_str("\r\n");
+}
+
+// Splits a CSV document into records of fields. Blank lines are skipped.
+pub fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut started = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                quoted = true;
+                started = true;
+            }
+            ',' => {
+                record.push(std::mem::take(&mut field));
+                started = true;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                if started || !field.is_empty() {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                }
+                started = false;
+            }
+            _ => {
+                field.push(c);
+                started = true;
+            }
+        }
+    }
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+    if started || !field.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_fields_and_line_endings() {
+        let text = "a,\"b,\"\"c\"\"\"\r\n\n\"multi\nline\",\n";
+        assert_eq!(
+            parse(text).unwrap(),
+            [vec!["a", "b,\"c\""], vec!["multi\nline", ""]]
+        );
+        assert_eq!(parse("x,y").unwrap(), [vec!["x", "y"]]);
+        assert!(parse("\"open").is_err());
+    }
+
+    #[test]
+    fn written_records_parse_back() {
+        let fields = ["plain", "with,comma", "with \"quote\"", "two\nlines", ""];
+        let mut out = String::new();
+        write_record(&mut out, &fields);
+        assert_eq!(
+            out,
+            "plain,\"with,comma\",\"with \"\"quote\"\"\",\"two\nlines\",\r\n"
+        );
+        assert_eq!(parse(&out).unwrap(), [fields.to_vec()]);
+    }
+}
@@ -0,0 +1,185 @@
+use crate::auth::API_KEY_HEADER;
+use crate::config::Config;
+use crate::gcp::{if_none_match, load_config};
+use crate::router::{Handler, Params};
+use crate::stats;
+use crate::stream::is_streamed;
+use fastly::cache::core::{self, CacheKey};
+use fastly::http::purge::purge_surrogate_key;
+use fastly::http::{header, StatusCode};
+use fastly::{Error, Request, Response};
+use log::error;
+use std::io::Write;
+use std::time::Duration;
+
+const DEFAULT_TTL_SECS: u64 = 60;
+const CACHE_STATUS_HEADER: &str = "X-Cache";
+// Per-request headers that are not cached with the body.
+const UNCACHED_HEADERS: &[&str] = &[
+    "X-Quota-Remaining",
+    "X-Quota-Bytes-Remaining",
+    "X-Quota-Reset",
+    "Warning",
+];
+
+// With `[cache]`, GET results are kept in Fastly's cache for `ttl_secs`,
+// tagged with a surrogate key naming their table and with ALL_TABLES_KEY.
+// Successful writes to a table purge its key; session commits and
+// `/admin/query`, which may write any table, purge ALL_TABLES_KEY. So a cached
+// read is never older than the last write through the service that has
+// finished. `/admin/load` only starts a job, so its rows show up once entries
+// expire, and snapshots only create a new table. Entries are keyed on
+// the URL, Accept and the API key, whose rows may differ; in OAuth passthrough
+// mode nothing is cached.
+const ALL_TABLES_KEY: &str = "tables";
+
+fn surrogate_key(tomlfile: &Config) -> String {
+    format!(
+        "table:{}.{}",
+        tomlfile.bigquery.projectid, tomlfile.bigquery.dataset_tableid
+    )
+}
+
+fn cache_key(req: &Request) -> CacheKey {
+    let source = format!(
+        "{} {}\n{}\n{}",
+        req.get_method_str(),
+        req.get_url_str(),
+        req.get_header_str(header::ACCEPT).unwrap_or(""),
+        req.get_header_str(API_KEY_HEADER).unwrap_or("")
+    );
+    CacheKey::from(hmac_sha256::Hash::hash(source.as_bytes()).to_vec())
+}
+
+fn enabled(tomlfile: &Config) -> bool {
+    tomlfile.cache.is_some() && !tomlfile.bigquery.oauth_passthrough.unwrap_or(false)
+}
+
+fn cached_response(req: &Request, key: CacheKey) -> Option<Response> {
+    let found = match core::lookup(key).execute() {
+        Ok(x) => x?,
+        Err(e) => {
+            error!("Cache lookup error: {}", e);
+            return None;
+        }
+    };
+    let headers: Vec<(String, String)> = serde_json::from_slice(&found.user_metadata()).ok()?;
+    let body = match found.to_stream() {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Cache read error: {}", e);
+            return None;
+        }
+    };
+    let mut resp = Response::from_status(StatusCode::OK);
+    for (name, value) in headers {
+        resp.append_header(name, value);
+    }
+    let etag = resp.get_header_str(header::ETAG).map(str::to_string);
+    let mut resp = match etag {
+        Some(x) if if_none_match(req, &x) => Response::from_status(StatusCode::NOT_MODIFIED)
+            .with_header(header::ETAG, x)
+            .with_header(header::VARY, "Accept"),
+        _ => resp.with_body(body),
+    };
+    resp.set_header(CACHE_STATUS_HEADER, "HIT");
+    Some(resp)
+}
+
+fn store_response(tomlfile: &Config, key: CacheKey, resp: &mut Response) {
+    let ttl = tomlfile
+        .cache
+        .as_ref()
+        .and_then(|x| x.ttl_secs)
+        .unwrap_or(DEFAULT_TTL_SECS);
+    let headers: Vec<(String, String)> = resp
+        .get_headers()
+        .filter(|(name, _)| {
+            !UNCACHED_HEADERS
+                .iter()
+                .any(|x| name.as_str().eq_ignore_ascii_case(x))
+        })
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let metadata = match serde_json::to_vec(&headers) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Cache metadata error: {}", e);
+            return;
+        }
+    };
+    let body = resp.take_body_bytes();
+    let table = surrogate_key(tomlfile);
+    let written = core::insert(key, Duration::from_secs(ttl))
+        .surrogate_keys([table.as_str(), ALL_TABLES_KEY])
+        .known_length(body.len() as u64)
+        .user_metadata(CacheKey::from(metadata))
+        .execute()
+        .map_err(Error::from)
+        .and_then(|mut x| {
+            x.write_all(&body)?;
+            Ok(x.finish()?)
+        });
+    if let Err(e) = written {
+        error!("Cache insert error: {}", e);
+    }
+    resp.set_body(body);
+}
+
+// Serves a GET route from the cache, or runs `handler` and caches its 200.
+// Streamed results are never held whole, so they are not cached.
+pub fn cached_read(
+    req: &mut Request,
+    params: &Params,
+    handler: Handler,
+) -> Result<Response, Error> {
+    let tomlfile = load_config(req, params)?;
+    if !enabled(&tomlfile) {
+        return handler(req, params);
+    }
+    let key = cache_key(req);
+    if let Some(resp) = cached_response(req, key.clone()) {
+        stats::cache_hit(stats::EDGE_CACHE);
+        return Ok(resp);
+    }
+    stats::cache_miss(stats::EDGE_CACHE);
+    let mut resp = handler(req, params)?;
+    if resp.get_status() == StatusCode::OK && !is_streamed() {
+        store_response(&tomlfile, key, &mut resp);
+    }
+    resp.set_header(CACHE_STATUS_HEADER, "MISS");
+    Ok(resp)
+}
+
+fn purge(tomlfile: &Config, resp: &Response, key: &str) {
+    if enabled(tomlfile) && resp.get_status().is_success() {
+        if let Err(e) = purge_surrogate_key(key) {
+            error!("Cache purge error: {}", e);
+        }
+    }
+}
+
+// Runs a write route's `handler`, then purges the table's cached reads if the
+// write succeeded.
+pub fn purge_after_write(
+    req: &mut Request,
+    params: &Params,
+    handler: Handler,
+) -> Result<Response, Error> {
+    let tomlfile = load_config(req, params)?;
+    let resp = handler(req, params)?;
+    purge(&tomlfile, &resp, &surrogate_key(&tomlfile));
+    Ok(resp)
+}
+
+// Like purge_after_write, for writes whose tables aren't known up front:
+// every cached read is purged.
+pub fn purge_all_after_write(
+    req: &mut Request,
+    params: &Params,
+    handler: Handler,
+) -> Result<Response, Error> {
+    let resp = handler(req, params)?;
+    purge(&Config::load(), &resp, ALL_TABLES_KEY);
+    Ok(resp)
+}
@@ -0,0 +1,153 @@
+use crate::config::ConfigError;
+use crate::request_id;
+use crate::stats;
+use fastly::http::StatusCode;
+use fastly::{Error, Request, Response};
+use log::error;
+
+// What went wrong while answering a request. The kind decides the status the
+// client gets: 400 for bad input, 401 and 403 for authentication, 429 and 402
+// for rate limits and quotas, 502 and 504 when BigQuery or another dependency
+// fails, 500 when the service is misconfigured. Handlers return it through
+// fastly::Error, e.g. `return Err(AppError::BadRequest(msg).into())`, and
+// `main` answers it with a JSON body:
+// `{"error": {"status": 400, "code": "bad_request", "message": .., "request_id": ..}}`.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    MethodNotAllowed(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    #[error("{0}")]
+    TooManyRequests(String),
+    // A caller's bytes budget is spent.
+    #[error("{0}")]
+    PaymentRequired(String),
+    // config.toml failed `Config::validate`.
+    #[error("Invalid configuration: {}", list(.0))]
+    InvalidConfig(Vec<ConfigError>),
+    // A misconfiguration validation cannot see, like an unlinked KV Store.
+    #[error("{0}")]
+    Config(String),
+    // No access token could be had for BigQuery.
+    #[error("Token Request Error: {0}")]
+    Token(String),
+    // BigQuery answered `operation` with an error status. `reason` is the
+    // first of its error reasons, e.g. `invalidQuery` or `rateLimitExceeded`.
+    #[error("BQ {operation} Request error: {status}: {message}")]
+    BigQuery {
+        operation: &'static str,
+        status: StatusCode,
+        reason: Option<String>,
+        message: String,
+    },
+    // BigQuery or another dependency failed or answered something unusable.
+    #[error("{0}")]
+    Upstream(String),
+    // BigQuery did not finish in time.
+    #[error("{0}")]
+    Timeout(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+fn list(errors: &[ConfigError]) -> String {
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    errors.join("; ")
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::PaymentRequired(_) => StatusCode::PAYMENT_REQUIRED,
+            AppError::InvalidConfig(_) | AppError::Config(_) | AppError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AppError::Token(_) | AppError::BigQuery { .. } | AppError::Upstream(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            AppError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    // BigQuery's reason code, for errors BigQuery answered.
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            AppError::BigQuery { reason, .. } => reason.as_deref(),
+            _ => None,
+        }
+    }
+
+    // `e` as an AppError; errors that are not one are Internal.
+    pub fn of(e: Error) -> Self {
+        match e.downcast::<AppError>() {
+            Ok(x) => x,
+            Err(e) => AppError::Internal(e.to_string()),
+        }
+    }
+}
+
+// `bad_request`, `too_many_requests`, ...: the status's reason phrase.
+fn status_code_name(status: StatusCode) -> String {
+    status
+        .canonical_reason()
+        .unwrap_or("error")
+        .to_ascii_lowercase()
+        .replace([' ', '-'], "_")
+}
+
+// The structured error body. Errors BigQuery answered also carry its status
+// and reason as `upstream`.
+impl From<AppError> for Response {
+    fn from(e: AppError) -> Self {
+        let status = e.status();
+        let mut body = serde_json::json!({
+            "status": status.as_u16(),
+            "code": status_code_name(status),
+            "message": e.to_string(),
+            "request_id": request_id::current(),
+        });
+        if let AppError::BigQuery { status, reason, .. } = &e {
+            body["upstream"] = serde_json::json!({
+                "status": status.as_u16(),
+                "reason": reason,
+            });
+        }
+        Response::from_status(status)
+            .with_content_type(fastly::mime::APPLICATION_JSON)
+            .with_body(serde_json::json!({ "error": body }).to_string())
+    }
+}
+
+// The request's JSON body as `T`. A body that doesn't parse is the client's
+// mistake and gets 400.
+pub fn json_body<T: serde::de::DeserializeOwned>(req: &mut Request) -> Result<T, AppError> {
+    req.take_body_json::<T>()
+        .map_err(|e| AppError::BadRequest(format!("Request body is not valid: {}", e)))
+}
+
+// The response for an error a handler returned, which is logged here once.
+pub fn error_response(e: Error) -> Response {
+    let e = AppError::of(e);
+    error!("{}", e);
+    stats::record_error(&e);
+    e.into()
+}
@@ -1,12 +1,35 @@
-use crate::config::Config;
+use crate::auth::{authenticate, ApiKey};
+use crate::config::{Config, SchemaFieldConfiguration};
+use crate::csv;
+use crate::error::{json_body, AppError};
+use crate::geography::wkt_to_geojson;
+use crate::mtls::require_client_certificate;
+use crate::query::QueryBuilder;
+use crate::router::Params;
+use crate::quota;
+use crate::request_id;
+use crate::rows::{
+    format_response, geojson_fields, records_response, write_csv, write_ndjson, BqColumnar, BqRows, OutputFormat,
+    RowStream,
+};
+use crate::schema::fetched_schema;
+use crate::storage_read::write_table_rows;
+use crate::storage_write::{append_rows, Column, ColumnType};
+use crate::stream::{self, streamed};
+use crate::tee::{tee_query, TeeRecord};
+use crate::token::bigquery_access_token;
+use crate::webhook::constant_time_eq;
 use anyhow::anyhow;
-use fastly::http::StatusCode;
-use fastly::{panic_with_status, Error, Request, Response};
-use jwt_simple::algorithms::{RS256KeyPair, RSAKeyPairLike};
-use jwt_simple::claims::Claims;
-use jwt_simple::prelude::Duration;
-use log::error;
-use time::{format_description, Date, OffsetDateTime};
+use fastly::http::body::StreamingBody;
+use fastly::http::{header, Method, StatusCode};
+use fastly::{mime, Body, Error, Request, Response};
+use log::{debug, error};
+use rand::{Rng, RngCore};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
 
 #[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
 pub struct BqQueryReq {
@@ -14,287 +37,3028 @@ pub struct BqQueryReq {
     query: String,
     location: String,
     use_legacy_sql: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameter_mode: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    query_parameters: Vec<QueryParameter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_results: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maximum_bytes_billed: Option<String>,
+    #[serde(skip_serializing_if = "HashMap::is_empty", default)]
+    labels: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    use_query_cache: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    create_session: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    connection_properties: Vec<ConnectionProperty>,
+    // Makes jobs.query idempotent, so a retried DML statement runs once.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+// Connection property of a query, e.g. `session_id`.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub struct ConnectionProperty {
+    key: String,
+    value: String,
+}
+
+// Per-call settings for jobs.query / jobs.getQueryResults.
+#[derive(Default, Debug, Clone)]
+pub struct QueryOptions {
+    pub max_results: Option<u32>,
+    pub timeout_ms: Option<u32>,
+    // Validate and estimate the query without running it.
+    pub dry_run: bool,
+    // Job location; None falls back to the configured one.
+    pub location: Option<String>,
+    // SQL dialect; None falls back to the configured one.
+    pub use_legacy_sql: Option<bool>,
+    // Job labels for audit logs and the billing export.
+    pub labels: HashMap<String, String>,
+    // Whether BigQuery may answer from its results cache; None falls back to
+    // the configured setting, then BigQuery's default (true).
+    pub use_query_cache: Option<bool>,
+    // Start a new BigQuery session with this query.
+    pub create_session: bool,
+    // Run the query inside an existing session.
+    pub session_id: Option<String>,
+    // Bind parameters to `?` placeholders in order (POSITIONAL) rather than
+    // to `@name` (NAMED, the default).
+    pub positional_params: bool,
+}
+
+impl QueryOptions {
+    pub fn legacy_sql(&self, tomlfile: &Config) -> bool {
+        self.use_legacy_sql
+            .or(tomlfile.bigquery.use_legacy_sql)
+            .unwrap_or(false)
+    }
+
+    pub fn query_cache(&self, tomlfile: &Config) -> Option<bool> {
+        self.use_query_cache.or(tomlfile.bigquery.use_query_cache)
+    }
+
+    // The job's labels, with the X-Request-Id of the request that runs it.
+    fn job_labels(&self) -> HashMap<String, String> {
+        let mut labels = self.labels.clone();
+        labels.insert("request_id".to_string(), label_value(&request_id::current()));
+        labels
+    }
+
+    fn session_create(&self) -> Option<bool> {
+        if self.create_session {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    fn parameter_mode(&self, params: &[QueryParameter]) -> Option<String> {
+        match (params.is_empty(), self.positional_params) {
+            (true, _) => None,
+            (false, true) => Some(PARAMETER_MODE_POSITIONAL.to_string()),
+            (false, false) => Some(PARAMETER_MODE_NAMED.to_string()),
+        }
+    }
+
+    // Positional parameters are sent without names.
+    fn query_parameters(&self, params: &[QueryParameter]) -> Vec<QueryParameter> {
+        let mut params = params.to_vec();
+        if self.positional_params {
+            for param in params.iter_mut() {
+                param.name.clear();
+            }
+        }
+        params
+    }
+
+    fn connection_properties(&self) -> Vec<ConnectionProperty> {
+        match &self.session_id {
+            Some(x) => vec![ConnectionProperty {
+                key: "session_id".to_string(),
+                value: x.to_string(),
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+// Request header naming the session to run in; `new` starts one. The session
+// a query ran in is returned in SESSION_ID_HEADER.
+pub const SESSION_HEADER: &str = "X-BigQuery-Session";
+pub const SESSION_ID_HEADER: &str = "X-BigQuery-Session-Id";
+const NEW_SESSION: &str = "new";
+
+// Per-request override of the configured job location, e.g. `EU`.
+pub const LOCATION_HEADER: &str = "X-BigQuery-Location";
+
+// Job location from config, defaulting to BigQuery's `US` multi-region.
+pub(crate) fn default_location(tomlfile: &Config) -> &str {
+    tomlfile.bigquery.location.as_deref().unwrap_or("US")
+}
+
+// Label values may only hold lowercase letters, digits, `_` and `-`, up to 63
+// characters.
+fn label_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| c.to_ascii_lowercase())
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(63)
+        .collect()
+}
+
+// Query options for a request to the query routes: what the client set, plus
+// the configured labels and a `client_id` label naming the API key.
+fn request_query_options(
+    tomlfile: &Config,
+    req: &Request,
+    api_key: Option<&ApiKey>,
+) -> QueryOptions {
+    let mut labels = tomlfile.bigquery.labels.clone().unwrap_or_default();
+    if let Some(key) = api_key {
+        labels.insert("client_id".to_string(), label_value(&key.name));
+    }
+    QueryOptions {
+        labels,
+        location: req.get_header_str(LOCATION_HEADER).map(str::to_string),
+        use_legacy_sql: req
+            .get_query_parameter("legacy_sql")
+            .map(|x| x == "true"),
+        use_query_cache: req.get_query_parameter("cache").map(|x| x != "false"),
+        create_session: req.get_header_str(SESSION_HEADER) == Some(NEW_SESSION),
+        session_id: req
+            .get_header_str(SESSION_HEADER)
+            .filter(|x| *x != NEW_SESSION)
+            .map(str::to_string),
+        ..Default::default()
+    }
+}
+
+// BigQuery's error reason for a query over `maximum_bytes_billed`.
+const BYTES_BILLED_LIMIT_EXCEEDED: &str = "bytesBilledLimitExceeded";
+
+pub const NEXT_PAGE_TOKEN_HEADER: &str = "X-Next-Page-Token";
+// Whether BigQuery answered from its results cache.
+pub const CACHE_HIT_HEADER: &str = "X-BigQuery-Cache-Hit";
+// Query metadata: rows in the whole result, bytes scanned and the job
+// (`project:location.jobId`) that produced the rows.
+pub const TOTAL_ROWS_HEADER: &str = "X-BigQuery-Total-Rows";
+pub const BYTES_PROCESSED_HEADER: &str = "X-BigQuery-Bytes-Processed";
+pub const JOB_REFERENCE_HEADER: &str = "X-BigQuery-Job";
+// Bytes a dry run of the query estimated it would scan, with `estimate_bytes`.
+pub const ESTIMATED_BYTES_HEADER: &str = "X-BQ-Estimated-Bytes";
+
+pub const QUERY_MODE_JOB: &str = "job";
+pub const READ_MODE_STORAGE_READ: &str = "storage_read";
+const DEFAULT_JOB_POLL_BUDGET_MS: u32 = 20_000;
+// Upper bound of a single jobs.getQueryResults wait.
+const JOB_POLL_STEP_MS: u32 = 10_000;
+// Rows per jobs.getQueryResults page of a streamed result.
+const DEFAULT_STREAM_PAGE_ROWS: u32 = 10_000;
+
+#[derive(serde::Serialize, Debug)]
+pub struct BqJobReq {
+    job_reference: BqJobReference,
+    configuration: BqJobConfiguration,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BqJobReference {
+    location: String,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BqJobConfiguration {
+    query: BqJobQueryConfiguration,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    labels: HashMap<String, String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BqJobQueryConfiguration {
+    query: String,
+    use_legacy_sql: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameter_mode: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    query_parameters: Vec<QueryParameter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maximum_bytes_billed: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    use_query_cache: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    create_session: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    connection_properties: Vec<ConnectionProperty>,
+}
+
+// Opaque page token handed to clients. It carries what jobs.getQueryResults
+// needs to continue a result set, plus the API key it was issued to, and is
+// signed with `BqConfiguration::page_token_key` so clients cannot forge one
+// for another caller's job.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct PageCursor {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    project: Option<String>,
+    job_id: String,
+    location: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    page_token: Option<String>,
+    key: Option<String>,
+}
+
+impl PageCursor {
+    pub fn from_response(bqresp_json: &serde_json::Value, key: Option<String>) -> Option<Self> {
+        Some(Self {
+            project: bqresp_json["jobReference"]["projectId"]
+                .as_str()
+                .map(str::to_string),
+            job_id: bqresp_json["jobReference"]["jobId"].as_str()?.to_string(),
+            location: bqresp_json["jobReference"]["location"]
+                .as_str()
+                .unwrap_or("")
+                .to_string(),
+            page_token: Some(bqresp_json["pageToken"].as_str()?.to_string()),
+            key,
+        })
+    }
+
+    // Cursor for the first page of a submitted job.
+    pub fn for_job(job_json: &serde_json::Value, key: Option<String>) -> Option<Self> {
+        Some(Self {
+            project: job_json["jobReference"]["projectId"]
+                .as_str()
+                .map(str::to_string),
+            job_id: job_json["jobReference"]["jobId"].as_str()?.to_string(),
+            location: job_json["jobReference"]["location"]
+                .as_str()
+                .unwrap_or("")
+                .to_string(),
+            page_token: None,
+            key,
+        })
+    }
+
+    pub fn encode(&self) -> Result<String, Error> {
+        Ok(self.encode_signed(&page_token_key()?)?)
+    }
+
+    pub fn decode(token: &str) -> Result<Self, Error> {
+        Ok(Self::decode_signed(token, &page_token_key()?)?)
+    }
+
+    // `{payload}.{mac}`, both URL-safe base64.
+    fn encode_signed(&self, key: &[u8]) -> Result<String, serde_json::Error> {
+        let payload = base64::encode_config(serde_json::to_string(self)?, base64::URL_SAFE_NO_PAD);
+        let mac = hmac_sha256::HMAC::mac(payload.as_bytes(), key);
+        Ok(format!("{}.{}", payload, base64::encode_config(mac, base64::URL_SAFE_NO_PAD)))
+    }
+
+    fn decode_signed(token: &str, key: &[u8]) -> Result<Self, AppError> {
+        let invalid = |e: String| AppError::BadRequest(format!("page or job token is not valid: {}", e));
+        let (payload, mac) = token.split_once('.').ok_or_else(|| invalid("not signed".to_string()))?;
+        let mac = base64::decode_config(mac, base64::URL_SAFE_NO_PAD).map_err(|e| invalid(e.to_string()))?;
+        if !constant_time_eq(&mac, &hmac_sha256::HMAC::mac(payload.as_bytes(), key)) {
+            return Err(AppError::Forbidden("page or job token was not issued by this service".to_string()));
+        }
+        let decoded = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|e| invalid(e.to_string()))?;
+        serde_json::from_slice(&decoded).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+// Tokens outlive the request's credentials, e.g. a job started by an insert is
+// polled with GET, so they are always signed for the `[bigquery]` account.
+fn page_token_key() -> Result<Vec<u8>, Error> {
+    match Config::load().bigquery.page_token_key() {
+        Ok(x) => Ok(x),
+        Err(e) => Err(AppError::Config(e).into()),
+    }
+}
+
+// Routes addressing a table by its logical name from `[tables]`:
+// /t/{name}[/schema|/dryrun|...]
+pub const TABLE_ROUTE_PREFIX: &str = "/t/";
+
+// The `[tables]` operation a request to `route` performs.
+fn table_operation(method: &Method, route: &str) -> &'static str {
+    match *method {
+        Method::GET | Method::HEAD => "read",
+        Method::POST if route.ends_with("/upsert") => "upsert",
+        Method::POST => "insert",
+        Method::PUT => "update",
+        _ => "delete",
+    }
+}
+
+// Config for a request. On a target route, /bq/:project/:dataset/:table,
+// `projectid` and `dataset_tableid` point at the addressed table, which must be
+// listed in `[[targets]]`; on a table route, /t/:name, at the named `[tables]`
+// entry, if it allows the operation.
+pub(crate) fn load_config(req: &Request, params: &Params) -> Result<Config, Error> {
+    let mut tomlfile = Config::load();
+    if let Some(name) = params.get("name") {
+        let table = match tomlfile.tables.as_ref().and_then(|x| x.get(name)) {
+            Some(x) => x,
+            None => {
+                let msg = format!("{} is not a configured table", name);
+                return Err(AppError::NotFound(msg).into());
+            }
+        };
+        let operation = table_operation(req.get_method(), params.route());
+        if let Some(operations) = &table.operations {
+            if !operations.iter().any(|x| x == operation) {
+                // 403, not 405: the route takes the method, the table's
+                // policy is what refuses it.
+                let msg = format!("Table {} does not allow {}", name, operation);
+                return Err(AppError::Forbidden(msg).into());
+            }
+        }
+        tomlfile.bigquery.use_table(table);
+    }
+    if let (Some(project), Some(dataset), Some(table)) = (
+        params.get("project"),
+        params.get("dataset"),
+        params.get("table"),
+    ) {
+        let dataset_tableid = format!("{}.{}", dataset, table);
+        let target = tomlfile.targets.iter().flatten().find(|target| {
+            target.projectid == project && target.dataset_tableid == dataset_tableid
+        });
+        let date_column = match target {
+            Some(x) => x.date_column.clone(),
+            None => {
+                let msg = format!("{}.{} is not a configured target", project, dataset_tableid);
+                return Err(AppError::NotFound(msg).into());
+            }
+        };
+        tomlfile.bigquery.projectid = project.to_string();
+        tomlfile.bigquery.dataset_tableid = dataset_tableid;
+        tomlfile.bigquery.date_column = date_column;
+    }
+    let credentials = match *req.get_method() {
+        Method::GET | Method::HEAD => tomlfile.bigquery.read_credentials.clone(),
+        _ => tomlfile.bigquery.write_credentials.clone(),
+    };
+    if let Some(name) = credentials {
+        tomlfile.use_credentials(&name)?;
+    }
+    if tomlfile.bigquery.oauth_passthrough.unwrap_or(false) {
+        match req.get_header_str("Authorization").and_then(|x| x.strip_prefix("Bearer ")) {
+            Some(x) => tomlfile.bigquery.user_access_token = Some(x.trim().to_string()),
+            None => {
+                let msg = "Missing Authorization: Bearer access token";
+                return Err(AppError::Unauthorized(msg.to_string()).into());
+            }
+        }
+    }
+    Ok(tomlfile)
+}
+
+pub const INSERT_MODE_INSERT_ALL: &str = "insert_all";
+pub const INSERT_MODE_STORAGE_WRITE: &str = "storage_write";
+
+// Column layout of the example table, used unless `[[bigquery.columns]]`
+// describes another. Every column is required.
+const TOP_RISING_TERMS_COLUMNS: &[(&str, ColumnType)] = &[
+    ("refresh_date", ColumnType::Date),
+    ("dma_name", ColumnType::String),
+    ("dma_id", ColumnType::Int64),
+    ("term", ColumnType::String),
+    ("week", ColumnType::Date),
+    ("score", ColumnType::Int64),
+    ("rank", ColumnType::Int64),
+    ("percent_gain", ColumnType::Int64),
+];
+
+// Columns of the configured table. They validate insert bodies, build the
+// INSERT and Storage Write rows, and limit which columns clients can name.
+// They come from `[[bigquery.columns]]`, the table itself with
+// `fetch_schema = true`, `[[bigquery.schema]]` or, failing those, the
+// TopRisingTerms defaults. Config::validate reports unknown column types,
+// which are skipped here.
+pub(crate) fn table_columns(tomlfile: &Config) -> Vec<Column> {
+    if let Some(columns) = &tomlfile.bigquery.columns {
+        return columns
+            .iter()
+            .filter_map(|x| Some(Column::new(&x.name, ColumnType::parse(&x.kind)?, x.required)))
+            .collect();
+    }
+    if tomlfile.bigquery.fetch_schema.unwrap_or(false) {
+        match fetched_schema(tomlfile) {
+            Ok(x) => return schema_columns(&x),
+            // Fall through to the configured schema rather than failing reads.
+            Err(e) => error!("Schema fetch error: {}", e),
+        }
+    }
+    match &tomlfile.bigquery.schema {
+        Some(x) => schema_columns(x),
+        None => TOP_RISING_TERMS_COLUMNS
+            .iter()
+            .map(|(name, kind)| Column::new(name, *kind, true))
+            .collect(),
+    }
+}
+
+// Columns of BigQuery schema fields; mode REQUIRED makes a column required.
+// REPEATED fields and types rows can't be checked against, such as RECORD or
+// TIMESTAMP, are left out, so inserts can't set them.
+fn schema_columns(fields: &[SchemaFieldConfiguration]) -> Vec<Column> {
+    fields
+        .iter()
+        .filter_map(|x| {
+            let mode = x.mode.as_deref().unwrap_or("NULLABLE");
+            match ColumnType::parse(&x.kind) {
+                Some(kind) if mode != "REPEATED" => Some(Column::new(&x.name, kind, mode == "REQUIRED")),
+                _ => {
+                    error!("Schema field `{}` ({} {}) is not supported, skipping", x.name, mode, x.kind);
+                    None
+                },
+            }
+        })
+        .collect()
+}
+
+// Error text for a value that doesn't fit `column`, if it doesn't.
+fn column_value_error(column: &Column, value: &serde_json::Value) -> Option<String> {
+    let (valid, expected) = match column.kind {
+        ColumnType::String => (value.is_string(), "a string"),
+        ColumnType::Int64 => (value.is_i64(), "an integer"),
+        ColumnType::Float64 => (value.is_number(), "a number"),
+        ColumnType::Bool => (value.is_boolean(), "a boolean"),
+        ColumnType::Date => (
+            value
+                .as_str()
+                .is_some_and(|x| Date::parse(x, &format_description!("[year]-[month]-[day]")).is_ok()),
+            "a YYYY-MM-DD date",
+        ),
+        ColumnType::Geography => (
+            value.as_str().is_some_and(|x| wkt_to_geojson(x).is_ok()),
+            "WKT text",
+        ),
+    };
+    if valid {
+        None
+    } else {
+        Some(format!("`{}` must be {}, got {}", column.name, expected, value))
+    }
+}
+
+// An insert body row checked against the columns: each value must fit its
+// column, required columns must be present and every field must name a
+// column. Missing optional columns are NULL. Fails with every problem found,
+// as (field, message) pairs.
+fn table_row(
+    columns: &[Column],
+    mut fields: serde_json::Map<String, serde_json::Value>,
+) -> Result<serde_json::Value, Vec<(String, String)>> {
+    let mut row = serde_json::Map::new();
+    let mut errors = Vec::new();
+    for column in columns {
+        let value = fields.remove(&column.name).unwrap_or(serde_json::Value::Null);
+        if value.is_null() {
+            if column.required {
+                errors.push((column.name.clone(), format!("missing field `{}`", column.name)));
+            }
+        } else if let Some(e) = column_value_error(column, &value) {
+            errors.push((column.name.clone(), e));
+        }
+        row.insert(column.name.clone(), value);
+    }
+    for name in fields.keys() {
+        errors.push((name.clone(), format!("`{}` is not a column of the table", name)));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(serde_json::Value::from(row))
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BqInsertAllReq {
+    kind: String,
+    skip_invalid_rows: bool,
+    ignore_unknown_values: bool,
+    rows: Vec<BqInsertAllRow>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BqInsertAllRow {
+    insert_id: String,
+    json: serde_json::Value,
+}
+
+// Client-chosen key naming one logical insert; a retry with the same key and
+// rows gets the same insertIds.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+// insertId for BigQuery's best-effort deduplication of insertAll retries: the
+// SHA-256 of the Idempotency-Key and row index when the client sent one,
+// otherwise of the row content, so an identical row is written only once.
+fn insert_id(idempotency_key: Option<&str>, index: usize, row: &serde_json::Value) -> String {
+    let source = match idempotency_key {
+        Some(key) => format!("{}:{}", key, index),
+        None => row.to_string(),
+    };
+    hex::encode(hmac_sha256::Hash::hash(source.as_bytes()))
+}
+
+pub const PARAMETER_MODE_NAMED: &str = "NAMED";
+pub const PARAMETER_MODE_POSITIONAL: &str = "POSITIONAL";
+
+// Query parameter, referenced as `@name` in the SQL text, or by position as
+// `?` when it has no name. Scalar, ARRAY (`dma_id IN UNNEST(@ids)`) and
+// STRUCT parameters are supported; clients may send them in BigQuery's
+// camelCase layout.
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct QueryParameter {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    name: String,
+    #[serde(alias = "parameterType")]
+    parameter_type: QueryParameterType,
+    #[serde(alias = "parameterValue")]
+    parameter_value: QueryParameterValue,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct QueryParameterType {
+    #[serde(rename = "type")]
+    kind: String,
+    // Element type of an ARRAY.
+    #[serde(default, alias = "arrayType", skip_serializing_if = "Option::is_none")]
+    array_type: Option<Box<QueryParameterType>>,
+    // Fields of a STRUCT, in order.
+    #[serde(default, alias = "structTypes", skip_serializing_if = "Vec::is_empty")]
+    struct_types: Vec<QueryParameterStructType>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct QueryParameterStructType {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(rename = "type")]
+    kind: QueryParameterType,
+}
+
+// A NULL scalar has no `value`; an empty ARRAY no `array_values`.
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug, Clone)]
+pub struct QueryParameterValue {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(default, alias = "arrayValues", skip_serializing_if = "Vec::is_empty")]
+    array_values: Vec<QueryParameterValue>,
+    #[serde(default, alias = "structValues", skip_serializing_if = "HashMap::is_empty")]
+    struct_values: HashMap<String, QueryParameterValue>,
+}
+
+impl QueryParameterType {
+    fn scalar(kind: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl QueryParameterValue {
+    fn scalar(value: impl ToString) -> Self {
+        Self {
+            value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+impl QueryParameter {
+    pub fn new(name: &str, kind: &str, value: impl ToString) -> Self {
+        Self {
+            name: name.to_string(),
+            parameter_type: QueryParameterType::scalar(kind),
+            parameter_value: QueryParameterValue::scalar(value),
+        }
+    }
+
+    // ARRAY<kind> parameter holding `values`.
+    pub fn array<T: ToString>(name: &str, kind: &str, values: &[T]) -> Self {
+        Self {
+            name: name.to_string(),
+            parameter_type: QueryParameterType {
+                kind: "ARRAY".to_string(),
+                array_type: Some(Box::new(QueryParameterType::scalar(kind))),
+                ..Default::default()
+            },
+            parameter_value: QueryParameterValue {
+                array_values: values.iter().map(|x| QueryParameterValue::scalar(x.to_string())).collect(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+// Transient failures retried by send_bigquery_req: HTTP statuses and the
+// `reason` of BigQuery error responses.
+const RETRYABLE_STATUSES: [u16; 4] = [429, 500, 502, 503];
+const RETRYABLE_REASONS: [&str; 2] = ["rateLimitExceeded", "backendError"];
+// Rejected before BigQuery did any work, so safe to retry for any request.
+const RATE_LIMIT_REASON: &str = "rateLimitExceeded";
+const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+const DEFAULT_RETRY_BACKOFF_MS: u64 = 250;
+
+const DEFAULT_BIGQUERY_HOST: &str = "bigquery.googleapis.com";
+const DEFAULT_BIGQUERY_BACKEND: &str = "bigquery";
+
+// Root of the BigQuery REST API on the configured (e.g. regional) endpoint.
+pub(crate) fn bigquery_api_base(tomlfile: &Config) -> String {
+    format!(
+        "https://{}/bigquery/v2",
+        tomlfile.bigquery.endpoint.as_deref().unwrap_or(DEFAULT_BIGQUERY_HOST)
+    )
+}
+
+pub(crate) fn bigquery_backend(tomlfile: &Config) -> &str {
+    tomlfile.bigquery.backend.as_deref().unwrap_or(DEFAULT_BIGQUERY_BACKEND)
+}
+
+// Sends a request to the BigQuery backend, retrying transient failures with
+// exponential backoff and full jitter. Requests that are not idempotent (DML
+// jobs, admin mutations) are only retried when
+// BigQuery rejected them with `rateLimitExceeded`. The last response is
+// returned when attempts run out.
+pub(crate) fn send_bigquery_req(
+    tomlfile: &Config,
+    mut req: Request,
+    idempotent: bool,
+) -> Result<Response, Error> {
+    let attempts = tomlfile
+        .bigquery
+        .retry_attempts
+        .unwrap_or(DEFAULT_RETRY_ATTEMPTS)
+        .max(1);
+    let backoff_ms = tomlfile
+        .bigquery
+        .retry_backoff_ms
+        .unwrap_or(DEFAULT_RETRY_BACKOFF_MS);
+    let mut attempt = 1;
+    loop {
+        let last = attempt >= attempts;
+        let mut resp = match req.clone_with_body().send(bigquery_backend(tomlfile)) {
+            Ok(x) => x,
+            Err(e) if idempotent && !last => {
+                error!("BigQuery attempt {} failed: {}", attempt, e);
+                retry_sleep(backoff_ms, attempt);
+                attempt += 1;
+                continue;
+            },
+            Err(e) => return Err(AppError::Upstream(format!("BigQuery request failed: {}", e)).into()),
+        };
+        let status = resp.get_status().as_u16();
+        if resp.get_status().is_success() || last {
+            return Ok(resp);
+        }
+        let body = resp.take_body_bytes();
+        let reason = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|x| x["error"]["errors"][0]["reason"].as_str().map(str::to_string));
+        let retryable = if idempotent {
+            RETRYABLE_STATUSES.contains(&status)
+                || reason.as_deref().is_some_and(|x| RETRYABLE_REASONS.contains(&x))
+        } else {
+            reason.as_deref() == Some(RATE_LIMIT_REASON)
+        };
+        if !retryable {
+            resp.set_body(body);
+            return Ok(resp);
+        }
+        error!(
+            "BigQuery attempt {} failed with {} ({}), retrying",
+            attempt,
+            status,
+            reason.as_deref().unwrap_or("no reason")
+        );
+        retry_sleep(backoff_ms, attempt);
+        attempt += 1;
+    }
+}
+
+fn retry_sleep(backoff_ms: u64, attempt: u32) {
+    let ceiling = backoff_ms.saturating_mul(1 << (attempt - 1).min(16));
+    let delay = rand::thread_rng().gen_range(0..=ceiling);
+    std::thread::sleep(std::time::Duration::from_millis(delay));
+}
+
+// The error for BigQuery's failed answer to `operation`, with its status and
+// the reason and message of its error body.
+pub(crate) fn bigquery_error(operation: &'static str, mut resp: Response) -> AppError {
+    let status = resp.get_status();
+    let body = resp.take_body_str();
+    let bqerror = serde_json::from_str::<serde_json::Value>(&body).unwrap_or_default();
+    AppError::BigQuery {
+        operation,
+        status,
+        reason: bqerror["error"]["errors"][0]["reason"].as_str().map(str::to_string),
+        message: bqerror["error"]["message"].as_str().map_or(body.clone(), str::to_string),
+    }
+}
+
+// Random UUID (version 4), e.g. for jobs.query `requestId`.
+pub(crate) fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
 }
 
 fn gcp_bq_job_query(
+    tomlfile: &Config,
     access_token: &str,
     req_url: &str,
     postbody: BqQueryReq,
 ) -> Result<String, Error> {
-    let mut resp = Request::post(req_url)
+    let bqreq = Request::post(req_url)
         .with_header("Authorization", format!("Bearer {}", access_token))
         .with_body_json(&postbody)?
-        .with_pass(true)
-        .send("bigquery")?;
+        .with_pass(true);
+    // Retries reuse the request's requestId, so they are idempotent.
+    let mut resp = send_bigquery_req(tomlfile, bqreq, true)?;
     if !resp.get_status().is_success() {
-        let resp_str = resp.take_body_str();
-        let msg = format!("BQ Query Request error: {}", resp_str);
+        return Err(bigquery_error("Query", resp).into());
+    }
+    let resp_str = resp.take_body_str();
+    Ok(resp_str)
+}
+
+pub fn handle_insert_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+// This is just an example to call INSERT SQL.
+    debug!("Start BQ Insert!");
+    let tomlfile = load_config(req, params)?;
+    require_client_certificate(&tomlfile, req)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let columns = table_columns(&tomlfile);
+    let rows = match parse_insert_rows(&tomlfile, &columns, req) {
+        Ok(x) => x,
+        Err(resp) => return Ok(*resp),
+    };
+    let scope = key_scope(&tomlfile, api_key.as_ref())?;
+    let no_columns = serde_json::Map::new();
+    for row in &rows {
+        check_scope(api_key.as_ref(), scope, row.as_object().unwrap_or(&no_columns))?;
+    }
+    let scope_column = scope_column_name(&tomlfile);
+    let mut dma_ids: Vec<i64> = rows.iter().filter_map(|row| row[scope_column].as_i64()).collect();
+    dma_ids.sort_unstable();
+    dma_ids.dedup();
+    let tee_params = serde_json::json!({ "dma_ids": dma_ids, "rows": rows.len() });
+    let (query, bqresp_json, row_count) = match tomlfile.bigquery.insert_mode.as_deref() {
+        Some(INSERT_MODE_INSERT_ALL) => {
+            let idempotency_key = req.get_header_str(IDEMPOTENCY_KEY_HEADER);
+            let bqresp_json = handle_bq_insert_all_req(&tomlfile, &rows, idempotency_key)?;
+            let errors = insert_all_row_errors(&bqresp_json);
+            if !errors.is_empty() {
+                error!("BQ insertAll rejected rows: {}", serde_json::Value::from(errors.clone()));
+                return Ok(insert_errors_response(&errors));
+            }
+            (INSERT_MODE_INSERT_ALL.to_string(), bqresp_json, rows.len() as u64)
+        },
+        Some(INSERT_MODE_STORAGE_WRITE) => {
+            let row_count = match append_rows(&tomlfile, &columns, &rows) {
+                Ok(x) => x,
+                Err(e) => {
+                    let msg = format!("BQ Storage Write Error: {}", e);
+                    return Err(AppError::Upstream(msg).into());
+                },
+            };
+            (INSERT_MODE_STORAGE_WRITE.to_string(), serde_json::Value::Null, row_count)
+        },
+        _ => {
+            // One multi-row INSERT; row i binds @{column}_{i}.
+            let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+            let mut statement = QueryBuilder::new(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid);
+            let mut values = Vec::new();
+            for (i, row) in rows.iter().enumerate() {
+                let mut placeholders = Vec::new();
+                for column in &columns {
+                    let value = &row[column.name.as_str()];
+                    if value.is_null() {
+                        placeholders.push("NULL".to_string());
+                        continue;
+                    }
+                    let param = format!("{}_{}", column.name, i);
+                    placeholders.push(statement.bind(&param, column.param_type(), json_param_value(value)));
+                }
+                values.push(placeholders);
+            }
+            let query = statement.insert_sql(&names, &values);
+            // DML with named parameters is standard SQL only.
+            let options = QueryOptions {
+                use_legacy_sql: Some(false),
+                ..request_query_options(&tomlfile, req, api_key.as_ref())
+            };
+            let bqresp_json = match handle_bq_query_req(&tomlfile, &query, statement.params(), &options) {
+                Ok(x) => x,
+                Err(e) => {
+                    if let Some(resp) = bytes_billed_limit_response(&e) {
+                        return Ok(resp);
+                    }
+                    if let Some(errors) = dml_row_errors(&e, &columns, rows.len()) {
+                        error!("BQ Insert rejected: {}", e);
+                        return Ok(insert_errors_response(&errors));
+                    }
+                    error!("BQ Insert failed, query: {}", query);
+                    return Err(e);
+                },
+            };
+            let row_count = bqresp_json["numDmlAffectedRows"]
+                .as_str()
+                .unwrap_or("0")
+                .parse::<u64>()
+                .unwrap_or(0);
+            (query, bqresp_json, row_count)
+        },
+    };
+    tee_query(
+        &tomlfile,
+        &TeeRecord::new("insert", &query, tee_params, row_count, req, &bqresp_json),
+    );
+    let body = serde_json::json!({ "num_rows": row_count });
+    let mut resp = Response::from_status(StatusCode::OK).with_body_json(&body)?;
+    set_session_header(&mut resp, &bqresp_json);
+    Ok(resp)
+}
+
+// Most rows accepted by one insert request; with the example table's 8
+// parameters per row this stays well under BigQuery's query parameter limits.
+const MAX_INSERT_ROWS: usize = 500;
+
+// Rows of an insert body: one object, a JSON array of objects, NDJSON
+// (`Content-Type: application/x-ndjson`) or, on `/ingest/csv`, CSV with a
+// header row. Every row is validated against the columns first; on failure
+// the Err is a 400 listing `{"row": i, "error": ..}` per invalid row.
+fn parse_insert_rows(
+    tomlfile: &Config,
+    columns: &[Column],
+    req: &mut Request,
+) -> Result<Vec<serde_json::Value>, Box<Response>> {
+    let content_type = req
+        .get_content_type()
+        .map(|x| x.essence_str().to_string())
+        .unwrap_or_default();
+    let is_csv = req.get_path() == "/ingest/csv";
+    let body = req.take_body_str();
+    let values: Vec<Result<serde_json::Value, String>> = if is_csv {
+        match csv_rows(tomlfile, columns, &body) {
+            Ok(x) => x,
+            Err(msg) => {
+                error!("{}", msg);
+                return Err(Box::new(
+                    Response::from(AppError::BadRequest(msg)),
+                ));
+            },
+        }
+    } else if content_type == "application/x-ndjson" {
+        body.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| e.to_string()))
+            .collect()
+    } else {
+        match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(serde_json::Value::Array(x)) => x.into_iter().map(Ok).collect(),
+            x => vec![x.map_err(|e| e.to_string())],
+        }
+    };
+    if values.is_empty() || values.len() > MAX_INSERT_ROWS {
+        let msg = format!(
+            "insert body must hold 1 to {} rows, got {}",
+            MAX_INSERT_ROWS,
+            values.len()
+        );
         error!("{}", msg);
-        return Err(anyhow!(msg));
+        return Err(Box::new(
+            Response::from(AppError::BadRequest(msg)),
+        ));
+    }
+    let mut rows = Vec::with_capacity(values.len());
+    let mut errors = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        let fields = match value {
+            Ok(serde_json::Value::Object(x)) => x,
+            Ok(x) => {
+                errors.push(serde_json::json!({ "row": i, "error": format!("row must be a JSON object, got {}", x) }));
+                continue;
+            },
+            Err(e) => {
+                errors.push(serde_json::json!({ "row": i, "error": e }));
+                continue;
+            },
+        };
+        match table_row(columns, fields) {
+            Ok(x) => rows.push(x),
+            Err(field_errors) => {
+                for (field, e) in field_errors {
+                    errors.push(serde_json::json!({ "row": i, "field": field, "error": e }));
+                }
+            },
+        }
+    }
+    if !errors.is_empty() {
+        error!("Insert rejected, {} invalid rows", errors.len());
+        return Err(Box::new(insert_errors_response(&errors)));
+    }
+    Ok(rows)
+}
+
+// 400 listing why rows were not written, as `{"row": i, "error": ..}` objects,
+// with the `field` at fault when there is one.
+fn insert_errors_response(errors: &[serde_json::Value]) -> Response {
+    let body = serde_json::json!({ "num_rows": 0, "errors": errors });
+    Response::from_status(StatusCode::BAD_REQUEST)
+        .with_content_type(mime::APPLICATION_JSON)
+        .with_body(body.to_string())
+}
+
+// Row errors of an insertAll response. Rows BigQuery didn't write only
+// because others were invalid carry the reason `stopped`.
+fn insert_all_row_errors(bqresp_json: &serde_json::Value) -> Vec<serde_json::Value> {
+    let mut errors = Vec::new();
+    for row_errors in bqresp_json["insertErrors"].as_array().map_or(&[][..], |x| x) {
+        for e in row_errors["errors"].as_array().map_or(&[][..], |x| x) {
+            errors.push(serde_json::json!({
+                "row": row_errors["index"],
+                "error": e["message"],
+                "reason": e["reason"],
+                "column": e["location"],
+            }));
+        }
+    }
+    errors
+}
+
+// Whether `message` names the query parameter `param` as a whole word, so
+// `dma_id_1` isn't found in `dma_id_12`.
+fn names_param(message: &str, param: &str) -> bool {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    message.match_indices(param).any(|(start, _)| {
+        let before = message[..start].chars().next_back();
+        let after = message[start + param.len()..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    })
+}
+
+// Row errors of a failed multi-row INSERT. BigQuery reports one error for the
+// whole statement; rows are attributed through the `@{column}_{i}` parameter
+// names the message mentions. None unless BigQuery rejected the statement
+// itself (400), e.g. a value that doesn't fit its column.
+fn dml_row_errors(e: &Error, columns: &[Column], row_count: usize) -> Option<Vec<serde_json::Value>> {
+    let (message, reason) = match e.downcast_ref::<AppError>()? {
+        AppError::BigQuery { status, reason, message, .. } if *status == StatusCode::BAD_REQUEST => {
+            (message.as_str(), reason)
+        },
+        _ => return None,
+    };
+    let rows: Vec<usize> = (0..row_count)
+        .filter(|i| {
+            columns
+                .iter()
+                .any(|c| names_param(message, &format!("{}_{}", c.name, i)))
+        })
+        .collect();
+    if rows.is_empty() {
+        return Some(vec![serde_json::json!({ "row": null, "error": message, "reason": reason })]);
+    }
+    Some(
+        rows.into_iter()
+            .map(|i| serde_json::json!({ "row": i, "error": message, "reason": reason }))
+            .collect(),
+    )
+}
+
+// JSON rows for a CSV body. Header names are mapped to table columns through
+// `[ingest.csv_columns]`; unmapped headers must be column names themselves.
+fn csv_rows(
+    tomlfile: &Config,
+    columns: &[Column],
+    body: &str,
+) -> Result<Vec<Result<serde_json::Value, String>>, String> {
+    let mut records = csv::parse(body)
+        .map_err(|e| format!("CSV body is not valid: {}", e))?
+        .into_iter();
+    let header = match records.next() {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let mapping = tomlfile
+        .ingest
+        .as_ref()
+        .and_then(|x| x.csv_columns.as_ref());
+    let table = columns;
+    let mut columns = Vec::with_capacity(header.len());
+    for name in &header {
+        let mapped = mapping
+            .and_then(|x| x.get(name.trim()))
+            .map_or(name.trim(), String::as_str);
+        match table.iter().find(|c| c.name == mapped) {
+            Some(x) => columns.push(x),
+            None => {
+                return Err(format!(
+                    "CSV header `{}` does not map to a column of the table",
+                    name
+                ))
+            },
+        }
+    }
+    Ok(records
+        .map(|record| {
+            if record.len() != columns.len() {
+                return Err(format!(
+                    "expected {} fields, got {}",
+                    columns.len(),
+                    record.len()
+                ));
+            }
+            let mut row = serde_json::Map::new();
+            for (column, value) in columns.iter().zip(record) {
+                // An empty field is NULL, except in a STRING column.
+                let trimmed = value.trim();
+                let value = match column.kind {
+                    ColumnType::String => serde_json::Value::from(value),
+                    _ if trimmed.is_empty() => serde_json::Value::Null,
+                    ColumnType::Int64 => match trimmed.parse::<i64>() {
+                        Ok(x) => serde_json::Value::from(x),
+                        Err(e) => return Err(format!("`{}`: {}", column.name, e)),
+                    },
+                    ColumnType::Float64 => match trimmed.parse::<f64>() {
+                        Ok(x) => serde_json::Value::from(x),
+                        Err(e) => return Err(format!("`{}`: {}", column.name, e)),
+                    },
+                    ColumnType::Bool => match trimmed.to_ascii_lowercase().parse::<bool>() {
+                        Ok(x) => serde_json::Value::from(x),
+                        Err(e) => return Err(format!("`{}`: {}", column.name, e)),
+                    },
+                    _ => serde_json::Value::from(value),
+                };
+                row.insert(column.name.clone(), value);
+            }
+            Ok(serde_json::Value::from(row))
+        })
+        .collect())
+}
+
+// Column of the table named by a client; anything else is a 400.
+fn table_column<'a>(columns: &'a [Column], name: &str, context: &str) -> Result<&'a Column, Error> {
+    match columns.iter().find(|c| c.name == name) {
+        Some(x) => Ok(x),
+        None => {
+            let msg = format!("{} `{}` is not a column of the table", context, name);
+            Err(AppError::BadRequest(msg).into())
+        },
+    }
+}
+
+// Query parameter text of a JSON body value.
+fn json_param_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(x) => x.clone(),
+        x => x.to_string(),
+    }
+}
+
+// `column = @column` predicates for the query string, which may only name
+// columns of the table.
+fn column_predicates(columns: &[Column], query_string: &HashMap<String, String>, statement: &mut QueryBuilder) -> Result<(), Error> {
+    // HashMap order is random; keep the SQL text stable for the query cache.
+    let mut names: Vec<&String> = query_string.keys().collect();
+    names.sort();
+    for name in names {
+        let column = table_column(columns, name, "query string")?;
+        statement.filter_eq(&column.name, column.param_type(), Some(&query_string[name]));
+    }
+    Ok(())
+}
+
+// DELETE /api/v1/top_rising_terms?dma_id=..&week=..: deletes the matching rows.
+// At least one column predicate is required, so a bare DELETE never empties
+// the table.
+pub fn handle_delete_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Delete");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let query_string = match req.get_query::<HashMap<String, String>>() {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Delete request, querystring Error: {}", e);
+            return Err(AppError::BadRequest(msg).into());
+        },
+    };
+    let mut statement = QueryBuilder::new(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid);
+    column_predicates(&table_columns(&tomlfile), &query_string, &mut statement)?;
+    if statement.params().is_empty() {
+        let msg = "DELETE requires at least one column in the query string";
+        return Err(AppError::BadRequest(msg.to_string()).into());
+    }
+    scope_filter(key_scope(&tomlfile, api_key.as_ref())?, &mut statement, "");
+    let query = statement.delete_sql();
+    let options = QueryOptions {
+        use_legacy_sql: Some(false),
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    let bqresp_json = match handle_bq_query_req(&tomlfile, &query, statement.params(), &options) {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(resp) = bytes_billed_limit_response(&e) {
+                return Ok(resp);
+            }
+            error!("BQ Delete failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    let row_count = bqresp_json["numDmlAffectedRows"]
+        .as_str()
+        .unwrap_or("0")
+        .parse::<u64>()
+        .unwrap_or(0);
+    tee_query(
+        &tomlfile,
+        &TeeRecord::new(
+            "delete",
+            &query,
+            serde_json::to_value(&query_string)?,
+            row_count,
+            req,
+            &bqresp_json,
+        ),
+    );
+    let body = serde_json::json!({ "num_dml_affected_rows": row_count });
+    let mut resp = Response::from_status(StatusCode::OK).with_body_json(&body)?;
+    set_session_header(&mut resp, &bqresp_json);
+    Ok(resp)
+}
+
+// PUT /api/v1/top_rising_terms: updates rows matching `keys` with the values
+// in `set`, e.g. {"keys": {"dma_id": 819, "week": "2024-01-07", "term": "x"},
+// "set": {"score": 90}}.
+pub fn handle_update_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Update");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    #[derive(serde::Deserialize)]
+    struct UpdateReq {
+        keys: serde_json::Map<String, serde_json::Value>,
+        set: serde_json::Map<String, serde_json::Value>,
+    }
+    let update: UpdateReq = json_body(req)?;
+    if update.keys.is_empty() || update.set.is_empty() {
+        let msg = "UPDATE requires non-empty `keys` and `set` objects";
+        return Err(AppError::BadRequest(msg.to_string()).into());
+    }
+    let scope = key_scope(&tomlfile, api_key.as_ref())?;
+    if update.set.contains_key(scope_column_name(&tomlfile)) {
+        check_scope(api_key.as_ref(), scope, &update.set)?;
+    }
+    let columns = table_columns(&tomlfile);
+    let mut statement = QueryBuilder::new(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid);
+    for (name, value) in &update.set {
+        let column = table_column(&columns, name, "set")?;
+        let value = if value.is_null() { None } else { Some(json_param_value(value)) };
+        statement.set(&column.name, column.param_type(), value.as_deref());
+    }
+    for (name, value) in &update.keys {
+        let column = table_column(&columns, name, "keys")?;
+        let value = if value.is_null() { None } else { Some(json_param_value(value)) };
+        statement.filter_eq(&column.name, column.param_type(), value.as_deref());
+    }
+    scope_filter(scope, &mut statement, "");
+    let query = statement.update_sql();
+    let options = QueryOptions {
+        use_legacy_sql: Some(false),
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    let bqresp_json = match handle_bq_query_req(&tomlfile, &query, statement.params(), &options) {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(resp) = bytes_billed_limit_response(&e) {
+                return Ok(resp);
+            }
+            error!("BQ Update failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    let row_count = bqresp_json["numDmlAffectedRows"]
+        .as_str()
+        .unwrap_or("0")
+        .parse::<u64>()
+        .unwrap_or(0);
+    tee_query(
+        &tomlfile,
+        &TeeRecord::new(
+            "update",
+            &query,
+            serde_json::Value::from(update.keys),
+            row_count,
+            req,
+            &bqresp_json,
+        ),
+    );
+    let body = serde_json::json!({ "num_dml_affected_rows": row_count });
+    let mut resp = Response::from_status(StatusCode::OK).with_body_json(&body)?;
+    set_session_header(&mut resp, &bqresp_json);
+    Ok(resp)
+}
+
+// Columns identifying a row for upserts, unless `key_columns` is configured.
+const DEFAULT_KEY_COLUMNS: &[&str] = &["refresh_date", "dma_id", "term", "week"];
+
+// POST /api/v1/top_rising_terms/upsert: a MERGE keyed on the key columns that
+// updates the matching row or inserts the body as a new one. The body is one
+// row object, e.g. {"refresh_date": "2024-01-08", "dma_id": 819, ...}.
+pub fn handle_upsert_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Upsert");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let row = json_body::<serde_json::Map<String, serde_json::Value>>(req)?;
+    let scope = key_scope(&tomlfile, api_key.as_ref())?;
+    check_scope(api_key.as_ref(), scope, &row)?;
+    let key_columns: Vec<&str> = match &tomlfile.bigquery.key_columns {
+        Some(x) => x.iter().map(String::as_str).collect(),
+        None => DEFAULT_KEY_COLUMNS.to_vec(),
+    };
+    let columns = table_columns(&tomlfile);
+    let mut statement = QueryBuilder::new(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid);
+    let mut source: Vec<(String, &str)> = Vec::new();
+    for (name, value) in &row {
+        let column = table_column(&columns, name, "body")?;
+        let expression = if value.is_null() {
+            format!("CAST(NULL AS {})", column.param_type())
+        } else {
+            statement.bind(&column.name, column.param_type(), json_param_value(value))
+        };
+        source.push((expression, &column.name));
+    }
+    for key_column in &key_columns {
+        if !source.iter().any(|(_, column)| column == key_column) {
+            let msg = format!("body is missing key column `{}`", key_column);
+            return Err(AppError::BadRequest(msg).into());
+        }
+    }
+    scope_filter(scope, &mut statement, "T.");
+    let query = statement.merge_sql(&source, &key_columns);
+    let options = QueryOptions {
+        use_legacy_sql: Some(false),
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    let bqresp_json = match handle_bq_query_req(&tomlfile, &query, statement.params(), &options) {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(resp) = bytes_billed_limit_response(&e) {
+                return Ok(resp);
+            }
+            error!("BQ Upsert failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    let row_count = bqresp_json["numDmlAffectedRows"]
+        .as_str()
+        .unwrap_or("0")
+        .parse::<u64>()
+        .unwrap_or(0);
+    let tee_params: serde_json::Map<String, serde_json::Value> = row
+        .iter()
+        .filter(|(name, _)| key_columns.contains(&name.as_str()))
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect();
+    tee_query(
+        &tomlfile,
+        &TeeRecord::new(
+            "upsert",
+            &query,
+            serde_json::Value::from(tee_params),
+            row_count,
+            req,
+            &bqresp_json,
+        ),
+    );
+    let body = serde_json::json!({ "num_dml_affected_rows": row_count });
+    let mut resp = Response::from_status(StatusCode::OK).with_body_json(&body)?;
+    set_session_header(&mut resp, &bqresp_json);
+    Ok(resp)
+}
+
+fn set_session_header(resp: &mut Response, bqresp_json: &serde_json::Value) {
+    if let Some(x) = bqresp_json["sessionInfo"]["sessionId"].as_str() {
+        resp.set_header(SESSION_ID_HEADER, x);
+    }
+}
+
+// Routes for multi-statement transactions spanning several requests:
+//   POST /api/v1/sessions               start a session and a transaction
+//   POST /api/v1/sessions/{id}/commit   commit the session's transaction
+//   POST /api/v1/sessions/{id}/rollback roll it back
+// Statements in between run in the session by sending `X-BigQuery-Session: {id}`.
+// Each route passes the statement it runs.
+pub fn handle_session_req(req: &mut Request, params: &Params, statement: &'static str) -> Result<Response, Error> {
+    debug!("Start BQ Session");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let session_id = params.get("id").map(str::to_string);
+    let options = QueryOptions {
+        create_session: session_id.is_none(),
+        session_id,
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    let bqresp_json = match handle_bq_query_req(&tomlfile, statement, &[], &options) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("BQ query failed, query: {}", statement);
+            return Err(e);
+        },
+    };
+    let session_id = bqresp_json["sessionInfo"]["sessionId"]
+        .as_str()
+        .map(str::to_string)
+        .or(options.session_id);
+    let body = serde_json::json!({ "session_id": session_id });
+    let mut resp = Response::from_status(StatusCode::OK).with_body_json(&body)?;
+    set_session_header(&mut resp, &bqresp_json);
+    Ok(resp)
+}
+
+// Schema fields of the configured table, from `[[bigquery.schema]]` or the
+// table's columns.
+pub(crate) fn table_schema_fields(tomlfile: &Config) -> Result<serde_json::Value, Error> {
+    Ok(match &tomlfile.bigquery.schema {
+        Some(x) => serde_json::to_value(x)?,
+        None => table_columns(tomlfile)
+            .iter()
+            .map(|column| serde_json::json!({ "name": column.name, "type": column.bq_type() }))
+            .collect(),
+    })
+}
+
+// Splits `dataset_tableid` into its dataset and table ids.
+pub(crate) fn dataset_and_table(tomlfile: &Config) -> Result<(&str, &str), Error> {
+    match tomlfile.bigquery.dataset_tableid.split_once('.') {
+        Some(x) => Ok(x),
+        None => {
+            let msg = format!(
+                "dataset_tableid `{}` is not in dataset.table form",
+                tomlfile.bigquery.dataset_tableid
+            );
+            Err(AppError::Config(msg).into())
+        },
+    }
+}
+
+// Streams rows through tabledata.insertAll instead of a DML INSERT job. Rows
+// that BigQuery rejects are listed in the response's `insertErrors`; none of
+// the rows are written then. Every row carries an insertId, which makes the
+// call safe to retry.
+pub fn handle_bq_insert_all_req(
+    tomlfile: &Config,
+    rows: &[serde_json::Value],
+    idempotency_key: Option<&str>,
+) -> Result<serde_json::Value, Error> {
+    debug!("Start BQ insertAll");
+    let (dataset_id, table_id) = dataset_and_table(tomlfile)?;
+    let req_url = format!(
+        "{}/projects/{}/datasets/{}/tables/{}/insertAll",
+        bigquery_api_base(tomlfile),
+        tomlfile.bigquery.projectid, dataset_id, table_id
+    );
+    let access_token = bigquery_access_token(tomlfile).map_err(|e| AppError::Token(e.to_string()))?;
+    let postbody = BqInsertAllReq {
+        kind: "bigquery#tableDataInsertAllRequest".to_string(),
+        skip_invalid_rows: false,
+        ignore_unknown_values: false,
+        rows: rows
+            .iter()
+            .enumerate()
+            .map(|(i, row)| BqInsertAllRow {
+                insert_id: insert_id(idempotency_key, i, row),
+                json: row.clone(),
+            })
+            .collect(),
+    };
+    let bqreq = Request::post(req_url)
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_body_json(&postbody)?
+        .with_pass(true);
+    let mut resp = send_bigquery_req(tomlfile, bqreq, true)?;
+    if !resp.get_status().is_success() {
+        return Err(bigquery_error("insertAll", resp).into());
+    }
+    let bqresp_json = resp.take_body_json::<serde_json::Value>()?;
+    Ok(bqresp_json)
+}
+
+// WHERE clause for the from/to query string of the GET routes, on the
+// table's `date_column`. None when the table has none.
+fn date_window(
+    tomlfile: &Config,
+    query_string: &serde_json::Value,
+    select: &mut QueryBuilder,
+) -> Result<Option<String>, Error> {
+    let from_str = query_string["from"].as_str();
+    let to_str = query_string["to"].as_str();
+    let column = match tomlfile.bigquery.date_column.as_deref() {
+        Some(x) => select.column(x),
+        None if from_str.is_none() && to_str.is_none() => return Ok(None),
+        None => {
+            let msg = "query string `from`/`to` need a date_column for the table";
+            return Err(AppError::BadRequest(msg.to_string()).into());
+        },
+    };
+    let condition = match (from_str, to_str) {
+        (None, None) => format!("{} >= DATE_TRUNC(CURRENT_DATE(), WEEK)", column),
+        (Some(x), None) => {
+            format!("{} >= {}", column, select.bind("from", "DATE", x))
+        },
+        (None, Some(y)) => {
+            let format = format_description!("[year]-[month]-[day]");
+            let to_date = match Date::parse(y, &format) {
+                Ok(to_date) => to_date.to_julian_day(),
+                Err(e) => {
+                    let msg = format!("Error parsing date: {}", e);
+                    return Err(AppError::BadRequest(msg).into());
+                },
+            };
+            let today = OffsetDateTime::now_utc().date();
+            let today_weekday = today.weekday().number_days_from_sunday();
+            let this_sunday = today
+                .checked_sub(time::Duration::days(today_weekday.into()))
+                .unwrap()
+                .to_julian_day();
+            if to_date < this_sunday {
+                let msg = format!("query string `to`:{} is not valid", y);
+                return Err(AppError::BadRequest(msg).into());
+            }
+            format!(
+                "{} >= DATE_TRUNC(CURRENT_DATE(), WEEK) and {} <= {}",
+                column,
+                column,
+                select.bind("to", "DATE", y)
+            )
+        },
+        (Some(x), Some(y)) => {
+            let format = format_description!("[year]-[month]-[day]");
+            let from_date = match Date::parse(x, &format) {
+                Ok(from_date) => from_date.to_julian_day(),
+                Err(e) => {
+                    let msg = format!("Error parsing date: {}", e);
+                    return Err(AppError::BadRequest(msg).into());
+                },
+            };
+            let to_date = match Date::parse(y, &format) {
+                Ok(to_date) => to_date.to_julian_day(),
+                Err(e) => {
+                    let msg = format!("Error parsing date: {}", e);
+                    return Err(AppError::BadRequest(msg).into());
+                },
+            };
+            if to_date < from_date {
+                let msg = format!("qurey string `from`: {} or `to`:{} is not valid", x, y);
+                return Err(AppError::BadRequest(msg).into());
+            }
+            let from = select.bind("from", "DATE", x);
+            let to = select.bind("to", "DATE", y);
+            format!("{} >= {} and {} <= {}", column, from, column, to)
+        },
+    };
+    Ok(Some(condition))
+}
+
+// Query string keys of the GET routes that are not column filters.
+pub(crate) const RESERVED_QUERY_KEYS: &[&str] = &[
+    "from", "to", "fields", "sort", "limit", "offset", "page_size", "page_token", "legacy_sql",
+    "cache", "read", "preset", "as_of", "geography",
+];
+const DEFAULT_LIMIT: u64 = 1000;
+const MAX_LIMIT: u64 = 10_000;
+
+// Numeric query string value; anything unparsable is a 400.
+fn query_string_number<T: std::str::FromStr>(query_string: &serde_json::Value, key: &str) -> Result<Option<T>, Error>
+where
+    T::Err: std::fmt::Display,
+{
+    let value = match query_string[key].as_str() {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    match value.parse::<T>() {
+        Ok(x) => Ok(Some(x)),
+        Err(e) => {
+            let msg = format!("query string `{}`: {} is not valid: {}", key, value, e);
+            Err(AppError::BadRequest(msg).into())
+        },
+    }
+}
+
+fn storage_read_requested(tomlfile: &Config, query_string: &serde_json::Value) -> bool {
+    query_string["read"]
+        .as_str()
+        .or(tomlfile.bigquery.read_mode.as_deref())
+        == Some(READ_MODE_STORAGE_READ)
+}
+
+// `sort=-score,term` (a leading `-` sorts descending), `limit` and `offset`.
+// REST queries get `default_limit` rows unless `limit` says otherwise, and
+// `limit` may not exceed `max_limit`; Storage Read queries are only limited
+// on request. `sortable` names the output columns of a grouped query; without
+// it any table column may be sorted on, or those of `allowed_sort_columns`.
+fn sort_and_limit(
+    tomlfile: &Config,
+    query_string: &serde_json::Value,
+    select: &mut QueryBuilder,
+    sortable: Option<&[&str]>,
+) -> Result<(), Error> {
+    let table = table_columns(tomlfile);
+    if let Some(sort) = query_string["sort"].as_str() {
+        for key in sort.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (name, descending) = match key.strip_prefix('-') {
+                Some(x) => (x, true),
+                None => (key, false),
+            };
+            let column = match sortable {
+                None if !sort_allowed(tomlfile, name) => {
+                    let msg = format!("sort `{}`: sorting on `{}` is not enabled", key, name);
+                    return Err(AppError::BadRequest(msg).into());
+                },
+                None => table_column(&table, name, "sort")?.name.as_str(),
+                Some(columns) if columns.contains(&name) => name,
+                Some(_) => {
+                    let msg = format!("sort `{}` is not a column of the aggregate", name);
+                    return Err(AppError::BadRequest(msg).into());
+                },
+            };
+            select.order_by(column, descending);
+        }
+    }
+    let storage_read = storage_read_requested(tomlfile, query_string);
+    let max_limit = tomlfile.bigquery.max_limit.unwrap_or(MAX_LIMIT);
+    let limit = match query_string_number::<u64>(query_string, "limit")? {
+        Some(x) if x == 0 || (x > max_limit && !storage_read) => {
+            let msg = format!("query string `limit`: {} is not between 1 and {}", x, max_limit);
+            return Err(AppError::BadRequest(msg).into());
+        },
+        Some(x) => Some(x),
+        None if storage_read => None,
+        None => Some(tomlfile.bigquery.default_limit.unwrap_or(DEFAULT_LIMIT)),
+    };
+    let offset = query_string_number::<u64>(query_string, "offset")?;
+    if select.is_legacy() && offset.is_some() {
+        let msg = "query string `offset` is not supported with legacy SQL";
+        return Err(AppError::BadRequest(msg.to_string()).into());
+    }
+    // BigQuery only accepts OFFSET after a LIMIT.
+    match (limit, offset) {
+        (Some(x), _) => {
+            select.limit(x);
+        },
+        (None, Some(_)) => {
+            select.limit(max_limit);
+        },
+        (None, None) => {},
+    }
+    if let Some(x) = offset {
+        select.offset(x);
+    }
+    Ok(())
+}
+const FILTER_OPERATORS: &[(&str, &str)] = &[("_gte", "gte"), ("_lte", "lte"), ("_in", "in")];
+
+fn sort_allowed(tomlfile: &Config, name: &str) -> bool {
+    match &tomlfile.bigquery.allowed_sort_columns {
+        Some(x) => x.iter().any(|x| x == name),
+        None => true,
+    }
+}
+
+// Columns of `allowed_filter_columns` take every operator; `[bigquery.filters]`
+// enables single operators on others.
+fn filter_allowed(tomlfile: &Config, name: &str, operator: &str) -> bool {
+    let bigquery = &tomlfile.bigquery;
+    bigquery
+        .allowed_filter_columns
+        .as_ref()
+        .is_some_and(|x| x.iter().any(|x| x == name))
+        || bigquery
+            .filters
+            .as_ref()
+            .and_then(|filters| filters.get(name))
+            .is_some_and(|operators| operators.iter().any(|x| x == operator))
+}
+
+// `as_of=2024-05-01T00:00:00Z` reads the table as it was at that time, within
+// BigQuery's time travel window.
+fn time_travel(query_string: &serde_json::Value, select: &mut QueryBuilder) -> Result<(), Error> {
+    let as_of = match query_string["as_of"].as_str() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    if let Err(e) = OffsetDateTime::parse(as_of, &Rfc3339) {
+        let msg = format!("query string `as_of`: {} is not an RFC 3339 timestamp: {}", as_of, e);
+        return Err(AppError::BadRequest(msg).into());
+    }
+    let placeholder = select.bind("as_of", "TIMESTAMP", as_of);
+    select.as_of(placeholder);
+    Ok(())
+}
+
+// Whether the from/to condition or a column filter already bounds `column`.
+fn partition_bounded(tomlfile: &Config, query_string: &serde_json::Value, column: &str) -> bool {
+    // The `from`/`to` window always bounds the date column.
+    if tomlfile.bigquery.date_column.as_deref() == Some(column) {
+        return true;
+    }
+    query_string.as_object().is_some_and(|entries| {
+        entries.keys().any(|key| {
+            key == column
+                || FILTER_OPERATORS
+                    .iter()
+                    .any(|(suffix, _)| key.strip_suffix(suffix) == Some(column))
+        })
+    })
+}
+
+// Keeps reads of a partitioned table from scanning every partition: an
+// unbounded `partition_column` gets the `partition_lookback_days` window, or
+// is a 400 when the table requires a partition filter.
+fn partition_filter(tomlfile: &Config, query_string: &serde_json::Value, select: &mut QueryBuilder) -> Result<(), Error> {
+    let column = match tomlfile.bigquery.partition_column.as_deref() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    if !is_table_column(&table_columns(tomlfile), column) {
+        let msg = format!("partition_column `{}` is not a column of the table", column);
+        return Err(AppError::Config(msg).into());
+    }
+    if partition_bounded(tomlfile, query_string, column) {
+        return Ok(());
+    }
+    match tomlfile.bigquery.partition_lookback_days {
+        Some(days) if !select.is_legacy() => {
+            let predicate = format!(
+                "{} >= DATE_SUB(CURRENT_DATE(), INTERVAL {} DAY)",
+                select.column(column),
+                days
+            );
+            select.filter(predicate);
+        },
+        _ if tomlfile.bigquery.require_partition_filter == Some(true) => {
+            let msg = format!(
+                "query would scan every partition of the table; bound `{}` with a column filter",
+                column
+            );
+            return Err(AppError::BadRequest(msg).into());
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+// Query parameter value for a filter on `column`; malformed INT64 and DATE
+// values are a 400 rather than a BigQuery error.
+fn filter_value<'a>(column: &Column, key: &str, value: &'a str) -> Result<&'a str, Error> {
+    let valid = match column.kind {
+        ColumnType::Int64 => value.parse::<i64>().is_ok(),
+        ColumnType::Geography => wkt_to_geojson(value).is_ok(),
+        ColumnType::Date => {
+            Date::parse(value, &format_description!("[year]-[month]-[day]")).is_ok()
+        },
+        _ => true,
+    };
+    if !valid {
+        let msg = format!(
+            "query string `{}`: {} is not a valid {}",
+            key,
+            value,
+            column.param_type()
+        );
+        return Err(AppError::BadRequest(msg).into());
+    }
+    Ok(value)
+}
+
+// Filters such as `dma_name=Seattle` or `score_gte=80` for the columns of
+// `allowed_filter_columns` and the operators declared in `[bigquery.filters]`.
+// Keys that name no column are ignored; filters on columns or operators that
+// are not declared are a 400.
+fn column_filters(tomlfile: &Config, query_string: &serde_json::Value, select: &mut QueryBuilder) -> Result<(), Error> {
+    let entries = match query_string.as_object() {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let columns = table_columns(tomlfile);
+    for (key, value) in entries {
+        if RESERVED_QUERY_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        let (name, operator) = match FILTER_OPERATORS
+            .iter()
+            .find_map(|(suffix, operator)| key.strip_suffix(suffix).map(|name| (name, *operator)))
+        {
+            Some((name, operator)) if !is_table_column(&columns, key) => (name, operator),
+            _ => (key.as_str(), "eq"),
+        };
+        if !is_table_column(&columns, name) {
+            continue;
+        }
+        if !filter_allowed(tomlfile, name, operator) {
+            let msg = format!(
+                "query string `{}`: filtering `{}` with `{}` is not enabled",
+                key, name, operator
+            );
+            return Err(AppError::BadRequest(msg).into());
+        }
+        let column = table_column(&columns, name, "filter")?;
+        let value = value.as_str().unwrap_or("");
+        let quoted = select.column(&column.name);
+        let predicate = match operator {
+            "gte" | "lte" => {
+                let placeholder = select.bind(
+                    &format!("filter_{}_{}", column.name, operator),
+                    column.param_type(),
+                    filter_value(column, key, value)?,
+                );
+                let comparison = if operator == "gte" { ">=" } else { "<=" };
+                format!("{} {} {}", quoted, comparison, placeholder)
+            },
+            "in" => {
+                let values = value
+                    .split(',')
+                    .map(|x| filter_value(column, key, x.trim()))
+                    .collect::<Result<Vec<&str>, Error>>()?;
+                let placeholder = select.bind_array(
+                    &format!("filter_{}_in", column.name),
+                    column.param_type(),
+                    &values,
+                );
+                format!("{} IN UNNEST({})", quoted, placeholder)
+            },
+            _ => {
+                let placeholder = select.bind(
+                    &format!("filter_{}", column.name),
+                    column.param_type(),
+                    filter_value(column, key, value)?,
+                );
+                format!("{} = {}", quoted, placeholder)
+            },
+        };
+        select.filter(predicate);
+    }
+    Ok(())
+}
+
+fn is_table_column(columns: &[Column], name: &str) -> bool {
+    columns.iter().any(|c| c.name == name)
+}
+
+const DEFAULT_SCOPE_COLUMN: &str = "dma_id";
+
+fn scope_column_name(tomlfile: &Config) -> &str {
+    tomlfile.bigquery.scope_column.as_deref().unwrap_or(DEFAULT_SCOPE_COLUMN)
+}
+
+// The key's `dma_ids` and the column they scope, for a key that has them. A
+// scoped key can't be used on a table without that column.
+fn key_scope<'a>(tomlfile: &'a Config, api_key: Option<&'a ApiKey>) -> Result<Option<(&'a str, &'a [i64])>, Error> {
+    let (key, ids) = match api_key.and_then(|key| Some((key, key.dma_ids.as_deref()?))) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let column = scope_column_name(tomlfile);
+    if !is_table_column(&table_columns(tomlfile), column) {
+        let msg = format!(
+            "API key `{}` is scoped on `{}`, which table {} does not have",
+            key.name, column, tomlfile.bigquery.dataset_tableid
+        );
+        return Err(AppError::Forbidden(msg).into());
+    }
+    Ok(Some((column, ids)))
+}
+
+// Restricts `statement` to the `key_scope` rows: `column IN UNNEST(@scope_ids)`,
+// with the column prefixed by `qualifier`, e.g. `T.` in a MERGE.
+fn scope_filter(scope: Option<(&str, &[i64])>, statement: &mut QueryBuilder, qualifier: &str) {
+    if let Some((column, ids)) = scope {
+        let ids = statement.bind_array("scope_ids", "INT64", ids);
+        let predicate = format!("{}{} IN UNNEST({})", qualifier, statement.column(column), ids);
+        statement.filter(predicate);
+    }
+}
+
+// 403 unless the `key_scope` allows `row`'s value of the scope column.
+fn check_scope(
+    api_key: Option<&ApiKey>,
+    scope: Option<(&str, &[i64])>,
+    row: &serde_json::Map<String, serde_json::Value>,
+) -> Result<(), Error> {
+    let (column, ids) = match scope {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let value = row.get(column);
+    if !value.and_then(|x| x.as_i64()).is_some_and(|x| ids.contains(&x)) {
+        let name = api_key.map_or("", |key| key.name.as_str());
+        let value = value.map_or("none".to_string(), ToString::to_string);
+        let msg = format!("API key `{}` may not write {} {}", name, column, value);
+        return Err(AppError::Forbidden(msg).into());
+    }
+    Ok(())
+}
+
+// SELECT for the GET routes, restricted to the API key's rows.
+fn top_rising_terms_query(
+    tomlfile: &Config,
+    query_string: &serde_json::Value,
+    api_key: Option<&ApiKey>,
+    options: &QueryOptions,
+) -> Result<QueryBuilder, Error> {
+    let mut select = if options.legacy_sql(tomlfile) {
+        QueryBuilder::legacy(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid)
+    } else {
+        QueryBuilder::new(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid)
+    };
+    // `fields=term,score` projects the SELECT onto those columns.
+    if let Some(fields) = query_string["fields"].as_str() {
+        let table = table_columns(tomlfile);
+        let mut columns: Vec<&str> = Vec::new();
+        for name in fields.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let column = table_column(&table, name, "fields")?;
+            if !columns.contains(&column.name.as_str()) {
+                columns.push(&column.name);
+            }
+        }
+        select.select(&columns);
+    }
+    time_travel(query_string, &mut select)?;
+    if let Some(condition) = date_window(tomlfile, query_string, &mut select)? {
+        select.filter(condition);
+    }
+    column_filters(tomlfile, query_string, &mut select)?;
+    partition_filter(tomlfile, query_string, &mut select)?;
+    sort_and_limit(tomlfile, query_string, &mut select, None)?;
+    scope_filter(key_scope(tomlfile, api_key)?, &mut select, "");
+    // Legacy SQL has no query parameters, and `from`/`to` are never inlined.
+    if select.is_legacy() && !select.params().is_empty() {
+        let msg = "query string `from`/`to`, `as_of`, column filters and scoped API keys are not supported with legacy SQL";
+        return Err(AppError::BadRequest(msg.to_string()).into());
+    }
+    Ok(select)
+}
+
+pub fn handle_get_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ SELECT");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let query_string = match req.get_query::<serde_json::Value>() {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Get request, querystring Error: {}", e);
+            return Err(AppError::BadRequest(msg).into());
+        },
+    };
+    let page_size = query_string_number::<u32>(&query_string, "page_size")?;
+    let key_name = api_key.as_ref().map(|key| key.name.to_string());
+    let cursor = query_string["page_token"]
+        .as_str()
+        .map(|x| decode_cursor(x, &key_name))
+        .transpose()?;
+    let stream = tomlfile.bigquery.stream_results.unwrap_or(false)
+        && stream::enabled()
+        && cursor.is_none()
+        && page_size.is_none()
+        && OutputFormat::negotiate(req.get_header_str("Accept")) != OutputFormat::Compact;
+    // A streamed result is fetched a bounded page at a time.
+    let max_results = match stream {
+        true => Some(tomlfile.bigquery.stream_page_rows.unwrap_or(DEFAULT_STREAM_PAGE_ROWS)),
+        false => page_size,
+    };
+    let options = QueryOptions {
+        max_results,
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    // Pages of a job's results are fetched by token and not revalidated.
+    let etag = match &cursor {
+        None if tomlfile.bigquery.etags.unwrap_or(false) => {
+            let select = top_rising_terms_query(&tomlfile, &query_string, api_key.as_ref(), &options)?;
+            result_etag(&tomlfile, req, &select.select_sql(), select.params(), &key_name)
+        },
+        _ => None,
+    };
+    if let Some(x) = &etag {
+        if if_none_match(req, x) {
+            return Ok(Response::from_status(StatusCode::NOT_MODIFIED)
+                .with_header(header::ETAG, x)
+                .with_header(header::VARY, "Accept"));
+        }
+    }
+    // Storage Read returns the whole result set in one response, so paged
+    // requests stay on the REST path.
+    if storage_read_requested(&tomlfile, &query_string) && cursor.is_none() && page_size.is_none() {
+        let select = top_rising_terms_query(&tomlfile, &query_string, api_key.as_ref(), &options)?;
+        let (query, query_params) = (select.select_sql(), select.params());
+        let estimate = estimated_bytes(&tomlfile, &query, query_params, &options);
+        let mut resp = storage_read_response(
+            req,
+            tomlfile,
+            &query,
+            query_params,
+            &options,
+            key_name,
+            &query_string,
+            stream,
+        )?;
+        if let Some(x) = estimate {
+            resp.set_header(ESTIMATED_BYTES_HEADER, x);
+        }
+        if let Some(x) = etag {
+            resp.set_header(header::ETAG, x);
+        }
+        return Ok(resp);
+    }
+    let mut estimate = None;
+    let (query, bqresp_json) = match &cursor {
+        Some(c) => {
+            let query = format!("jobs.getQueryResults {}", c.job_id);
+            let bqresp_json = match handle_bq_query_results_req(&tomlfile, c, &options) {
+                Ok(x) => x,
+                Err(e) => {
+                    error!("BQ query failed, query: {}", query);
+                    return Err(e);
+                },
+            };
+            if bqresp_json["jobComplete"] == false {
+                return job_pending_response(c);
+            }
+            (query, bqresp_json)
+        },
+        None => {
+            let select = top_rising_terms_query(&tomlfile, &query_string, api_key.as_ref(), &options)?;
+            let (query, params) = (select.select_sql(), select.params());
+            estimate = estimated_bytes(&tomlfile, &query, params, &options);
+            if tomlfile.bigquery.query_mode.as_deref() == Some(QUERY_MODE_JOB) {
+                let job_json = match handle_bq_job_insert_req(&tomlfile, &query, params, &options) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!("BQ query failed, query: {}", query);
+                        return Err(e);
+                    },
+                };
+                let job_cursor = match PageCursor::for_job(&job_json, key_name.clone()) {
+                    Some(x) => x,
+                    None => {
+                        let msg = format!("BQ job response has no jobReference, query: {}", query);
+                        return Err(AppError::Upstream(msg).into());
+                    },
+                };
+                let bqresp_json = match handle_bq_job_wait(&tomlfile, &job_cursor, &options) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        if let Some(resp) = bytes_billed_limit_response(&e) {
+                            return Ok(resp);
+                        }
+                        error!("BQ query failed, query: {}", query);
+                        return Err(e);
+                    },
+                };
+                if bqresp_json["jobComplete"] == false {
+                    return job_pending_response(&job_cursor);
+                }
+                (query, bqresp_json)
+            } else {
+                let bqresp_json = match handle_bq_query_req(&tomlfile, &query, params, &options) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        if let Some(resp) = bytes_billed_limit_response(&e) {
+                            return Ok(resp);
+                        }
+                        error!("BQ query failed, query: {}", query);
+                        return Err(e);
+                    },
+                };
+                // jobs.query gave up waiting after timeoutMs; the job keeps
+                // running and can be collected from /api/v1/jobs/{token}.
+                if bqresp_json["jobComplete"] == false {
+                    return match PageCursor::for_job(&bqresp_json, key_name.clone()) {
+                        Some(job_cursor) => job_pending_response(&job_cursor),
+                        None => {
+                            let msg = format!("BQ query timed out without a jobReference, query: {}", query);
+                            return Err(AppError::Timeout(msg).into());
+                        },
+                    };
+                }
+                (query, bqresp_json)
+            }
+        },
+    };
+    let next_page = PageCursor::from_response(&bqresp_json, key_name);
+    quota::record_bytes_processed(&bqresp_json);
+    let mut resp = if stream {
+        streamed_rows_response(req, tomlfile, bqresp_json, query, options, &query_string)?
+    } else {
+        let row_count = bqresp_json["rows"].as_array().map_or(0, |x| x.len());
+        tee_query(
+            &tomlfile,
+            &TeeRecord::new(
+                "get",
+                &query,
+                query_string.clone(),
+                row_count as u64,
+                req,
+                &bqresp_json,
+            ),
+        );
+        rows_response(req, &bqresp_json, &query, next_page.as_ref())?
+    };
+    if let Some(x) = estimate {
+        resp.set_header(ESTIMATED_BYTES_HEADER, x);
+    }
+    if let Some(x) = etag {
+        resp.set_header(header::ETAG, x);
     }
-    let resp_str = resp.take_body_str();
-    Ok(resp_str)
+    Ok(resp)
 }
 
-//Service Account to get access token
-fn gcp_access_token_request(tomlfile: &Config, scope_value: String) -> Result<String, Error> {
-    // create jwt
-    #[derive(Debug, serde::Serialize, serde::Deserialize)]
-    struct Scope {
-        scope: String,
-    }
-    let scope = Scope { scope: scope_value };
-    let claims = Claims::with_custom_claims(scope, Duration::from_secs(3600))
-        .with_issuer(&tomlfile.bigquery.service_account_email)
-        .with_audience(&tomlfile.gcp.aud);
-    let private_key = &tomlfile.bigquery.service_account_key.replace("\\n", "\n");
-    let jwt = RS256KeyPair::from_pem(private_key)?.sign(claims)?;
-
-    // get access token
-    #[derive(serde::Serialize, Default, Debug)]
-    struct Form {
-        grant_type: String,
-        assertion: String,
-    }
-    let form = Form {
-        grant_type: tomlfile.gcp.grant_type.to_string(),
-        assertion: jwt,
-    };
-    let mut resp = match Request::post(tomlfile.gcp.aud.to_string())
-        .with_body_form(&form)?
-        .send("idp")
-    {
+// Strong ETag of a GET result, known before the query runs: a hash of the
+// table's lastModifiedTime and streaming buffer, today's date (lookback
+// windows move with CURRENT_DATE), the query and whatever shapes its
+// rendering. None when tables.get fails, so the result is sent in full.
+fn result_etag(
+    tomlfile: &Config,
+    req: &Request,
+    query: &str,
+    params: &[QueryParameter],
+    key_name: &Option<String>,
+) -> Option<String> {
+    let table_json = match handle_bq_table_get_req(tomlfile) {
         Ok(x) => x,
         Err(e) => {
-            let msg = format!("Request to Google IDP Error: {}", e);
-            error!("{}", msg);
-            panic_with_status!(501, "{}", msg);
+            error!("ETag tables.get error: {}", e);
+            return None;
         },
     };
-    if !resp.get_status().is_success() {
-        let resp_str = resp.take_body_str();
-        let msg = format!("Error Access Token!: {}", resp_str);
-        error!("{}", msg);
-        panic_with_status!(501, "{}", msg);
-    }
-    let resp_value = resp.take_body_json::<serde_json::Value>()?;
-    let access_token = resp_value["access_token"]
-        .as_str()
-        .unwrap_or_else(|| {
-            let msg = "Can NOT get gcp access token, logger: {}";
-            error!("{}", msg);
-            panic_with_status!(501, "{}", msg);
-        })
-        .to_string();
+    let source = serde_json::json!({
+        "last_modified": table_json["lastModifiedTime"],
+        "streaming_buffer": table_json["streamingBuffer"],
+        "date": OffsetDateTime::now_utc().date().to_string(),
+        "query": query,
+        "params": params,
+        "query_string": req.get_query_str(),
+        "accept": req.get_header_str(header::ACCEPT),
+        "key": key_name,
+    });
+    Some(format!("\"{}\"", hex::encode(hmac_sha256::Hash::hash(source.to_string().as_bytes()))))
+}
 
-    Ok(access_token)
+// Whether If-None-Match lists `etag` (or is `*`), compared weakly as RFC 9110
+// asks for GET.
+pub(crate) fn if_none_match(req: &Request, etag: &str) -> bool {
+    let header = match req.get_header_str(header::IF_NONE_MATCH) {
+        Some(x) => x,
+        None => return false,
+    };
+    header
+        .split(',')
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
 }
 
-pub fn handle_insert_req(req: &mut Request) -> Result<Response, Error> {
-// This is just an example to call INSERT SQL.
-    println!("Start BQ Insert!");
-    let tomlfile = Config::load();
-    #[derive(serde::Deserialize, Default)]
-    struct TopRisingTerms {
-        refresh_date: String,
-        dma_name: String,
-        dma_id: i64,
-        term: String,
-        week: String,
-        score: i64,
-        rank: i64,
-        percent_gain: i64,
-    }
-    let top_rising_terms: TopRisingTerms = req.take_body_json::<TopRisingTerms>()?;
-    let query = format!(
-        "INSERT INTO {}.{} (refresh_date, dma_name, dma_id, term, week, score, rank, percent_gain) VALUES ('{}', '{}', {}, '{}', '{}', {}, {}, {})",
-        tomlfile.bigquery.projectid, tomlfile.bigquery.dataset_tableid, top_rising_terms.refresh_date, top_rising_terms.dma_name, top_rising_terms.dma_id, top_rising_terms.term, top_rising_terms.week, top_rising_terms.score, top_rising_terms.rank, top_rising_terms.percent_gain);
-    match handle_bq_query_req(&tomlfile, &query) {
+// Runs `query` as a job without fetching rows, then streams its destination
+// table through the Storage Read API into the response body as a JSON array.
+// With `stream` the rows go straight to the client as they are read.
+#[allow(clippy::too_many_arguments)]
+fn storage_read_response(
+    req: &Request,
+    tomlfile: Config,
+    query: &str,
+    params: &[QueryParameter],
+    options: &QueryOptions,
+    key_name: Option<String>,
+    query_string: &serde_json::Value,
+    stream: bool,
+) -> Result<Response, Error> {
+    let job_json = match handle_bq_job_insert_req(&tomlfile, query, params, options) {
         Ok(x) => x,
         Err(e) => {
-            let msg = format!("BQ Insert Error: {}, query: {}", e, query);
-            error!("{}", msg);
-            panic_with_status!(501, "{}", msg);
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    let job_cursor = match PageCursor::for_job(&job_json, key_name) {
+        Some(x) => x,
+        None => {
+            let msg = format!("BQ job response has no jobReference, query: {}", query);
+            return Err(AppError::Upstream(msg).into());
         },
     };
-    Ok(Response::from_status(StatusCode::OK))
+    let wait_options = QueryOptions {
+        max_results: Some(0),
+        ..options.clone()
+    };
+    let bqresp_json = match handle_bq_job_wait(&tomlfile, &job_cursor, &wait_options) {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(resp) = bytes_billed_limit_response(&e) {
+                return Ok(resp);
+            }
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    if bqresp_json["jobComplete"] == false {
+        return job_pending_response(&job_cursor);
+    }
+    // The results of a finished query job live in its (anonymous) destination
+    // table, which jobs.get reports.
+    let mut job_resp = bq_rest_request(
+        &tomlfile,
+        Method::GET,
+        &format!("jobs/{}?location={}", job_cursor.job_id, job_cursor.location),
+        None,
+    )?;
+    let job = job_resp.take_body_json::<serde_json::Value>()?;
+    let destination = &job["configuration"]["query"]["destinationTable"];
+    let table = match (
+        destination["projectId"].as_str(),
+        destination["datasetId"].as_str(),
+        destination["tableId"].as_str(),
+    ) {
+        (Some(p), Some(d), Some(t)) => format!("projects/{}/datasets/{}/tables/{}", p, d, t),
+        _ => {
+            let msg = format!("BQ job has no destinationTable, query: {}", query);
+            return Err(AppError::Upstream(msg).into());
+        },
+    };
+    quota::record_bytes_processed(&bqresp_json);
+    let mut record = TeeRecord::new("get", query, query_string.clone(), 0, req, &bqresp_json);
+    let mut resp = Response::from_status(StatusCode::OK).with_content_type(mime::APPLICATION_JSON);
+    set_query_metadata_headers(&mut resp, &bqresp_json);
+    set_session_header(&mut resp, &bqresp_json);
+    if stream {
+        let query = query.to_string();
+        let writer = move |body: &mut StreamingBody| -> Result<(), Error> {
+            record.row_count = write_table_rows(&tomlfile, &table, body)
+                .map_err(|e| anyhow!("{}, query: {}", e, query))?;
+            tee_query(&tomlfile, &record);
+            Ok(())
+        };
+        return Ok(streamed(resp, Box::new(writer)));
+    }
+    let mut body = Body::new();
+    record.row_count = match write_table_rows(&tomlfile, &table, &mut body) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    tee_query(&tomlfile, &record);
+    resp.set_body(body);
+    Ok(resp)
+}
+
+// totalBytesProcessed of a dry run of `query`, when `estimate_bytes` is on. A
+// failed dry run only loses the header; the query itself still runs.
+fn estimated_bytes(
+    tomlfile: &Config,
+    query: &str,
+    params: &[QueryParameter],
+    options: &QueryOptions,
+) -> Option<String> {
+    if tomlfile.bigquery.estimate_bytes != Some(true) {
+        return None;
+    }
+    let dry_run = QueryOptions {
+        dry_run: true,
+        max_results: None,
+        create_session: false,
+        ..options.clone()
+    };
+    match handle_bq_query_req(tomlfile, query, params, &dry_run) {
+        Ok(x) => x["totalBytesProcessed"].as_str().map(str::to_string),
+        Err(e) => {
+            error!("Dry run for {} failed: {}", ESTIMATED_BYTES_HEADER, e);
+            None
+        },
+    }
 }
 
-pub fn handle_get_req(req: &Request) -> Result<Response, Error> {
-    println!("Start BQ SELECT");
-    let tomlfile = Config::load();
+// GET /api/v1/top_rising_terms/aggregate?preset=weekly_score: runs a GROUP BY
+// preset from `[aggregates]` over the rows the from/to and column filters
+// select, so clients get summarized rows instead of aggregating raw ones.
+pub fn handle_aggregate_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Aggregate");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
     let query_string = match req.get_query::<serde_json::Value>() {
         Ok(x) => x,
         Err(e) => {
             let msg = format!("Get request, querystring Error: {}", e);
-            error!("{}", msg);
-            panic_with_status!(501, "{}", msg);
+            return Err(AppError::BadRequest(msg).into());
         },
     };
-    let from_str = query_string["from"].as_str();
-    let to_str = query_string["to"].as_str();
-    let condition = match (from_str, to_str) {
-        (None, None) => "week >= DATE_TRUNC(CURRENT_DATE(), week)".to_string(),
-        (Some(x), None) => format!("week >= '{}'", x),
-        (None, Some(y)) => {
-            let format = format_description::parse("[year]-[month]-[day]")?;
-            let to_date = match Date::parse(y, &format) {
-                Ok(to_date) => to_date.to_julian_day(),
-                Err(e) => {
-                    let msg = format!("Error parsing date: {}", e);
-                    error!("{}", msg);
-                    panic_with_status!(400, "{}", msg);
-                },
-            };
-            let today = OffsetDateTime::now_utc().date();
-            let today_weekday = today.weekday().number_days_from_sunday();
-            let this_sunday = today
-                .checked_sub(time::Duration::days(today_weekday.into()))
-                .unwrap()
-                .to_julian_day();
-            if to_date < this_sunday {
-                let msg = format!("query string `to`:{} is not valid", y);
-                error!("{}", msg);
-                panic_with_status!(400, "{}", msg);
-            }
-            format!(
-                "week >= DATE_TRUNC(CURRENT_DATE(), week) and week <= '{}'",
-                y
-            )
+    let presets = match &tomlfile.aggregates {
+        Some(x) => x,
+        None => {
+            let msg = "Aggregates are not configured, add an [aggregates] section";
+            return Err(AppError::NotFound(msg.to_string()).into());
         },
-        (Some(x), Some(y)) => {
-            let format = format_description::parse("[year]-[month]-[day]")?;
-            let from_date = match Date::parse(x, &format) {
-                Ok(from_date) => from_date.to_julian_day(),
-                Err(e) => {
-                    let msg = format!("Error parsing date: {}", e);
-                    error!("{}", msg);
-                    panic_with_status!(501, "{}", msg);
-                },
-            };
-            let to_date = match Date::parse(y, &format) {
-                Ok(to_date) => to_date.to_julian_day(),
-                Err(e) => {
-                    let msg = format!("Error parsing date: {}", e);
-                    error!("{}", msg);
-                    panic_with_status!(501, "{}", msg);
-                },
-            };
-            if to_date < from_date {
-                let msg = format!("qurey string `from`: {} or `to`:{} is not valid", x, y);
-                error!("{}", msg);
-                panic_with_status!(501, "{}", msg);
+    };
+    let preset_name = query_string["preset"].as_str().unwrap_or("");
+    let preset = match presets.get(preset_name) {
+        Some(x) => x,
+        None => {
+            let mut names: Vec<&str> = presets.keys().map(|x| x.as_str()).collect();
+            names.sort_unstable();
+            let msg = format!("query string `preset`: `{}` is not one of {}", preset_name, names.join(", "));
+            return Err(AppError::BadRequest(msg).into());
+        },
+    };
+    let columns = table_columns(&tomlfile);
+    if let Some(name) = preset.group_by.iter().find(|x| !is_table_column(&columns, x)) {
+        let msg = format!("aggregate `{}`: group_by `{}` is not a column of the table", preset_name, name);
+        return Err(AppError::Config(msg).into());
+    }
+    let options = request_query_options(&tomlfile, req, api_key.as_ref());
+    // Presets bind `from`/`to` and filter values, so they always run as
+    // standard SQL.
+    let options = QueryOptions {
+        use_legacy_sql: Some(false),
+        ..options
+    };
+    let group_by: Vec<&str> = preset.group_by.iter().map(|x| x.as_str()).collect();
+    // HashMap order is random; keep the SQL text stable for the query cache.
+    let mut measures: Vec<(&String, &String)> = preset.measures.iter().collect();
+    measures.sort();
+    let mut select = QueryBuilder::new(&tomlfile.bigquery.projectid, &tomlfile.bigquery.dataset_tableid);
+    select.select(&group_by).group_by(&group_by);
+    for (name, expression) in &measures {
+        select.select_as(expression, name);
+    }
+    time_travel(&query_string, &mut select)?;
+    if let Some(condition) = date_window(&tomlfile, &query_string, &mut select)? {
+        select.filter(condition);
+    }
+    column_filters(&tomlfile, &query_string, &mut select)?;
+    partition_filter(&tomlfile, &query_string, &mut select)?;
+    let mut sortable = group_by.clone();
+    sortable.extend(measures.iter().map(|(name, _)| name.as_str()));
+    sort_and_limit(&tomlfile, &query_string, &mut select, Some(&sortable))?;
+    scope_filter(key_scope(&tomlfile, api_key.as_ref())?, &mut select, "");
+    let (query, params) = (select.select_sql(), select.params());
+    let estimate = estimated_bytes(&tomlfile, &query, params, &options);
+    let bqresp_json = match handle_bq_query_req(&tomlfile, &query, params, &options) {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(resp) = bytes_billed_limit_response(&e) {
+                return Ok(resp);
             }
-            format!("date >= '{}' and date <= '{}'", x, y)
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
         },
     };
-    let query = format!(
-        "SELECT * FROM {}.{} where {}",
-        tomlfile.bigquery.projectid, tomlfile.bigquery.dataset_tableid, condition
+    if bqresp_json["jobComplete"] == false {
+        let key_name = api_key.as_ref().map(|key| key.name.to_string());
+        return match PageCursor::for_job(&bqresp_json, key_name) {
+            Some(job_cursor) => job_pending_response(&job_cursor),
+            None => {
+                let msg = format!("BQ query timed out without a jobReference, query: {}", query);
+                return Err(AppError::Timeout(msg).into());
+            },
+        };
+    }
+    quota::record_bytes_processed(&bqresp_json);
+    let row_count = bqresp_json["rows"].as_array().map_or(0, |x| x.len());
+    tee_query(
+        &tomlfile,
+        &TeeRecord::new("aggregate", &query, query_string.clone(), row_count as u64, req, &bqresp_json),
     );
-    let bqresp_json = match handle_bq_query_req(&tomlfile, &query) {
+    let mut resp = rows_response(req, &bqresp_json, &query, None)?;
+    if let Some(x) = estimate {
+        resp.set_header(ESTIMATED_BYTES_HEADER, x);
+    }
+    Ok(resp)
+}
+
+// GET /api/v1/top_rising_terms/dryrun: validates the from/to query and reports
+// the bytes it would process, without running it.
+pub fn handle_dry_run_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Dry Run");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let query_string = match req.get_query::<serde_json::Value>() {
         Ok(x) => x,
         Err(e) => {
-            let msg = format!("{}, query: {}", e, query);
-            error!("{}", msg);
-            panic_with_status!(501, "{}", msg);
+            let msg = format!("Get request, querystring Error: {}", e);
+            return Err(AppError::BadRequest(msg).into());
+        },
+    };
+    let options = QueryOptions {
+        dry_run: true,
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    let select = top_rising_terms_query(&tomlfile, &query_string, api_key.as_ref(), &options)?;
+    let (query, params) = (select.select_sql(), select.params());
+    let bqresp_json = match handle_bq_query_req(&tomlfile, &query, params, &options) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    let body = serde_json::json!({
+        "totalBytesProcessed": bqresp_json["totalBytesProcessed"],
+        "schema": bqresp_json["schema"],
+    });
+    Ok(Response::from_status(StatusCode::OK).with_body_json(&body)?)
+}
+
+// GET /api/v1/top_rising_terms/schema: column names and types of the
+// configured table.
+pub fn handle_schema_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Schema");
+    let tomlfile = load_config(req, params)?;
+    let table_json = handle_bq_table_get_req(&tomlfile)?;
+    let body = serde_json::json!({
+        "table": tomlfile.bigquery.dataset_tableid,
+        "fields": table_json["schema"]["fields"],
+    });
+    let fields = body["fields"].as_array().map_or(&[][..], |x| x);
+    records_response(req.get_header_str("Accept"), &body, fields)
+}
+
+pub const TABLES_ROUTE: &str = "/api/v1/tables";
+
+// GET /api/v1/tables and GET /api/v1/tables/{table}/columns: the tables of
+// the configured dataset and the columns of one of them, read from
+// INFORMATION_SCHEMA, so frontends can build pickers without GCP credentials.
+pub fn handle_information_schema_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ INFORMATION_SCHEMA");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let (dataset_id, _) = dataset_and_table(&tomlfile)?;
+    let table_id = params.get("table");
+    let view = if table_id.is_some() { "COLUMNS" } else { "TABLES" };
+    let mut select = QueryBuilder::new(
+        &tomlfile.bigquery.projectid,
+        &format!("{}.INFORMATION_SCHEMA.{}", dataset_id, view),
+    );
+    match table_id {
+        Some(table) => {
+            select
+                .select(&["column_name", "data_type", "is_nullable", "is_partitioning_column"])
+                .filter_eq("table_name", "STRING", Some(table))
+                .order_by("ordinal_position", false);
+        },
+        None => {
+            select
+                .select(&["table_name", "table_type", "creation_time"])
+                .order_by("table_name", false);
+        },
+    }
+    let (query, params) = (select.select_sql(), select.params());
+    let options = QueryOptions {
+        use_legacy_sql: Some(false),
+        ..request_query_options(&tomlfile, req, api_key.as_ref())
+    };
+    let bqresp_json = match handle_bq_query_req(&tomlfile, &query, params, &options) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
+        },
+    };
+    quota::record_bytes_processed(&bqresp_json);
+    let rows: Vec<serde_json::Value> = bq_rows_to(&bqresp_json)?;
+    let body = match table_id {
+        // A table without columns doesn't exist.
+        Some(table) if rows.is_empty() => {
+            let msg = format!("Table `{}.{}` does not exist", dataset_id, table);
+            return Err(AppError::NotFound(msg).into());
+        },
+        Some(table) => serde_json::json!({ "dataset": dataset_id, "table": table, "columns": rows }),
+        None => serde_json::json!({ "dataset": dataset_id, "tables": rows }),
+    };
+    records_response(req.get_header_str("Accept"), &body, &rows)
+}
+
+// GET /api/v1/jobs/{token}: results of a job submitted in job query mode.
+pub fn handle_job_req(req: &mut Request, params: &Params) -> Result<Response, Error> {
+    debug!("Start BQ Job Results");
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let key_name = api_key.as_ref().map(|key| key.name.to_string());
+    let token = params.get("token").unwrap_or("");
+    let cursor = decode_cursor(token, &key_name)?;
+    let query = format!("jobs.getQueryResults {}", cursor.job_id);
+    let options = QueryOptions {
+        timeout_ms: Some(0),
+        ..Default::default()
+    };
+    let bqresp_json = match handle_bq_query_results_req(&tomlfile, &cursor, &options) {
+        Ok(x) => x,
+        Err(e) => {
+            if let Some(resp) = bytes_billed_limit_response(&e) {
+                return Ok(resp);
+            }
+            error!("BQ query failed, query: {}", query);
+            return Err(e);
         },
     };
-    let fields: Vec<serde_json::Value> = match bqresp_json["schema"]["fields"].as_array() {
+    if bqresp_json["jobComplete"] == false {
+        return job_pending_response(&cursor);
+    }
+    let next_page = PageCursor::from_response(&bqresp_json, key_name);
+    rows_response(req, &bqresp_json, &query, next_page.as_ref())
+}
+
+// Decodes a client supplied page/job token issued to `key_name`.
+fn decode_cursor(token: &str, key_name: &Option<String>) -> Result<PageCursor, Error> {
+    match PageCursor::decode(token)? {
+        c if &c.key == key_name => Ok(c),
+        _ => Err(AppError::Forbidden("page or job token belongs to another API key".to_string()).into()),
+    }
+}
+
+// Renders the rows of a jobs.query / jobs.getQueryResults response in the
+// layout negotiated from the Accept header. `geography=geojson` renders
+// GEOGRAPHY values as GeoJSON geometries instead of WKT.
+fn rows_response(
+    req: &Request,
+    bqresp_json: &serde_json::Value,
+    query: &str,
+    next_page: Option<&PageCursor>,
+) -> Result<Response, Error> {
+    let fields: &[serde_json::Value] = match bqresp_json["schema"]["fields"].as_array() {
         None => {
             let msg = format!(
                 "BQ response format doesn't include schema.fields, query: {}",
                 query
             );
-            error!("{}", msg);
-            panic_with_status!(501, "{}", msg);
+            return Err(AppError::Upstream(msg).into());
         }
-        Some(x) => x.to_vec(),
+        Some(x) => x,
+    };
+    let converted_fields;
+    let fields = if req.get_query_parameter("geography") == Some("geojson") {
+        converted_fields = geojson_fields(fields);
+        &converted_fields[..]
+    } else {
+        fields
     };
-    let rows: Vec<serde_json::Value> = match bqresp_json["rows"].as_array() {
+    let format = OutputFormat::negotiate(req.get_header_str("Accept"));
+    let rows: &[serde_json::Value] = match bqresp_json["rows"].as_array() {
+        // CSV keeps its header row and NDJSON is simply empty.
+        None if matches!(format, OutputFormat::Csv | OutputFormat::Ndjson) => &[],
         None => {
             let msg = format!("There is no rows array in BQ resp, query: {}", query);
-            eprintln!("{}", msg);
+            error!("{}", msg);
             let body: serde_json::Value = serde_json::from_str("[]")?;
-            return Ok(Response::from_status(StatusCode::OK).with_body_json(&body)?);
-        }
-        Some(x) => x.to_vec(),
-    };
-    let mut resp_json: Vec<serde_json::Value> = Vec::new();
-    for row in rows {
-        let mut data_str = "{".to_string();
-        for (i, field) in fields.iter().enumerate() {
-            if field["type"] == "INTEGER" {
-                data_str = format!(
-                    r#"{} {}:{},"#,
-                    data_str,
-                    field["name"],
-                    row["f"][i]["v"]
-                        .as_str()
-                        .unwrap_or("0")
-                        .parse::<i64>()
-                        .unwrap()
-                );
-            } else {
-                let data_decoded = match field["name"].as_str().unwrap() {
-                    "update" => urlencoding::decode(row["f"][i]["v"].as_str().unwrap_or(""))?,
-                    _ => row["f"][i]["v"].as_str().unwrap_or("").to_string(),
-                };
-                data_str = format!(
-                    r#"{} {}:{},"#,
-                    data_str,
-                    field["name"],
-                    serde_json::to_string::<String>(&data_decoded)?
-                );
+            let mut resp = Response::from_status(StatusCode::OK).with_body_json(&body)?;
+            set_query_metadata_headers(&mut resp, bqresp_json);
+            if let Some(c) = next_page {
+                resp.set_header(NEXT_PAGE_TOKEN_HEADER, c.encode()?);
             }
+            return Ok(resp);
         }
-        data_str.pop();
-        data_str = format!(r#"{}}}"#, data_str);
-        println!("{}", data_str);
-        let data: serde_json::Value = serde_json::from_str(&data_str)?;
-        resp_json.push(data);
+        Some(x) => x,
+    };
+    let mut body = Body::new();
+    match format {
+        // The columnar layout is serialized straight into the response body.
+        OutputFormat::Compact => serde_json::to_writer(&mut body, &BqColumnar { fields, rows })?,
+        OutputFormat::Json => serde_json::to_writer(&mut body, &BqRows { fields, rows })?,
+        OutputFormat::Csv => write_csv(&mut body, fields, rows).map_err(|e| anyhow!(e))?,
+        OutputFormat::Ndjson => write_ndjson(&mut body, fields, rows).map_err(|e| anyhow!(e))?,
     }
-    Ok(Response::from_status(StatusCode::OK).with_body_json(&resp_json)?)
+    let mut resp = format_response(format, body);
+    set_query_metadata_headers(&mut resp, bqresp_json);
+    set_session_header(&mut resp, bqresp_json);
+    if let Some(c) = next_page {
+        resp.set_header(NEXT_PAGE_TOKEN_HEADER, c.encode()?);
+    }
+    Ok(resp)
 }
 
-pub fn handle_bq_query_req(tomlfile: &Config, query: &str) -> Result<serde_json::Value, Error> {
-    println!("Start BQ Query");
-    // Get Access Token to access BQ.
+// Response whose body streams every row of the result: the first page from
+// `bqresp_json`, then each further page of jobs.getQueryResults as the client
+// reads, so only one page is held in memory at a time. The query is teed once
+// the last row is out.
+fn streamed_rows_response(
+    req: &Request,
+    tomlfile: Config,
+    bqresp_json: serde_json::Value,
+    query: String,
+    options: QueryOptions,
+    query_string: &serde_json::Value,
+) -> Result<Response, Error> {
+    let mut fields = match bqresp_json["schema"]["fields"].as_array() {
+        Some(x) => x.clone(),
+        None => {
+            let msg = format!(
+                "BQ response format doesn't include schema.fields, query: {}",
+                query
+            );
+            return Err(AppError::Upstream(msg).into());
+        }
+    };
+    if req.get_query_parameter("geography") == Some("geojson") {
+        fields = geojson_fields(&fields);
+    }
+    let format = OutputFormat::negotiate(req.get_header_str("Accept"));
+    let mut resp = format_response(format, Body::new());
+    set_query_metadata_headers(&mut resp, &bqresp_json);
+    set_session_header(&mut resp, &bqresp_json);
+    let mut record = TeeRecord::new("get", &query, query_string.clone(), 0, req, &bqresp_json);
+    let writer = move |body: &mut StreamingBody| -> Result<(), Error> {
+        let mut rows = RowStream::new(format);
+        let mut page = bqresp_json;
+        loop {
+            let page_rows = page["rows"].as_array().map_or(&[][..], |x| &x[..]);
+            rows.write_page(body, &fields, page_rows).map_err(|e| anyhow!(e))?;
+            let cursor = match PageCursor::from_response(&page, None) {
+                Some(x) => x,
+                None => break,
+            };
+            page = handle_bq_query_results_req(&tomlfile, &cursor, &options)
+                .map_err(|e| anyhow!("{}, query: {}", e, query))?;
+        }
+        rows.finish(body).map_err(|e| anyhow!(e))?;
+        record.row_count = rows.rows_written();
+        tee_query(&tomlfile, &record);
+        Ok(())
+    };
+    Ok(streamed(resp, Box::new(writer)))
+}
+
+// Maps BigQuery's tabledata layout (`schema.fields` + `rows[].f[].v`) onto
+// typed rows, e.g. `Vec<TopRisingTerms>` or `Vec<serde_json::Value>`. Cells are
+// decoded as in BqRows, including nested RECORD and REPEATED columns.
+pub fn bq_rows_to<T: DeserializeOwned>(bqresp_json: &serde_json::Value) -> Result<Vec<T>, Error> {
+    let fields = match bqresp_json["schema"]["fields"].as_array() {
+        Some(x) => x,
+        None => {
+            let msg = "BQ response format doesn't include schema.fields".to_string();
+            return Err(AppError::Upstream(msg).into());
+        },
+    };
+    let rows = match bqresp_json["rows"].as_array() {
+        Some(x) => x,
+        None => return Ok(Vec::new()),
+    };
+    let value = serde_json::to_value(BqRows { fields, rows })?;
+    Ok(serde_json::from_value(value)?)
+}
+
+// Authorized call to a BigQuery REST resource of the configured project, e.g.
+// `datasets` or `datasets/{id}`. BigQuery's response is returned unchanged so
+// admin routes can relay its status and error details.
+pub(crate) fn bq_rest_request(
+    tomlfile: &Config,
+    method: Method,
+    resource: &str,
+    body: Option<&serde_json::Value>,
+) -> Result<Response, Error> {
+    debug!("Start BQ {} {}", method, resource);
     let req_url = format!(
-        "https://bigquery.googleapis.com/bigquery/v2/projects/{}/queries",
-        tomlfile.bigquery.projectid
+        "{}/projects/{}/{}",
+        bigquery_api_base(tomlfile),
+        tomlfile.bigquery.projectid, resource
+    );
+    let access_token = bigquery_access_token(tomlfile).map_err(|e| AppError::Token(e.to_string()))?;
+    let idempotent = matches!(method, Method::GET | Method::PUT | Method::DELETE);
+    let mut bqreq = Request::new(method, req_url)
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_pass(true);
+    if let Some(x) = body {
+        bqreq.set_body_json(x)?;
+    }
+    send_bigquery_req(tomlfile, bqreq, idempotent)
+}
+
+// Table metadata, including `schema.fields`, through tables.get.
+pub fn handle_bq_table_get_req(tomlfile: &Config) -> Result<serde_json::Value, Error> {
+    debug!("Start BQ Table Get");
+    let (dataset_id, table_id) = dataset_and_table(tomlfile)?;
+    let req_url = format!(
+        "{}/projects/{}/datasets/{}/tables/{}",
+        bigquery_api_base(tomlfile),
+        tomlfile.bigquery.projectid, dataset_id, table_id
+    );
+    let access_token = bigquery_access_token(tomlfile).map_err(|e| AppError::Token(e.to_string()))?;
+    let bqreq = Request::get(req_url)
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_pass(true);
+    let mut resp = send_bigquery_req(tomlfile, bqreq, true)?;
+    if !resp.get_status().is_success() {
+        return Err(bigquery_error("tables.get", resp).into());
+    }
+    Ok(resp.take_body_json::<serde_json::Value>()?)
+}
+
+// Jobs run in the configured project, a `[[targets]]` project or the project of
+// a `[tables]` entry; results are only fetched from those.
+fn job_project_allowed(tomlfile: &Config, project: &str) -> bool {
+    project == tomlfile.bigquery.projectid
+        || tomlfile.targets.iter().flatten().any(|x| x.projectid == project)
+        || tomlfile.tables.iter().flat_map(|x| x.values()).any(|x| x.projectid == project)
+}
+
+// Fetches a page of a query job's results. `jobComplete` is false while the
+// job is still running.
+pub fn handle_bq_query_results_req(
+    tomlfile: &Config,
+    cursor: &PageCursor,
+    options: &QueryOptions,
+) -> Result<serde_json::Value, Error> {
+    debug!("Start BQ getQueryResults");
+    let project = cursor.project.as_deref().unwrap_or(&tomlfile.bigquery.projectid);
+    if !job_project_allowed(tomlfile, project) {
+        let msg = format!("{} is not a configured project", project);
+        return Err(AppError::Forbidden(msg).into());
+    }
+    let mut req_url = format!(
+        "{}/projects/{}/queries/{}?location={}",
+        bigquery_api_base(tomlfile),
+        urlencoding::encode(project),
+        urlencoding::encode(&cursor.job_id),
+        urlencoding::encode(&cursor.location)
     );
-    let access_token = match gcp_access_token_request(tomlfile, tomlfile.bigquery.scope.to_string())
-    {
+    if let Some(page_token) = &cursor.page_token {
+        req_url = format!("{}&pageToken={}", req_url, urlencoding::encode(page_token));
+    }
+    if let Some(max_results) = options.max_results {
+        req_url = format!("{}&maxResults={}", req_url, max_results);
+    }
+    if let Some(timeout_ms) = options.timeout_ms {
+        req_url = format!("{}&timeoutMs={}", req_url, timeout_ms);
+    }
+    let access_token = bigquery_access_token(tomlfile).map_err(|e| AppError::Token(e.to_string()))?;
+    let bqreq = Request::get(req_url)
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_pass(true);
+    let mut resp = send_bigquery_req(tomlfile, bqreq, true)?;
+    if !resp.get_status().is_success() {
+        return Err(bigquery_error("getQueryResults", resp).into());
+    }
+    let bqresp_json: serde_json::Value = match serde_json::from_str(&resp.take_body_str()) {
         Ok(x) => x,
         Err(e) => {
-            let msg = format!("Token Request Error: {}", e);
-            error!("{}", msg);
-            return Err(anyhow!(msg));
+            let msg = format!("BQ response format is NOT valid JSON: {}", e);
+            return Err(AppError::Upstream(msg).into());
+        },
+    };
+    Ok(bqresp_json)
+}
+
+// Submits the query as an asynchronous job through jobs.insert.
+pub fn handle_bq_job_insert_req(
+    tomlfile: &Config,
+    query: &str,
+    params: &[QueryParameter],
+    options: &QueryOptions,
+) -> Result<serde_json::Value, Error> {
+    debug!("Start BQ Job Insert");
+    let req_url = format!(
+        "{}/projects/{}/jobs",
+        bigquery_api_base(tomlfile),
+        tomlfile.bigquery.projectid
+    );
+    let access_token = bigquery_access_token(tomlfile).map_err(|e| AppError::Token(e.to_string()))?;
+    let postbody = BqJobReq {
+        job_reference: BqJobReference {
+            location: options
+                .location
+                .clone()
+                .unwrap_or_else(|| default_location(tomlfile).to_string()),
+        },
+        configuration: BqJobConfiguration {
+            query: BqJobQueryConfiguration {
+                query: query.to_string(),
+                use_legacy_sql: options.legacy_sql(tomlfile),
+                parameter_mode: options.parameter_mode(params),
+                query_parameters: options.query_parameters(params),
+                maximum_bytes_billed: tomlfile
+                    .bigquery
+                    .maximum_bytes_billed
+                    .map(|x| x.to_string()),
+                use_query_cache: options.query_cache(tomlfile),
+                create_session: options.session_create(),
+                connection_properties: options.connection_properties(),
+            },
+            labels: options.job_labels(),
         },
     };
+    let bqreq = Request::post(req_url)
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_body_json(&postbody)?
+        .with_pass(true);
+    let mut resp = send_bigquery_req(tomlfile, bqreq, false)?;
+    if !resp.get_status().is_success() {
+        return Err(bigquery_error("Job Insert", resp).into());
+    }
+    Ok(resp.take_body_json::<serde_json::Value>()?)
+}
+
+// Polls jobs.getQueryResults until the job completes or the poll budget is
+// spent. The last response is returned either way.
+pub fn handle_bq_job_wait(
+    tomlfile: &Config,
+    cursor: &PageCursor,
+    options: &QueryOptions,
+) -> Result<serde_json::Value, Error> {
+    let budget_ms = tomlfile
+        .bigquery
+        .job_poll_budget_ms
+        .unwrap_or(DEFAULT_JOB_POLL_BUDGET_MS);
+    let started = std::time::Instant::now();
+    loop {
+        let elapsed_ms = started.elapsed().as_millis() as u32;
+        let step_ms = budget_ms.saturating_sub(elapsed_ms).min(JOB_POLL_STEP_MS);
+        let poll_options = QueryOptions {
+            timeout_ms: Some(step_ms),
+            ..options.clone()
+        };
+        let bqresp_json = handle_bq_query_results_req(tomlfile, cursor, &poll_options)?;
+        if bqresp_json["jobComplete"] != false || step_ms == 0 {
+            return Ok(bqresp_json);
+        }
+        if started.elapsed().as_millis() as u32 >= budget_ms {
+            return Ok(bqresp_json);
+        }
+    }
+}
+
+// 400 carrying BigQuery's message when a query was rejected for exceeding
+// `maximum_bytes_billed`, instead of the generic 502.
+fn bytes_billed_limit_response(e: &Error) -> Option<Response> {
+    let e = e.downcast_ref::<AppError>()?;
+    if e.reason() != Some(BYTES_BILLED_LIMIT_EXCEEDED) {
+        return None;
+    }
+    let msg = format!("Query exceeds maximum_bytes_billed: {}", e);
+    error!("{}", msg);
+    Some(Response::from(AppError::BadRequest(msg)))
+}
+
+// Copies cacheHit, totalRows, totalBytesProcessed and jobReference of a query
+// response into headers, so they are available whatever the body format.
+fn set_query_metadata_headers(resp: &mut Response, bqresp_json: &serde_json::Value) {
+    if let Some(x) = bqresp_json["cacheHit"].as_bool() {
+        resp.set_header(CACHE_HIT_HEADER, x.to_string());
+    }
+    if let Some(x) = bqresp_json["totalRows"].as_str() {
+        resp.set_header(TOTAL_ROWS_HEADER, x);
+    }
+    if let Some(x) = bqresp_json["totalBytesProcessed"].as_str() {
+        resp.set_header(BYTES_PROCESSED_HEADER, x);
+    }
+    let job = &bqresp_json["jobReference"];
+    if let (Some(project), Some(job_id)) = (job["projectId"].as_str(), job["jobId"].as_str()) {
+        let reference = match job["location"].as_str() {
+            Some(location) => format!("{}:{}.{}", project, location, job_id),
+            None => format!("{}:{}", project, job_id),
+        };
+        resp.set_header(JOB_REFERENCE_HEADER, reference);
+    }
+}
+
+// 202 for a job that is still running, pointing at `/api/v1/jobs/{token}`.
+fn job_pending_response(cursor: &PageCursor) -> Result<Response, Error> {
+    let token = cursor.encode()?;
+    let body = serde_json::json!({ "job": token, "jobComplete": false });
+    Ok(Response::from_status(StatusCode::ACCEPTED)
+        .with_header("Location", format!("/api/v1/jobs/{}", token))
+        .with_body_json(&body)?)
+}
+
+pub fn handle_bq_query_req(
+    tomlfile: &Config,
+    query: &str,
+    params: &[QueryParameter],
+    options: &QueryOptions,
+) -> Result<serde_json::Value, Error> {
+    debug!("Start BQ Query");
+    // Get Access Token to access BQ.
+    let req_url = format!(
+        "{}/projects/{}/queries",
+        bigquery_api_base(tomlfile),
+        tomlfile.bigquery.projectid
+    );
+    let access_token = bigquery_access_token(tomlfile).map_err(|e| AppError::Token(e.to_string()))?;
     // Requesting to BQ
     let querydata = BqQueryReq {
         kind: "bigquery#queryRequest".to_string(),
         query: query.to_string(),
-        location: "US".to_string(),
-        use_legacy_sql: false,
-    };
-    let bqresp_str = match gcp_bq_job_query(&access_token, &req_url, querydata) {
-        Ok(x) => x,
-        Err(e) => {
-            let msg = format!("BQ Query Request Error: {}", e);
-            error!("{}", msg);
-            return Err(anyhow!(msg));
-        },
+        location: options
+            .location
+            .clone()
+            .unwrap_or_else(|| default_location(tomlfile).to_string()),
+        use_legacy_sql: options.legacy_sql(tomlfile),
+        parameter_mode: options.parameter_mode(params),
+        query_parameters: options.query_parameters(params),
+        max_results: options.max_results,
+        timeout_ms: options.timeout_ms.or(tomlfile.bigquery.query_timeout_ms),
+        dry_run: if options.dry_run { Some(true) } else { None },
+        maximum_bytes_billed: tomlfile
+            .bigquery
+            .maximum_bytes_billed
+            .map(|x| x.to_string()),
+        labels: options.job_labels(),
+        use_query_cache: options.query_cache(tomlfile),
+        create_session: options.session_create(),
+        connection_properties: options.connection_properties(),
+        request_id: Some(random_uuid()),
     };
+    let bqresp_str = gcp_bq_job_query(tomlfile, &access_token, &req_url, querydata)?;
     let bqresp_json: serde_json::Value = match serde_json::from_str(&bqresp_str) {
         Ok(x) => x,
         Err(e) => {
             let msg = format!("BQ response format is NOT valid JSON: {}", e);
-            eprintln!("{}", msg);
-            return Err(anyhow!(msg));
+            return Err(AppError::Upstream(msg).into());
         },
     };
     Ok(bqresp_json)
 }
+
+// Runs a multi-statement script. BigQuery executes each statement as a child
+// job of the script's job; their status is attached as `statements`, and when
+// the script's own response has no rows the last SELECT's results are used.
+pub fn handle_bq_script_req(
+    tomlfile: &Config,
+    script: &str,
+    params: &[QueryParameter],
+    options: &QueryOptions,
+) -> Result<serde_json::Value, Error> {
+    debug!("Start BQ Script");
+    let mut bqresp_json = handle_bq_query_req(tomlfile, script, params, options)?;
+    if bqresp_json["jobComplete"] == false {
+        return Ok(bqresp_json);
+    }
+    let parent = match PageCursor::for_job(&bqresp_json, None) {
+        Some(x) => x,
+        None => return Ok(bqresp_json),
+    };
+    let resource = format!("jobs?parentJobId={}", urlencoding::encode(&parent.job_id));
+    let mut jobs_resp = bq_rest_request(tomlfile, Method::GET, &resource, None)?;
+    if !jobs_resp.get_status().is_success() {
+        return Err(bigquery_error("jobs.list", jobs_resp).into());
+    }
+    let jobs_json = jobs_resp.take_body_json::<serde_json::Value>()?;
+    let mut children: Vec<&serde_json::Value> = match jobs_json["jobs"].as_array() {
+        Some(x) => x.iter().collect(),
+        None => Vec::new(),
+    };
+    // jobs.list returns the newest job first.
+    children.sort_by_key(|job| {
+        job["statistics"]["creationTime"]
+            .as_str()
+            .unwrap_or("0")
+            .parse::<u64>()
+            .unwrap_or(0)
+    });
+    let statements: Vec<serde_json::Value> = children
+        .iter()
+        .map(|job| {
+            serde_json::json!({
+                "job_id": job["jobReference"]["jobId"],
+                "statement_type": job["statistics"]["query"]["statementType"],
+                "state": job["state"],
+                "error": job["status"]["errorResult"],
+                "num_dml_affected_rows": job["statistics"]["query"]["numDmlAffectedRows"],
+                "total_bytes_processed": job["statistics"]["totalBytesProcessed"],
+            })
+        })
+        .collect();
+    let last_select = children
+        .iter()
+        .rev()
+        .find(|job| job["statistics"]["query"]["statementType"] == "SELECT");
+    if bqresp_json["schema"].is_null() {
+        if let Some(cursor) = last_select.and_then(|job| PageCursor::for_job(job, None)) {
+            let results = handle_bq_query_results_req(tomlfile, &cursor, options)?;
+            bqresp_json["schema"] = results["schema"].clone();
+            bqresp_json["rows"] = results["rows"].clone();
+        }
+    }
+    bqresp_json["statements"] = serde_json::Value::from(statements);
+    Ok(bqresp_json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TableConfiguration;
+
+    const KEY: &[u8] = b"key";
+
+    fn cursor() -> PageCursor {
+        let bqresp_json = serde_json::json!({
+            "jobReference": { "projectId": "p", "jobId": "j", "location": "EU" },
+            "pageToken": "next",
+        });
+        PageCursor::from_response(&bqresp_json, Some("reader".to_string())).unwrap()
+    }
+
+    #[test]
+    fn page_cursor_round_trips() {
+        let token = cursor().encode_signed(KEY).unwrap();
+        let decoded = PageCursor::decode_signed(&token, KEY).unwrap();
+        assert_eq!(decoded.project.as_deref(), Some("p"));
+        assert_eq!(decoded.job_id, "j");
+        assert_eq!(decoded.location, "EU");
+        assert_eq!(decoded.page_token.as_deref(), Some("next"));
+        assert_eq!(decoded.key.as_deref(), Some("reader"));
+    }
+
+    #[test]
+    fn page_cursor_rejects_other_keys_and_edits() {
+        let token = cursor().encode_signed(KEY).unwrap();
+        assert!(matches!(PageCursor::decode_signed(&token, b"other"), Err(AppError::Forbidden(_))));
+        let (_, mac) = token.split_once('.').unwrap();
+        let forged = serde_json::json!({ "project": "q", "job_id": "j", "location": "EU", "key": null });
+        let forged = base64::encode_config(forged.to_string(), base64::URL_SAFE_NO_PAD);
+        let forged = format!("{}.{}", forged, mac);
+        assert!(matches!(PageCursor::decode_signed(&forged, KEY), Err(AppError::Forbidden(_))));
+    }
+
+    #[test]
+    fn page_cursor_rejects_malformed_tokens() {
+        for token in ["", "unsigned", "a.!!"] {
+            assert!(matches!(PageCursor::decode_signed(token, KEY), Err(AppError::BadRequest(_))));
+        }
+    }
+
+    fn config() -> Config {
+        toml::from_str(include_str!("config.toml")).unwrap()
+    }
+
+    #[test]
+    fn reads_of_the_configured_table_start_at_the_current_week() {
+        let tomlfile = config();
+        let mut select = QueryBuilder::new("p", "d.t");
+        let condition = date_window(&tomlfile, &serde_json::json!({}), &mut select).unwrap();
+        assert_eq!(condition.as_deref(), Some("`week` >= DATE_TRUNC(CURRENT_DATE(), WEEK)"));
+        let query_string = serde_json::json!({ "from": "2024-01-07", "to": "2024-02-04" });
+        let condition = date_window(&tomlfile, &query_string, &mut select).unwrap();
+        assert_eq!(condition.as_deref(), Some("`week` >= @from and `week` <= @to"));
+    }
+
+    #[test]
+    fn tables_without_a_date_column_have_no_window() {
+        let table: TableConfiguration =
+            toml::from_str("projectid = \"p\"\ndataset_tableid = \"d.events\"").unwrap();
+        let mut tomlfile = config();
+        tomlfile.bigquery.use_table(&table);
+        assert_eq!(tomlfile.bigquery.dataset_tableid, "d.events");
+        let mut select = QueryBuilder::new("p", "d.events");
+        assert_eq!(date_window(&tomlfile, &serde_json::json!({}), &mut select).unwrap(), None);
+        let query_string = serde_json::json!({ "from": "2024-01-07" });
+        assert!(date_window(&tomlfile, &query_string, &mut select).is_err());
+        assert!(select.params().is_empty());
+    }
+
+    #[test]
+    fn scoped_keys_bind_their_ids_and_may_only_write_them() {
+        let key = ApiKey { name: "seattle".to_string(), tier: None, dma_ids: Some(vec![819]) };
+        let scope = Some(("market", key.dma_ids.as_deref().unwrap()));
+        let mut select = QueryBuilder::new("p", "d.t");
+        scope_filter(scope, &mut select, "T.");
+        assert_eq!(select.delete_sql(), "DELETE FROM `p.d.t` WHERE T.`market` IN UNNEST(@scope_ids)");
+        let row = |x: serde_json::Value| x.as_object().unwrap().clone();
+        assert!(check_scope(Some(&key), scope, &row(serde_json::json!({ "market": 819 }))).is_ok());
+        assert!(check_scope(Some(&key), scope, &row(serde_json::json!({ "market": 501 }))).is_err());
+        assert!(check_scope(Some(&key), scope, &row(serde_json::json!({ "dma_id": 819 }))).is_err());
+        assert!(check_scope(None, None, &row(serde_json::json!({}))).is_ok());
+    }
+
+    #[test]
+    fn table_operations_follow_method_and_route() {
+        assert_eq!(table_operation(&Method::GET, "/t/:name/schema"), "read");
+        assert_eq!(table_operation(&Method::HEAD, "/t/:name"), "read");
+        assert_eq!(table_operation(&Method::POST, "/t/:name"), "insert");
+        assert_eq!(table_operation(&Method::POST, "/t/:name/upsert"), "upsert");
+        assert_eq!(table_operation(&Method::PUT, "/t/:name"), "update");
+        assert_eq!(table_operation(&Method::DELETE, "/t/:name"), "delete");
+    }
+}
@@ -0,0 +1,220 @@
+use serde_json::json;
+
+// GEOGRAPHY values travel as WKT (`POINT(-122.35 47.62)`), which is what
+// BigQuery accepts on insert and returns from queries. `wkt_to_geojson`
+// validates WKT and converts it to a GeoJSON geometry object for clients that
+// ask for GeoJSON.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(format!("expected `{}` at offset {}", c, self.pos));
+        }
+        self.pos += c.len_utf8();
+        Ok(())
+    }
+
+    fn token(&mut self, accept: impl Fn(char) -> bool) -> &'a str {
+        self.skip_whitespace();
+        let rest = &self.text[self.pos..];
+        let len = rest.find(|c: char| !accept(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        let word = self.token(|c| c.is_ascii_alphabetic());
+        if word.is_empty() {
+            return Err(format!("expected a geometry type at offset {}", self.pos));
+        }
+        Ok(word.to_ascii_uppercase())
+    }
+
+    // `EMPTY` in place of a coordinate list.
+    fn empty(&mut self) -> bool {
+        let start = self.pos;
+        if self
+            .token(|c| c.is_ascii_alphabetic())
+            .eq_ignore_ascii_case("EMPTY")
+        {
+            return true;
+        }
+        self.pos = start;
+        false
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let start = self.pos;
+        let token = self.token(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E'));
+        token
+            .parse::<f64>()
+            .map_err(|_| format!("expected a number at offset {}", start))
+    }
+
+    // `x y`, optionally with z (and m).
+    fn coordinate(&mut self) -> Result<serde_json::Value, String> {
+        let mut values = vec![self.number()?, self.number()?];
+        while matches!(self.peek(), Some(c) if c != ',' && c != ')') {
+            values.push(self.number()?);
+        }
+        Ok(json!(values))
+    }
+
+    // `(item, item, ...)`.
+    fn list(
+        &mut self,
+        item: fn(&mut Self) -> Result<serde_json::Value, String>,
+    ) -> Result<serde_json::Value, String> {
+        if self.empty() {
+            return Ok(json!([]));
+        }
+        self.expect('(')?;
+        let mut items = vec![item(self)?];
+        while self.peek() == Some(',') {
+            self.expect(',')?;
+            items.push(item(self)?);
+        }
+        self.expect(')')?;
+        Ok(json!(items))
+    }
+
+    fn coordinates(&mut self) -> Result<serde_json::Value, String> {
+        self.list(Self::coordinate)
+    }
+
+    fn rings(&mut self) -> Result<serde_json::Value, String> {
+        self.list(Self::coordinates)
+    }
+
+    // MULTIPOINT members may or may not be parenthesized.
+    fn point_member(&mut self) -> Result<serde_json::Value, String> {
+        if self.peek() != Some('(') {
+            return self.coordinate();
+        }
+        self.expect('(')?;
+        let coordinate = self.coordinate()?;
+        self.expect(')')?;
+        Ok(coordinate)
+    }
+
+    fn geometry(&mut self) -> Result<serde_json::Value, String> {
+        let kind = self.word()?;
+        Ok(match kind.as_str() {
+            "POINT" => {
+                if self.empty() {
+                    json!({ "type": "Point", "coordinates": [] })
+                } else {
+                    self.expect('(')?;
+                    let coordinate = self.coordinate()?;
+                    self.expect(')')?;
+                    json!({ "type": "Point", "coordinates": coordinate })
+                }
+            }
+            "LINESTRING" => json!({ "type": "LineString", "coordinates": self.coordinates()? }),
+            "POLYGON" => json!({ "type": "Polygon", "coordinates": self.rings()? }),
+            "MULTIPOINT" => {
+                json!({ "type": "MultiPoint", "coordinates": self.list(Self::point_member)? })
+            }
+            "MULTILINESTRING" => {
+                json!({ "type": "MultiLineString", "coordinates": self.rings()? })
+            }
+            "MULTIPOLYGON" => {
+                json!({ "type": "MultiPolygon", "coordinates": self.list(Self::rings)? })
+            }
+            "GEOMETRYCOLLECTION" => {
+                json!({ "type": "GeometryCollection", "geometries": self.list(Self::geometry)? })
+            }
+            x => return Err(format!("unsupported geometry type `{}`", x)),
+        })
+    }
+}
+
+pub fn wkt_to_geojson(wkt: &str) -> Result<serde_json::Value, String> {
+    let mut parser = Parser { text: wkt, pos: 0 };
+    let geometry = parser
+        .geometry()
+        .map_err(|e| format!("invalid WKT: {}", e))?;
+    if parser.peek().is_some() {
+        return Err(format!(
+            "invalid WKT: unexpected text at offset {}",
+            parser.pos
+        ));
+    }
+    Ok(geometry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_simple_geometries() {
+        assert_eq!(
+            wkt_to_geojson("POINT(-122.35 47.62)").unwrap(),
+            json!({ "type": "Point", "coordinates": [-122.35, 47.62] })
+        );
+        assert_eq!(
+            wkt_to_geojson(" linestring (0 0, 1 1 2) ").unwrap(),
+            json!({ "type": "LineString", "coordinates": [[0.0, 0.0], [1.0, 1.0, 2.0]] })
+        );
+        assert_eq!(
+            wkt_to_geojson("POLYGON((0 0, 1 0, 1 1, 0 0))").unwrap(),
+            json!({
+                "type": "Polygon",
+                "coordinates": [[[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 0.0]]]
+            })
+        );
+    }
+
+    #[test]
+    fn converts_multi_geometries_and_empty() {
+        let expected = json!({ "type": "MultiPoint", "coordinates": [[0.0, 0.0], [1.0, 1.0]] });
+        assert_eq!(wkt_to_geojson("MULTIPOINT(0 0, 1 1)").unwrap(), expected);
+        assert_eq!(
+            wkt_to_geojson("MULTIPOINT((0 0), (1 1))").unwrap(),
+            expected
+        );
+        assert_eq!(
+            wkt_to_geojson("POINT EMPTY").unwrap(),
+            json!({ "type": "Point", "coordinates": [] })
+        );
+        assert_eq!(
+            wkt_to_geojson("GEOMETRYCOLLECTION(POINT(1 2), LINESTRING EMPTY)").unwrap(),
+            json!({
+                "type": "GeometryCollection",
+                "geometries": [
+                    { "type": "Point", "coordinates": [1.0, 2.0] },
+                    { "type": "LineString", "coordinates": [] }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_wkt() {
+        for wkt in [
+            "",
+            "CIRCLE(0 0)",
+            "POINT(0)",
+            "POINT(0 0",
+            "POINT(0 0) x",
+            "POINT(a b)",
+        ] {
+            assert!(wkt_to_geojson(wkt).is_err(), "{}", wkt);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use crate::admin::API_KEY_STORE;
+use crate::config::Config;
+use crate::gcp::{bigquery_api_base, bigquery_backend, handle_bq_query_req, QueryOptions};
+use crate::token::{cached_bigquery_token_remaining, gcp_access_token_request};
+use fastly::http::{header, StatusCode};
+use fastly::kv_store::KVStore;
+use fastly::{Error, Request, Response};
+use std::time::Instant;
+
+pub const HEALTHZ_ROUTE: &str = "/healthz";
+pub const STATUS_ROUTE: &str = "/status";
+// Service version Fastly runs, set in the environment of every request.
+const SERVICE_VERSION_ENV: &str = "FASTLY_SERVICE_VERSION";
+
+// GET /healthz is for uptime monitors, which call it without credentials. It
+// reports each component as ok or failed, with how long its check took:
+// the config, an access token for the service account (from the token cache
+// when it holds one) and, with `[health] check_query = true`, a `SELECT 1`
+// through jobs.query. Checks after a failed one are skipped. The status is 200
+// when every component is ok and 503 otherwise.
+fn run_check(check: impl FnOnce() -> Result<(), String>) -> serde_json::Value {
+    let started = Instant::now();
+    let result = check();
+    let latency_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(()) => serde_json::json!({ "status": "ok", "latency_ms": latency_ms }),
+        Err(e) => serde_json::json!({ "status": "fail", "latency_ms": latency_ms, "error": e }),
+    }
+}
+
+// One component's check.
+type Check = fn(&Config) -> Result<(), String>;
+
+pub fn check_config(tomlfile: &Config) -> Result<(), String> {
+    tomlfile.validate().map_err(|errors| {
+        let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        errors.join("; ")
+    })
+}
+
+fn check_access_token(tomlfile: &Config) -> Result<(), String> {
+    gcp_access_token_request(tomlfile, &tomlfile.bigquery.scopes())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn select_one(tomlfile: &Config) -> Result<(), String> {
+    let bqresp_json = handle_bq_query_req(tomlfile, "SELECT 1", &[], &QueryOptions::default())
+        .map_err(|e| e.to_string())?;
+    if bqresp_json["jobComplete"] != true {
+        return Err("SELECT 1 did not complete".to_string());
+    }
+    Ok(())
+}
+
+pub fn handle_healthz_req(_req: &Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    let mut checks: Vec<(&str, Check)> = vec![
+        ("config", check_config),
+        ("access_token", check_access_token),
+    ];
+    if tomlfile
+        .health
+        .as_ref()
+        .and_then(|x| x.check_query)
+        .unwrap_or(false)
+    {
+        checks.push(("bigquery", select_one));
+    }
+
+    let mut components = serde_json::Map::new();
+    let mut healthy = true;
+    for (name, check) in checks {
+        let result = match healthy {
+            true => run_check(|| check(&tomlfile)),
+            false => serde_json::json!({ "status": "skipped" }),
+        };
+        healthy = result["status"] == "ok";
+        components.insert(name.to_string(), result);
+    }
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "fail" },
+        "components": components,
+    });
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(Response::from_status(status)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_json(&body)?)
+}
+
+// GET /status tells operators which dependency is failing, also without
+// credentials: whether the `idp` and BigQuery backends answer, whether the
+// token cache holds a BigQuery token, whether each configured KV Store is
+// linked, and the build and service versions. Unlike /healthz it fetches no
+// token and runs no query. The status is 200 when the config is valid and
+// every backend and store is ok, and 503 otherwise.
+fn probe(url: &str, backend: &str) -> serde_json::Value {
+    let started = Instant::now();
+    let result = Request::get(url).with_pass(true).send(backend);
+    let latency_ms = started.elapsed().as_millis() as u64;
+    // Any HTTP answer, even an error status, shows the backend is reachable.
+    match result {
+        Ok(resp) => serde_json::json!({
+            "status": "ok",
+            "backend": backend,
+            "http_status": resp.get_status().as_u16(),
+            "latency_ms": latency_ms,
+        }),
+        Err(e) => serde_json::json!({
+            "status": "fail",
+            "backend": backend,
+            "latency_ms": latency_ms,
+            "error": e.to_string(),
+        }),
+    }
+}
+
+// An unlinked store is only a failure when the config names it.
+fn kv_store_status(name: &str, configured: bool) -> serde_json::Value {
+    match KVStore::open(name) {
+        Ok(Some(_)) => serde_json::json!({ "status": "ok", "linked": true }),
+        Ok(None) if !configured => serde_json::json!({ "status": "ok", "linked": false }),
+        Ok(None) => serde_json::json!({
+            "status": "fail",
+            "linked": false,
+            "error": "not linked to this service",
+        }),
+        Err(e) => serde_json::json!({ "status": "fail", "error": e.to_string() }),
+    }
+}
+
+fn token_cache_status(tomlfile: &Config) -> serde_json::Value {
+    if tomlfile.token_cache.is_none() {
+        return serde_json::json!({ "enabled": false });
+    }
+    match cached_bigquery_token_remaining(tomlfile) {
+        Some(x) => serde_json::json!({ "enabled": true, "hit": true, "expires_in_secs": x }),
+        None => serde_json::json!({ "enabled": true, "hit": false }),
+    }
+}
+
+pub fn handle_status_req(_req: &Request) -> Result<Response, Error> {
+    let tomlfile = Config::load();
+    let config = run_check(|| check_config(&tomlfile));
+
+    let mut backends = serde_json::Map::new();
+    // External accounts get their tokens from STS rather than the `idp` backend.
+    if tomlfile.bigquery.external_account.is_none() {
+        backends.insert("idp".to_string(), probe(&tomlfile.gcp.aud, "idp"));
+    }
+    backends.insert(
+        "bigquery".to_string(),
+        probe(&bigquery_api_base(&tomlfile), bigquery_backend(&tomlfile)),
+    );
+
+    let mut kv_stores = serde_json::Map::new();
+    kv_stores.insert(
+        API_KEY_STORE.to_string(),
+        kv_store_status(API_KEY_STORE, false),
+    );
+    let configured = [
+        tomlfile.token_cache.as_ref().map(|x| &x.kv_store),
+        tomlfile.oidc.as_ref().and_then(|x| x.kv_store.as_ref()),
+        tomlfile.bigquery.schema_cache.as_ref(),
+    ];
+    for name in configured.iter().flatten() {
+        kv_stores.insert(name.to_string(), kv_store_status(name, true));
+    }
+
+    let healthy = config["status"] == "ok"
+        && backends.values().all(|x| x["status"] == "ok")
+        && kv_stores.values().all(|x| x["status"] == "ok");
+    let body = serde_json::json!({
+        "status": if healthy { "ok" } else { "fail" },
+        "config": config,
+        "backends": backends,
+        "token_cache": token_cache_status(&tomlfile),
+        "kv_stores": kv_stores,
+        "build": {
+            "version": env!("CARGO_PKG_VERSION"),
+            "service_version": std::env::var(SERVICE_VERSION_ENV).ok(),
+        },
+    });
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok(Response::from_status(status)
+        .with_header(header::CACHE_CONTROL, "no-store")
+        .with_body_json(&body)?)
+}
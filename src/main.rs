@@ -1,23 +1,231 @@
+mod admin;
+mod auth;
+mod batch;
+mod body_limit;
+mod compression;
 mod config;
+mod cors;
+mod csv;
+mod edge_cache;
+mod error;
 mod gcp;
+mod geography;
+mod health;
+mod mtls;
+mod oidc;
+mod openapi;
+mod query;
+mod quota;
+mod rate_limit;
+mod request_id;
+mod router;
+mod rows;
+mod schema;
+mod stats;
+mod storage_read;
+mod storage_write;
+mod stream;
+mod tee;
+mod token;
+mod token_cache;
+mod webhook;
 
-use fastly::http::{Method, StatusCode};
+use config::Config;
+use error::AppError;
+use fastly::http::Method;
 use fastly::{Error, Request, Response};
+use router::Router;
 
 const LOGENDPOINT: &str = "papertrail";
 
-#[fastly::main]
-fn main(mut req: Request) -> Result<Response, Error> {
+// Not `#[fastly::main]`: a streamed result is sent in two steps, the head
+// and then its body, by `stream::send`.
+fn main() -> Result<(), Error> {
+    let req = Request::from_client();
+    request_id::assign(&req);
     //set logstreaming
-    log_fastly::init_simple(LOGENDPOINT, log::LevelFilter::Error);
+    request_id::init_logging(LOGENDPOINT, log::LevelFilter::Error);
     fastly::log::set_panic_endpoint(LOGENDPOINT).unwrap();
 
+    let mut resp = serve(req)?;
+    resp.set_header(request_id::REQUEST_ID_HEADER, request_id::current());
+    stream::send(resp)
+}
+
+// Answers the request, with the CORS and compression headers every response
+// gets.
+fn serve(req: Request) -> Result<Response, Error> {
+    // CORS preflights carry no credentials, so they are answered before the
+    // middleware runs.
+    let tomlfile = Config::load();
+    if let Some(resp) = cors::preflight(&tomlfile, &req) {
+        return Ok(resp);
+    }
+    let origin = req.get_header_str("Origin").map(str::to_string);
+    let accept_encoding = req.get_header_str("Accept-Encoding").map(str::to_string);
+    let mut resp = match handle(req) {
+        Ok(x) => x,
+        Err(e) => error::error_response(e),
+    };
+    cors::apply_headers(&tomlfile, origin.as_deref(), &mut resp);
+    compression::apply_compression(&tomlfile, accept_encoding.as_deref(), &mut resp);
+    Ok(resp)
+}
+
+// Checks the config, runs the routing middleware and dispatches the request.
+fn handle(mut req: Request) -> Result<Response, Error> {
+    // Uptime monitors and operators call /healthz and /status without
+    // credentials, and they report a broken config themselves.
+    if [health::HEALTHZ_ROUTE, health::STATUS_ROUTE].contains(&req.get_path()) {
+        return router().dispatch(&mut req);
+    }
+
+    // A broken config.toml gets one clear 500 listing its problems instead of a
+    // confusing failure halfway through a request.
+    if let Err(errors) = Config::load().validate() {
+        return Err(AppError::InvalidConfig(errors).into());
+    }
+
+    // Bodies are bounded before anything reads them, the webhook signature
+    // check included.
+    body_limit::check_body_size(&Config::load(), &mut req)?;
+
+    // The /admin routes check X-Admin-Key themselves.
+    if !req.get_path().starts_with("/admin") {
+        auth::require_api_key(&req)?;
+        let tomlfile = Config::load();
+        if let Some(resp) = rate_limit::check_rate_limit(&tomlfile, &req) {
+            return Ok(resp);
+        }
+        let signed = webhook::verify_signature(&tomlfile, &mut req)?;
+        // In passthrough mode Authorization carries the caller's access token,
+        // which BigQuery checks itself.
+        let passthrough = tomlfile.bigquery.oauth_passthrough.unwrap_or(false);
+        if !signed && !passthrough && !matches!(*req.get_method(), Method::GET | Method::HEAD) {
+            oidc::require_id_token(&tomlfile, &req)?;
+        }
+    }
+
     // Handle the authorized request
-    match (req.get_method(), req.get_path()) {
-        (&Method::GET, "/api/v1/top_rising_terms") => Ok(gcp::handle_get_req(&req)?),
-        (&Method::POST, "/api/v1/top_rising_terms") => Ok(gcp::handle_insert_req(&mut req)?),
+    router().dispatch(&mut req)
+}
+
+// Table routes, for the configured table, a `[[targets]]` table or a named
+// `[tables]` entry.
+const TABLE_ROUTES: &[&str] = &[
+    "/api/v1/top_rising_terms",
+    "/bq/:project/:dataset/:table",
+    "/t/:name",
+];
 
-        // Catch all other requests and return a 404.
-        _ => Ok(Response::from_status(StatusCode::NOT_FOUND)),
+fn router() -> Router {
+    let mut router = Router::new();
+    for prefix in TABLE_ROUTES {
+        router = router
+            .get(prefix, |req, params| {
+                quota::metered(req, params, |req, params| {
+                    edge_cache::cached_read(req, params, gcp::handle_get_req)
+                })
+            })
+            .get(&format!("{}/dryrun", prefix), |req, params| {
+                quota::metered(req, params, gcp::handle_dry_run_req)
+            })
+            .get(&format!("{}/schema", prefix), |req, params| {
+                quota::metered(req, params, gcp::handle_schema_req)
+            })
+            .get(&format!("{}/aggregate", prefix), |req, params| {
+                quota::metered(req, params, |req, params| {
+                    edge_cache::cached_read(req, params, gcp::handle_aggregate_req)
+                })
+            })
+            .post(prefix, |req, params| {
+                quota::metered(req, params, |req, params| {
+                    edge_cache::purge_after_write(req, params, gcp::handle_insert_req)
+                })
+            })
+            .post(&format!("{}/upsert", prefix), |req, params| {
+                quota::metered(req, params, |req, params| {
+                    edge_cache::purge_after_write(req, params, gcp::handle_upsert_req)
+                })
+            })
+            .put(prefix, |req, params| {
+                quota::metered(req, params, |req, params| {
+                    edge_cache::purge_after_write(req, params, gcp::handle_update_req)
+                })
+            })
+            .delete(prefix, |req, params| {
+                quota::metered(req, params, |req, params| {
+                    edge_cache::purge_after_write(req, params, gcp::handle_delete_req)
+                })
+            });
     }
+    router
+        .post("/ingest/csv", |req, params| {
+            quota::metered(req, params, |req, params| {
+                edge_cache::purge_after_write(req, params, gcp::handle_insert_req)
+            })
+        })
+        .post("/api/v1/sessions", |req, params| {
+            quota::metered(req, params, |req, params| {
+                gcp::handle_session_req(req, params, "BEGIN TRANSACTION")
+            })
+        })
+        .post("/api/v1/sessions/:id/commit", |req, params| {
+            quota::metered(req, params, |req, params| {
+                edge_cache::purge_all_after_write(req, params, |req, params| {
+                    gcp::handle_session_req(req, params, "COMMIT TRANSACTION")
+                })
+            })
+        })
+        .post("/api/v1/sessions/:id/rollback", |req, params| {
+            quota::metered(req, params, |req, params| {
+                gcp::handle_session_req(req, params, "ROLLBACK TRANSACTION")
+            })
+        })
+        .get("/api/v1/jobs/:token", |req, params| {
+            quota::metered(req, params, gcp::handle_job_req)
+        })
+        .get(gcp::TABLES_ROUTE, |req, params| {
+            quota::metered(req, params, gcp::handle_information_schema_req)
+        })
+        .get(
+            &format!("{}/:table/columns", gcp::TABLES_ROUTE),
+            |req, params| quota::metered(req, params, gcp::handle_information_schema_req),
+        )
+        .get(health::HEALTHZ_ROUTE, |req, _| {
+            health::handle_healthz_req(req)
+        })
+        .get(health::STATUS_ROUTE, |req, _| {
+            health::handle_status_req(req)
+        })
+        .post(batch::BATCH_ROUTE, |req, _| {
+            batch::handle_batch_req(req, &crate::router())
+        })
+        .get(openapi::OPENAPI_ROUTE, |_, _| {
+            openapi::handle_openapi_req(&crate::router(), TABLE_ROUTES)
+        })
+        .get("/admin/status", |req, _| admin::handle_status_req(req))
+        .get("/admin/jobs", |req, _| admin::handle_jobs_req(req))
+        .post("/admin/query", |req, params| {
+            edge_cache::purge_all_after_write(req, params, |req, _| admin::handle_query_req(req))
+        })
+        .post("/admin/load", |req, _| admin::handle_load_req(req))
+        .post("/admin/export", |req, _| admin::handle_export_req(req))
+        .post("/admin/snapshot", |req, _| admin::handle_snapshot_req(req))
+        .post("/admin/refresh", |req, _| admin::handle_refresh_req(req))
+        // These handlers tell their operations apart by the method and the
+        // `:name` or `:id` parameter.
+        .post("/admin/keys", admin::handle_keys_req)
+        .get("/admin/keys", admin::handle_keys_req)
+        .post("/admin/keys/:name/rotate", admin::handle_keys_req)
+        .delete("/admin/keys/:name", admin::handle_keys_req)
+        .post("/admin/datasets", admin::handle_datasets_req)
+        .get("/admin/datasets", admin::handle_datasets_req)
+        .delete("/admin/datasets/:id", admin::handle_datasets_req)
+        .post("/admin/tables", admin::handle_tables_req)
+        .patch("/admin/tables/:id", admin::handle_tables_req)
+        .delete("/admin/tables/:id", admin::handle_tables_req)
+        .get("/admin/tables/:id/iam", admin::handle_tables_req)
+        .put("/admin/tables/:id/iam", admin::handle_tables_req)
+        .post("/admin/tables/:id/iam/grant", admin::handle_tables_req)
 }
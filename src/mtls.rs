@@ -0,0 +1,191 @@
+use crate::config::{Config, MtlsConfiguration};
+use crate::error::AppError;
+use fastly::{Error, Request};
+use fastly_shared::ClientCertVerifyResult;
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_VERSION: u8 = 0xa0;
+
+// Inserts and the /admin routes can be limited to clients presenting a
+// certificate that Fastly's mTLS setup verified, and whose SHA-256
+// fingerprint or subject is listed under `[mtls]`. Fingerprints are compared
+// as `openssl x509 -noout -fingerprint -sha256` prints them, colons and case
+// aside; subjects as `openssl x509 -noout -subject -nameopt RFC2253` does,
+// e.g. `CN=ingest,O=Example`.
+fn certificate_der(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .map(str::trim)
+        .skip_while(|x| !x.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|x| !x.starts_with("-----END CERTIFICATE-----"))
+        .collect();
+    base64::decode(body).ok()
+}
+
+// The tag, contents and remainder of the DER element at the start of `input`.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *input.first()?;
+    let first = *input.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let octets = first & 0x7f;
+        if octets == 0 || octets > 4 {
+            return None;
+        }
+        let len = input
+            .get(2..2 + octets)?
+            .iter()
+            .fold(0usize, |acc, x| (acc << 8) | *x as usize);
+        (len, 2 + octets)
+    };
+    let contents = input.get(header..header + len)?;
+    Some((tag, contents, &input[header + len..]))
+}
+
+fn der_elements(mut input: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut elements = Vec::new();
+    while !input.is_empty() {
+        let (tag, contents, rest) = der_element(input)?;
+        elements.push((tag, contents));
+        input = rest;
+    }
+    Some(elements)
+}
+
+fn attribute_name(oid: &[u8]) -> String {
+    match oid {
+        [0x55, 0x04, 0x03] => "CN".to_string(),
+        [0x55, 0x04, 0x06] => "C".to_string(),
+        [0x55, 0x04, 0x07] => "L".to_string(),
+        [0x55, 0x04, 0x08] => "ST".to_string(),
+        [0x55, 0x04, 0x0a] => "O".to_string(),
+        [0x55, 0x04, 0x0b] => "OU".to_string(),
+        _ => {
+            let mut arcs: Vec<u64> = Vec::new();
+            let mut arc = 0u64;
+            for x in oid {
+                arc = (arc << 7) | (*x & 0x7f) as u64;
+                if x & 0x80 == 0 {
+                    arcs.push(arc);
+                    arc = 0;
+                }
+            }
+            let mut parts = match arcs.first() {
+                Some(x) if *x >= 80 => vec![2, x - 80],
+                Some(x) => vec![x / 40, x % 40],
+                None => vec![],
+            };
+            parts.extend(arcs.iter().skip(1));
+            let parts: Vec<String> = parts.iter().map(|x| x.to_string()).collect();
+            parts.join(".")
+        }
+    }
+}
+
+// The certificate's subject as an RFC 2253 string: the last RDN first.
+fn certificate_subject(der: &[u8]) -> Option<String> {
+    let (tag, certificate, _) = der_element(der)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+    let (tag, tbs, _) = der_element(certificate)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+    let mut fields = der_elements(tbs)?.into_iter().peekable();
+    if let Some((TAG_VERSION, _)) = fields.peek() {
+        fields.next();
+    }
+    // serialNumber, signature, issuer, validity, subject
+    let (tag, subject) = fields.nth(4)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+    let mut rdns = Vec::new();
+    for (tag, rdn) in der_elements(subject)? {
+        if tag != TAG_SET {
+            return None;
+        }
+        let mut attributes = Vec::new();
+        for (tag, attribute) in der_elements(rdn)? {
+            match (tag, der_elements(attribute)?.as_slice()) {
+                (TAG_SEQUENCE, [(TAG_OID, oid), (_, value)]) => attributes.push(format!(
+                    "{}={}",
+                    attribute_name(oid),
+                    String::from_utf8_lossy(value)
+                )),
+                _ => return None,
+            }
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+fn is_allowed(mtls: &MtlsConfiguration, der: &[u8]) -> bool {
+    if let Some(allowed) = &mtls.allowed_fingerprints {
+        let fingerprint = hex::encode(hmac_sha256::Hash::hash(der));
+        if allowed
+            .iter()
+            .any(|x| normalize_fingerprint(x) == fingerprint)
+        {
+            return true;
+        }
+    }
+    if let Some(allowed) = &mtls.allowed_subjects {
+        if let Some(subject) = certificate_subject(der) {
+            if allowed.contains(&subject) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Called by the insert and admin handlers when `[mtls]` is configured: a
+// missing or unverified client certificate gets 401, one that is not allowed
+// 403.
+pub fn require_client_certificate(tomlfile: &Config, req: &Request) -> Result<(), Error> {
+    let mtls = match &tomlfile.mtls {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let pem = match req.get_tls_raw_client_certificate() {
+        Some(x) => x,
+        None => {
+            let msg = "Client certificate required";
+            return Err(AppError::Unauthorized(msg.to_string()).into());
+        }
+    };
+    match req.get_tls_client_cert_verify_result() {
+        Some(ClientCertVerifyResult::Ok) => {}
+        result => {
+            let msg = format!("Client certificate did not verify: {:?}", result);
+            return Err(AppError::Unauthorized(msg).into());
+        }
+    }
+    let der = match certificate_der(pem) {
+        Some(x) => x,
+        None => {
+            let msg = "Client certificate is not PEM";
+            return Err(AppError::Unauthorized(msg.to_string()).into());
+        }
+    };
+    if !is_allowed(mtls, &der) {
+        let msg = format!(
+            "Client certificate `{}` is not allowed",
+            certificate_subject(&der).unwrap_or_default()
+        );
+        return Err(AppError::Forbidden(msg).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,240 @@
+use crate::config::{Config, OidcConfiguration};
+use crate::error::AppError;
+use anyhow::anyhow;
+use fastly::kv_store::{KVStore, KVStoreError};
+use fastly::{Error, Request};
+use jwt_simple::algorithms::{RS256PublicKey, RSAPublicKeyLike};
+use jwt_simple::common::VerificationOptions;
+use jwt_simple::token::Token;
+use log::error;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+const DEFAULT_JWKS_URL: &str = "https://www.googleapis.com/oauth2/v3/certs";
+const DEFAULT_JWKS_BACKEND: &str = "google_certs";
+const DEFAULT_ISSUERS: &[&str] = &["https://accounts.google.com", "accounts.google.com"];
+// How long fetched keys are cached when Google sends no max-age.
+const DEFAULT_JWKS_TTL_SECS: u64 = 3600;
+// Tokens with an unknown kid refetch the keys at most this often, so forged
+// ones can't make every request call Google.
+const MIN_JWKS_REFRESH_SECS: i64 = 60;
+
+thread_local! {
+    // Without `kv_store`, the keys this instance fetched, by JWKS URL, with
+    // the Unix times they were fetched at and expire at.
+    static FETCHED: RefCell<HashMap<String, (Jwks, i64, i64)>> = RefCell::new(HashMap::new());
+}
+
+// Callers of mutating routes prove who they are with a Google-signed OIDC ID
+// token, e.g. from Cloud Scheduler or a Cloud Run service, sent as
+// `Authorization: Bearer <token>`. Its signature is checked against Google's
+// JWKS, which is cached in the `[oidc]` KV Store or else in memory, and its issuer, audience
+// and, optionally, email against `[oidc]`.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct IdTokenClaims {
+    email: Option<String>,
+    email_verified: Option<bool>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+struct Jwk {
+    kid: String,
+    n: String,
+    e: String,
+}
+
+fn cache_key(url: &str) -> String {
+    format!(
+        "jwks/{}",
+        hex::encode(hmac_sha256::Hash::hash(url.as_bytes()))
+    )
+}
+
+fn open_cache(oidc: &OidcConfiguration) -> Option<KVStore> {
+    let name = oidc.kv_store.as_ref()?;
+    match KVStore::open(name) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("KV Store `{}` open error: {}", name, e);
+            None
+        }
+    }
+}
+
+// `max-age` of a Cache-Control header value.
+fn max_age(cache_control: &str) -> Option<u64> {
+    cache_control
+        .split(',')
+        .filter_map(|x| x.trim().strip_prefix("max-age="))
+        .find_map(|x| x.parse().ok())
+}
+
+fn fetch_jwks(oidc: &OidcConfiguration, url: &str) -> Result<(String, u64), Error> {
+    let backend = oidc.backend.as_deref().unwrap_or(DEFAULT_JWKS_BACKEND);
+    let mut resp = Request::get(url).send(backend)?;
+    if !resp.get_status().is_success() {
+        return Err(anyhow!("JWKS request error: {}", resp.get_status()));
+    }
+    let ttl = resp
+        .get_header_str("Cache-Control")
+        .and_then(max_age)
+        .unwrap_or(DEFAULT_JWKS_TTL_SECS);
+    Ok((resp.take_body_str(), ttl))
+}
+
+// The cached keys and the Unix time they were fetched at, which the entry's
+// metadata holds.
+fn cached_jwks(store: &KVStore, url: &str) -> Option<(Jwks, Option<i64>)> {
+    match store.lookup(&cache_key(url)) {
+        Ok(mut x) => {
+            let fetched_at = x
+                .metadata()
+                .and_then(|x| String::from_utf8_lossy(&x).parse().ok());
+            let jwks = serde_json::from_slice(&x.take_body_bytes()).ok()?;
+            Some((jwks, fetched_at))
+        }
+        Err(KVStoreError::ItemNotFound) => None,
+        Err(e) => {
+            error!("JWKS cache lookup error: {}", e);
+            None
+        }
+    }
+}
+
+// The unexpired keys this instance fetched itself.
+fn fetched_jwks(url: &str, now: i64) -> Option<(Jwks, Option<i64>)> {
+    FETCHED.with(|fetched| match fetched.borrow().get(url) {
+        Some((jwks, fetched_at, expires_at)) if now < *expires_at => {
+            Some((jwks.clone(), Some(*fetched_at)))
+        }
+        _ => None,
+    })
+}
+
+// Google's signing keys, from the KV cache when it holds them, or without
+// `kv_store` from this instance's memory. `refresh` skips the cache, for
+// tokens signed with a key it does not know yet, unless the keys were fetched
+// less than MIN_JWKS_REFRESH_SECS ago.
+fn jwks(oidc: &OidcConfiguration, refresh: bool) -> Result<Jwks, Error> {
+    let url = oidc.jwks_url.as_deref().unwrap_or(DEFAULT_JWKS_URL);
+    let store = open_cache(oidc);
+    let now = OffsetDateTime::now_utc().unix_timestamp();
+    let cached = match &store {
+        Some(x) => cached_jwks(x, url),
+        None => fetched_jwks(url, now),
+    };
+    match cached {
+        Some((jwks, _)) if !refresh => return Ok(jwks),
+        Some((jwks, Some(fetched_at))) if now - fetched_at < MIN_JWKS_REFRESH_SECS => {
+            return Ok(jwks)
+        }
+        _ => {}
+    }
+    let (body, ttl) = fetch_jwks(oidc, url)?;
+    let jwks: Jwks = serde_json::from_str(&body)?;
+    match store {
+        Some(store) => {
+            if let Err(e) = store
+                .build_insert()
+                .time_to_live(Duration::from_secs(ttl))
+                .metadata(&now.to_string())
+                .execute(&cache_key(url), body)
+            {
+                error!("JWKS cache insert error: {}", e);
+            }
+        }
+        None => FETCHED.with(|fetched| {
+            let expires_at = now + ttl as i64;
+            fetched
+                .borrow_mut()
+                .insert(url.to_string(), (jwks.clone(), now, expires_at));
+        }),
+    }
+    Ok(jwks)
+}
+
+fn public_key(jwks: &Jwks, kid: &str) -> Result<Option<RS256PublicKey>, Error> {
+    let jwk = match jwks.keys.iter().find(|x| x.kid == kid) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let n = base64::decode_config(&jwk.n, base64::URL_SAFE_NO_PAD)?;
+    let e = base64::decode_config(&jwk.e, base64::URL_SAFE_NO_PAD)?;
+    Ok(Some(RS256PublicKey::from_components(&n, &e)?))
+}
+
+fn verify(oidc: &OidcConfiguration, token: &str) -> Result<IdTokenClaims, Error> {
+    let metadata = Token::decode_metadata(token)?;
+    if metadata.algorithm() != "RS256" {
+        return Err(anyhow!(
+            "Unsupported ID token alg: {}",
+            metadata.algorithm()
+        ));
+    }
+    let kid = metadata
+        .key_id()
+        .ok_or_else(|| anyhow!("ID token has no kid"))?;
+    let key = match public_key(&jwks(oidc, false)?, kid)? {
+        Some(x) => x,
+        // Google rotates its keys; a new one is not in the cache yet.
+        None => public_key(&jwks(oidc, true)?, kid)?
+            .ok_or_else(|| anyhow!("ID token signed with unknown key {}", kid))?,
+    };
+    let issuers: HashSet<String> = match &oidc.issuers {
+        Some(x) => x.iter().cloned().collect(),
+        None => DEFAULT_ISSUERS.iter().map(|x| x.to_string()).collect(),
+    };
+    let options = VerificationOptions {
+        allowed_issuers: Some(issuers),
+        allowed_audiences: Some(HashSet::from([oidc.audience.clone()])),
+        ..Default::default()
+    };
+    Ok(key
+        .verify_token::<IdTokenClaims>(token, Some(options))?
+        .custom)
+}
+
+// Routing middleware for the mutating routes when `[oidc]` is configured:
+// a missing or invalid ID token gets 401, a caller outside `allowed_emails`
+// 403.
+pub fn require_id_token(tomlfile: &Config, req: &Request) -> Result<(), Error> {
+    let oidc = match &tomlfile.oidc {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let token = match req
+        .get_header_str("Authorization")
+        .and_then(|x| x.strip_prefix("Bearer "))
+    {
+        Some(x) => x.trim(),
+        None => {
+            let msg = "Missing Authorization: Bearer ID token";
+            return Err(AppError::Unauthorized(msg.to_string()).into());
+        }
+    };
+    let claims = match verify(oidc, token) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("ID token is not valid: {}", e);
+            return Err(AppError::Unauthorized(msg).into());
+        }
+    };
+    if let Some(allowed) = &oidc.allowed_emails {
+        let email = match (&claims.email, claims.email_verified) {
+            (Some(x), Some(true)) => x,
+            _ => "",
+        };
+        if !allowed.iter().any(|x| x == email) {
+            let msg = format!("ID token for `{}` is not allowed", email);
+            return Err(AppError::Forbidden(msg).into());
+        }
+    }
+    Ok(())
+}
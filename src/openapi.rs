@@ -0,0 +1,389 @@
+use crate::admin::ADMIN_KEY_HEADER;
+use crate::auth::API_KEY_HEADER;
+use crate::gcp::RESERVED_QUERY_KEYS;
+use crate::router::Router;
+use fastly::http::StatusCode;
+use fastly::{Error, Response};
+
+pub const OPENAPI_ROUTE: &str = "/openapi.json";
+
+// GET /openapi.json: an OpenAPI 3 document generated from the router, so every
+// route `main` dispatches is listed with its path parameters. Summaries,
+// query parameters, bodies and responses are looked up here by pattern; a
+// route without an entry is still listed, under its method and path.
+
+// Method, router pattern and summary of an operation.
+type Summary = (&'static str, &'static str, &'static str);
+
+const SUMMARIES: &[Summary] = &[
+    ("POST", "/ingest/csv", "Insert the rows of a CSV body"),
+    (
+        "POST",
+        "/api/v1/sessions",
+        "Start a session with a transaction",
+    ),
+    (
+        "POST",
+        "/api/v1/sessions/:id/commit",
+        "Commit a session's transaction",
+    ),
+    (
+        "POST",
+        "/api/v1/sessions/:id/rollback",
+        "Roll back a session's transaction",
+    ),
+    (
+        "GET",
+        "/api/v1/jobs/:token",
+        "Fetch the rows of a query that answered 202",
+    ),
+    ("GET", "/api/v1/tables", "List the dataset's tables"),
+    (
+        "GET",
+        "/api/v1/tables/:table/columns",
+        "List a table's columns",
+    ),
+    (
+        "POST",
+        "/batch",
+        "Run several inserts, reads and deletes in one request",
+    ),
+    ("GET", "/healthz", "Health check for uptime monitors"),
+    ("GET", "/status", "Status of the service's dependencies"),
+    ("GET", "/openapi.json", "This document"),
+    (
+        "GET",
+        "/admin/status",
+        "Readiness checks, quota consumption and enabled features",
+    ),
+    ("GET", "/admin/jobs", "List the project's jobs"),
+    ("POST", "/admin/query", "Run a SQL script"),
+    ("POST", "/admin/load", "Start a load job for files in GCS"),
+    (
+        "POST",
+        "/admin/export",
+        "Export a table or query result to GCS",
+    ),
+    ("POST", "/admin/snapshot", "Snapshot a table"),
+    ("POST", "/admin/refresh", "Refresh the materialized view"),
+    ("POST", "/admin/keys", "Create a key"),
+    ("GET", "/admin/keys", "List keys"),
+    (
+        "POST",
+        "/admin/keys/:name/rotate",
+        "Issue a new key and revoke the old one",
+    ),
+    ("DELETE", "/admin/keys/:name", "Revoke a key"),
+    ("POST", "/admin/datasets", "Create a dataset"),
+    ("GET", "/admin/datasets", "List the project's datasets"),
+    ("DELETE", "/admin/datasets/:id", "Delete a dataset"),
+    ("POST", "/admin/tables", "Create a table"),
+    (
+        "PATCH",
+        "/admin/tables/:id",
+        "Update a table's schema to the configured one",
+    ),
+    ("DELETE", "/admin/tables/:id", "Delete a table"),
+    ("GET", "/admin/tables/:id/iam", "Read a table's IAM policy"),
+    (
+        "PUT",
+        "/admin/tables/:id/iam",
+        "Replace a table's IAM policy",
+    ),
+    (
+        "POST",
+        "/admin/tables/:id/iam/grant",
+        "Grant a role on a table",
+    ),
+];
+
+// Operations of a table route: the suffix after the table prefix, the method
+// and a summary.
+const TABLE_OPERATIONS: &[(&str, &str, &str)] = &[
+    ("", "GET", "Query rows"),
+    ("/dryrun", "GET", "Estimate the bytes a query would process"),
+    ("/schema", "GET", "Column names and types of the table"),
+    ("/aggregate", "GET", "Run an aggregate preset"),
+    ("", "POST", "Insert rows"),
+    (
+        "/upsert",
+        "POST",
+        "Update or insert a row by its key columns",
+    ),
+    ("", "PUT", "Update rows matching keys"),
+    ("", "DELETE", "Delete rows matching column predicates"),
+];
+
+fn query_parameter_description(name: &str) -> &'static str {
+    match name {
+        "from" => "First DATE of the range, with `to`",
+        "to" => "Last DATE of the range, with `from`",
+        "fields" => "Comma-separated columns to return",
+        "sort" => "Comma-separated columns to order by; `-` sorts descending",
+        "limit" => "Maximum number of rows",
+        "offset" => "Rows to skip",
+        "page_size" => "Rows per page; the next page's token comes in X-Next-Page-Token",
+        "page_token" => "Token of the page to fetch, from X-Next-Page-Token",
+        "legacy_sql" => "Run the query as legacy SQL",
+        "cache" => "Whether BigQuery may answer from its results cache",
+        "read" => "`rest` or `storage_read`",
+        "preset" => "Aggregate preset from `[aggregates]`",
+        "as_of" => "Read the table as of this RFC 3339 time",
+        "geography" => "`geojson` renders GEOGRAPHY values as GeoJSON",
+        _ => "",
+    }
+}
+
+// OpenAPI path of a router pattern: `:name` and `*name` become `{name}`.
+fn openapi_path(pattern: &str) -> (String, Vec<String>) {
+    let mut params = Vec::new();
+    let segments: Vec<String> = pattern
+        .split('/')
+        .map(
+            |x| match x.strip_prefix(':').or_else(|| x.strip_prefix('*')) {
+                Some(name) => {
+                    params.push(name.to_string());
+                    format!("{{{}}}", name)
+                }
+                None => x.to_string(),
+            },
+        )
+        .collect();
+    (segments.join("/"), params)
+}
+
+fn json_content(schema: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({ "application/json": { "schema": schema } })
+}
+
+fn schema_ref(name: &str) -> serde_json::Value {
+    serde_json::json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn rows_content() -> serde_json::Value {
+    serde_json::json!({
+        "application/json": { "schema": schema_ref("Rows") },
+        "application/x-ndjson": { "schema": { "type": "string" } },
+        "text/csv": { "schema": { "type": "string" } },
+    })
+}
+
+fn responses(method: &str, path: &str, table_suffix: Option<&str>) -> serde_json::Value {
+    let mut responses = serde_json::Map::new();
+    let ok = match (method, table_suffix) {
+        ("GET", Some("")) | ("GET", Some("/aggregate")) => {
+            responses.insert(
+                "202".to_string(),
+                serde_json::json!({
+                    "description": "The query is still running; fetch its rows from /api/v1/jobs/{token}",
+                    "content": json_content(schema_ref("JobPending")),
+                }),
+            );
+            serde_json::json!({ "description": "Rows", "content": rows_content() })
+        }
+        ("POST", Some("")) => {
+            responses.insert(
+                "400".to_string(),
+                serde_json::json!({
+                    "description": "Rows that do not match the table",
+                    "content": json_content(schema_ref("InsertErrors")),
+                }),
+            );
+            serde_json::json!({
+                "description": "Rows inserted",
+                "content": json_content(serde_json::json!({
+                    "type": "object",
+                    "properties": { "num_rows": { "type": "integer" } },
+                })),
+            })
+        }
+        ("POST", Some("/upsert")) | ("PUT", Some("")) | ("DELETE", Some("")) => {
+            serde_json::json!({
+                "description": "Rows changed",
+                "content": json_content(serde_json::json!({
+                    "type": "object",
+                    "properties": { "num_dml_affected_rows": { "type": "integer" } },
+                })),
+            })
+        }
+        _ if path == "/api/v1/jobs/{token}" => {
+            responses.insert(
+                "202".to_string(),
+                serde_json::json!({
+                    "description": "The query is still running",
+                    "content": json_content(schema_ref("JobPending")),
+                }),
+            );
+            serde_json::json!({ "description": "Rows", "content": rows_content() })
+        }
+        _ if path == "/healthz" || path == "/status" => {
+            responses.insert(
+                "503".to_string(),
+                serde_json::json!({
+                    "description": "A component failed",
+                    "content": json_content(schema_ref("Health")),
+                }),
+            );
+            serde_json::json!({ "description": "Every component is ok", "content": json_content(schema_ref("Health")) })
+        }
+        _ => serde_json::json!({
+            "description": "Success",
+            "content": json_content(serde_json::json!({ "type": "object" })),
+        }),
+    };
+    responses.insert("200".to_string(), ok);
+    serde_json::Value::Object(responses)
+}
+
+fn operation<'a>(
+    method: &'a str,
+    pattern: &str,
+    summary: Option<&str>,
+    table_suffix: Option<&str>,
+) -> (&'a str, String, serde_json::Value) {
+    let (path, names) = openapi_path(pattern);
+    let mut parameters: Vec<serde_json::Value> = names
+        .iter()
+        .map(|name| {
+            serde_json::json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            })
+        })
+        .collect();
+    if method == "GET"
+        && matches!(
+            table_suffix,
+            Some("") | Some("/aggregate") | Some("/dryrun")
+        )
+    {
+        for name in RESERVED_QUERY_KEYS {
+            parameters.push(serde_json::json!({
+                "name": name,
+                "in": "query",
+                "description": query_parameter_description(name),
+                "schema": { "type": "string" },
+            }));
+        }
+    }
+    let mut operation = serde_json::json!({
+        "summary": summary.map_or_else(|| format!("{} {}", method, path), str::to_string),
+        "parameters": parameters,
+        "responses": responses(method, &path, table_suffix),
+    });
+    operation["security"] = if path.starts_with("/admin") {
+        serde_json::json!([{ "adminKey": [] }])
+    } else if path == "/healthz" || path == "/status" {
+        serde_json::json!([])
+    } else {
+        serde_json::json!([{ "apiKey": [] }, {}])
+    };
+    if method == "GET" && table_suffix == Some("") {
+        operation["description"] = serde_json::json!(
+            "Query string keys other than the listed parameters filter rows by column, e.g. `dma_id=819`."
+        );
+    }
+    let body = match (method, table_suffix) {
+        ("POST", Some("")) => Some(serde_json::json!({
+            "oneOf": [schema_ref("Row"), schema_ref("Rows")],
+        })),
+        ("POST", Some("/upsert")) => Some(schema_ref("Row")),
+        ("PUT", Some("")) => Some(serde_json::json!({
+            "type": "object",
+            "properties": { "keys": schema_ref("Row"), "set": schema_ref("Row") },
+            "required": ["keys", "set"],
+        })),
+        _ => None,
+    };
+    if let Some(x) = body {
+        operation["requestBody"] =
+            serde_json::json!({ "required": true, "content": json_content(x) });
+    }
+    (method, path, operation)
+}
+
+// The document for the routes of `router`. `table_routes` are the prefixes of
+// the table routes, whose sub-routes share their operations.
+fn openapi_document(router: &Router, table_routes: &[&str]) -> serde_json::Value {
+    let mut operations = Vec::new();
+    for (method, pattern) in router.routes() {
+        let method = method.as_str();
+        let table = table_routes.iter().find_map(|prefix| {
+            TABLE_OPERATIONS
+                .iter()
+                .find(|(suffix, x, _)| *x == method && format!("{}{}", prefix, suffix) == pattern)
+        });
+        let summary = match table {
+            Some((_, _, x)) => Some(*x),
+            None => SUMMARIES
+                .iter()
+                .find(|(x, p, _)| *x == method && *p == pattern)
+                .map(|(_, _, x)| *x),
+        };
+        operations.push(operation(
+            method,
+            pattern,
+            summary,
+            table.map(|(x, _, _)| *x),
+        ));
+    }
+    let mut paths = serde_json::Map::new();
+    for (method, path, operation) in operations {
+        let item = paths.entry(path).or_insert_with(|| serde_json::json!({}));
+        item[method.to_ascii_lowercase()] = operation;
+    }
+    serde_json::json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "apiKey": { "type": "apiKey", "in": "header", "name": API_KEY_HEADER },
+                "adminKey": { "type": "apiKey", "in": "header", "name": ADMIN_KEY_HEADER },
+            },
+            "schemas": {
+                "Row": { "type": "object", "additionalProperties": true },
+                "Rows": { "type": "array", "items": schema_ref("Row") },
+                "JobPending": {
+                    "type": "object",
+                    "properties": {
+                        "job": { "type": "string" },
+                        "jobComplete": { "type": "boolean" },
+                    },
+                },
+                "InsertErrors": {
+                    "type": "object",
+                    "properties": {
+                        "num_rows": { "type": "integer" },
+                        "errors": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "row": { "type": "integer" },
+                                    "field": { "type": "string" },
+                                    "error": { "type": "string" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "Health": {
+                    "type": "object",
+                    "properties": { "status": { "type": "string", "enum": ["ok", "fail"] } },
+                    "additionalProperties": true,
+                },
+            },
+        },
+    })
+}
+
+pub fn handle_openapi_req(router: &Router, table_routes: &[&str]) -> Result<Response, Error> {
+    Ok(Response::from_status(StatusCode::OK)
+        .with_body_json(&openapi_document(router, table_routes))?)
+}
@@ -0,0 +1,348 @@
+use crate::gcp::QueryParameter;
+
+// Small SQL builder for the statements the handlers run against one table.
+// Identifiers are quoted and client values are only ever bound as named query
+// parameters; `filter` takes trusted SQL such as an API key's row filter.
+#[derive(Debug, Clone)]
+pub struct QueryBuilder {
+    table: String,
+    legacy: bool,
+    columns: Vec<String>,
+    system_time: Option<String>,
+    predicates: Vec<String>,
+    assignments: Vec<String>,
+    group_by: Vec<String>,
+    order_by: Vec<String>,
+    limit: Option<u64>,
+    offset: Option<u64>,
+    params: Vec<QueryParameter>,
+}
+
+// `name` as a standard SQL quoted identifier.
+pub fn quote_identifier(name: &str) -> String {
+    format!("`{}`", name.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+impl QueryBuilder {
+    // Standard SQL statements on `project.dataset.table`.
+    pub fn new(project: &str, dataset_table: &str) -> Self {
+        Self::with_table(
+            quote_identifier(&format!("{}.{}", project, dataset_table)),
+            false,
+        )
+    }
+
+    // Legacy SQL statements on `[project:dataset.table]`. Legacy SQL has no
+    // query parameters, so nothing may be bound.
+    pub fn legacy(project: &str, dataset_table: &str) -> Self {
+        Self::with_table(format!("[{}:{}]", project, dataset_table), true)
+    }
+
+    fn with_table(table: String, legacy: bool) -> Self {
+        Self {
+            table,
+            legacy,
+            columns: Vec::new(),
+            system_time: None,
+            predicates: Vec::new(),
+            assignments: Vec::new(),
+            group_by: Vec::new(),
+            order_by: Vec::new(),
+            limit: None,
+            offset: None,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.legacy
+    }
+
+    pub fn column(&self, name: &str) -> String {
+        if self.legacy {
+            format!("[{}]", name)
+        } else {
+            quote_identifier(name)
+        }
+    }
+
+    // Binds a named parameter and returns its placeholder, e.g. `@from`.
+    pub fn bind(&mut self, name: &str, kind: &str, value: impl ToString) -> String {
+        self.params.push(QueryParameter::new(name, kind, value));
+        format!("@{}", name)
+    }
+
+    // Binds an ARRAY parameter, e.g. for `column IN UNNEST(@ids)`.
+    pub fn bind_array<T: ToString>(&mut self, name: &str, kind: &str, values: &[T]) -> String {
+        self.params.push(QueryParameter::array(name, kind, values));
+        format!("@{}", name)
+    }
+
+    pub fn params(&self) -> &[QueryParameter] {
+        &self.params
+    }
+
+    // Columns of the SELECT list; `*` when none are given.
+    pub fn select(&mut self, columns: &[&str]) -> &mut Self {
+        self.columns = columns.iter().map(|x| self.column(x)).collect();
+        self
+    }
+
+    // Adds `expression AS alias` to the SELECT list, e.g. an aggregate.
+    pub fn select_as(&mut self, expression: &str, alias: &str) -> &mut Self {
+        let column = format!("{} AS {}", expression, self.column(alias));
+        self.columns.push(column);
+        self
+    }
+
+    pub fn group_by(&mut self, columns: &[&str]) -> &mut Self {
+        self.group_by = columns.iter().map(|x| self.column(x)).collect();
+        self
+    }
+
+    // Reads the table as of `timestamp`, an expression such as `@as_of`.
+    pub fn as_of(&mut self, timestamp: String) -> &mut Self {
+        self.system_time = Some(timestamp);
+        self
+    }
+
+    pub fn filter(&mut self, predicate: impl Into<String>) -> &mut Self {
+        self.predicates.push(predicate.into());
+        self
+    }
+
+    // `column = @column`, or `column IS NULL` without a value.
+    pub fn filter_eq(&mut self, column: &str, kind: &str, value: Option<&str>) -> &mut Self {
+        let predicate = match value {
+            Some(x) => format!("{} = {}", self.column(column), self.bind(column, kind, x)),
+            None => format!("{} IS NULL", self.column(column)),
+        };
+        self.filter(predicate)
+    }
+
+    // `column = @set_column` for UPDATE, or `column = NULL` without a value.
+    pub fn set(&mut self, column: &str, kind: &str, value: Option<&str>) -> &mut Self {
+        let assignment = match value {
+            Some(x) => {
+                let placeholder = self.bind(&format!("set_{}", column), kind, x);
+                format!("{} = {}", self.column(column), placeholder)
+            }
+            None => format!("{} = NULL", self.column(column)),
+        };
+        self.assignments.push(assignment);
+        self
+    }
+
+    pub fn order_by(&mut self, column: &str, descending: bool) -> &mut Self {
+        let direction = if descending { "DESC" } else { "ASC" };
+        self.order_by
+            .push(format!("{} {}", self.column(column), direction));
+        self
+    }
+
+    pub fn limit(&mut self, limit: u64) -> &mut Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(&mut self, offset: u64) -> &mut Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    fn where_clause(&self) -> String {
+        match self.predicates.len() {
+            0 => String::new(),
+            1 => format!(" WHERE {}", self.predicates[0]),
+            _ => format!(
+                " WHERE {}",
+                self.predicates
+                    .iter()
+                    .map(|x| format!("({})", x))
+                    .collect::<Vec<_>>()
+                    .join(" AND ")
+            ),
+        }
+    }
+
+    pub fn select_sql(&self) -> String {
+        let columns = if self.columns.is_empty() {
+            "*".to_string()
+        } else {
+            self.columns.join(", ")
+        };
+        let system_time = match &self.system_time {
+            Some(x) => format!(" FOR SYSTEM_TIME AS OF {}", x),
+            None => String::new(),
+        };
+        let mut sql = format!(
+            "SELECT {} FROM {}{}{}",
+            columns,
+            self.table,
+            system_time,
+            self.where_clause()
+        );
+        if !self.group_by.is_empty() {
+            sql.push_str(&format!(" GROUP BY {}", self.group_by.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            sql.push_str(&format!(" ORDER BY {}", self.order_by.join(", ")));
+        }
+        if let Some(x) = self.limit {
+            sql.push_str(&format!(" LIMIT {}", x));
+        }
+        if let Some(x) = self.offset {
+            sql.push_str(&format!(" OFFSET {}", x));
+        }
+        sql
+    }
+
+    pub fn delete_sql(&self) -> String {
+        format!("DELETE FROM {}{}", self.table, self.where_clause())
+    }
+
+    pub fn update_sql(&self) -> String {
+        format!(
+            "UPDATE {} SET {}{}",
+            self.table,
+            self.assignments.join(", "),
+            self.where_clause()
+        )
+    }
+
+    // Multi-row INSERT; `rows` holds each row's placeholders in column order.
+    pub fn insert_sql(&self, columns: &[&str], rows: &[Vec<String>]) -> String {
+        let values: Vec<String> = rows
+            .iter()
+            .map(|row| format!("({})", row.join(", ")))
+            .collect();
+        format!(
+            "INSERT INTO {} ({}) VALUES {}",
+            self.table,
+            columns
+                .iter()
+                .map(|x| self.column(x))
+                .collect::<Vec<_>>()
+                .join(", "),
+            values.join(", ")
+        )
+    }
+
+    // MERGE of one source row, given as (expression, column) pairs, keyed on
+    // `key_columns`: matching rows get the other columns, otherwise the row is
+    // inserted. Filters restrict the matched target rows (`T`).
+    pub fn merge_sql(&self, source: &[(String, &str)], key_columns: &[&str]) -> String {
+        let select: Vec<String> = source
+            .iter()
+            .map(|(expression, column)| format!("{} AS {}", expression, self.column(column)))
+            .collect();
+        let mut matches: Vec<String> = key_columns
+            .iter()
+            .map(|x| format!("T.{} = S.{}", self.column(x), self.column(x)))
+            .collect();
+        matches.extend(self.predicates.iter().cloned());
+        let columns: Vec<String> = source.iter().map(|(_, x)| self.column(x)).collect();
+        let updates: Vec<String> = source
+            .iter()
+            .filter(|(_, x)| !key_columns.contains(x))
+            .map(|(_, x)| format!("{} = S.{}", self.column(x), self.column(x)))
+            .collect();
+        let when_matched = if updates.is_empty() {
+            String::new()
+        } else {
+            format!(" WHEN MATCHED THEN UPDATE SET {}", updates.join(", "))
+        };
+        format!(
+            "MERGE {} T USING (SELECT {}) S ON {}{} WHEN NOT MATCHED THEN INSERT ({}) VALUES ({})",
+            self.table,
+            select.join(", "),
+            matches.join(" AND "),
+            when_matched,
+            columns.join(", "),
+            columns
+                .iter()
+                .map(|x| format!("S.{}", x))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param_names(query: &QueryBuilder) -> Vec<String> {
+        serde_json::to_value(query.params())
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| x["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn quotes_identifiers() {
+        assert_eq!(quote_identifier("a`b\\c"), "`a\\`b\\\\c`");
+        let query = QueryBuilder::new("p", "d.t");
+        assert_eq!(query.select_sql(), "SELECT * FROM `p.d.t`");
+        let query = QueryBuilder::legacy("p", "d.t");
+        assert_eq!(query.select_sql(), "SELECT * FROM [p:d.t]");
+    }
+
+    #[test]
+    fn filter_eq_binds_the_column_name() {
+        let mut query = QueryBuilder::new("p", "d.t");
+        query
+            .filter_eq("term", "STRING", Some("x"))
+            .filter_eq("rank", "INT64", None);
+        assert_eq!(
+            query.delete_sql(),
+            "DELETE FROM `p.d.t` WHERE (`term` = @term) AND (`rank` IS NULL)"
+        );
+        assert_eq!(param_names(&query), ["term"]);
+    }
+
+    #[test]
+    fn set_binds_apart_from_filters() {
+        let mut query = QueryBuilder::new("p", "d.t");
+        query
+            .set("term", "STRING", Some("new"))
+            .set("rank", "INT64", None)
+            .filter_eq("term", "STRING", Some("old"));
+        assert_eq!(
+            query.update_sql(),
+            "UPDATE `p.d.t` SET `term` = @set_term, `rank` = NULL WHERE `term` = @term"
+        );
+        assert_eq!(param_names(&query), ["set_term", "term"]);
+    }
+
+    #[test]
+    fn select_clauses_are_ordered() {
+        let mut query = QueryBuilder::new("p", "d.t");
+        query
+            .select(&["term"])
+            .select_as("COUNT(*)", "n")
+            .group_by(&["term"])
+            .order_by("n", true)
+            .limit(10)
+            .offset(20);
+        assert_eq!(
+            query.select_sql(),
+            "SELECT `term`, COUNT(*) AS `n` FROM `p.d.t` GROUP BY `term` ORDER BY `n` DESC LIMIT 10 OFFSET 20"
+        );
+    }
+
+    #[test]
+    fn merge_updates_non_key_columns() {
+        let query = QueryBuilder::new("p", "d.t");
+        let source = [("@id".to_string(), "id"), ("@term".to_string(), "term")];
+        assert_eq!(
+            query.merge_sql(&source, &["id"]),
+            "MERGE `p.d.t` T USING (SELECT @id AS `id`, @term AS `term`) S ON T.`id` = S.`id` \
+             WHEN MATCHED THEN UPDATE SET `term` = S.`term` \
+             WHEN NOT MATCHED THEN INSERT (`id`, `term`) VALUES (S.`id`, S.`term`)"
+        );
+    }
+}
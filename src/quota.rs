@@ -0,0 +1,243 @@
+use crate::auth::{authenticate, ApiKey};
+use crate::config::Config;
+use crate::error::AppError;
+use crate::gcp::load_config;
+use crate::router::{Handler, Params};
+use crate::stream::is_streamed;
+use fastly::erl::{CounterDuration, RateCounter};
+use fastly::{mime, Error, Request, Response};
+use log::error;
+use std::cell::RefCell;
+
+pub const QUOTA_RATE_COUNTER: &str = "quota";
+// Share of a budget after which clients are warned to back off.
+pub const SOFT_LIMIT_RATIO: f64 = 0.8;
+// Budgets are counted over ERL's sliding sixty second window.
+const WINDOW_SECS: u64 = 60;
+
+thread_local! {
+    // Budget state of the request `metered` is running.
+    static CURRENT: RefCell<Option<QuotaState>> = const { RefCell::new(None) };
+}
+
+// Per-minute budgets of an API key's tier. Bytes are tracked in KiB because
+// ERL counters are u32.
+#[derive(serde::Serialize, Debug)]
+pub struct QuotaState {
+    entry: String,
+    requests_limit: Option<u64>,
+    requests_used: u64,
+    bytes_limit: Option<u64>,
+    bytes_used: u64,
+}
+
+impl QuotaState {
+    fn remaining(limit: Option<u64>, used: u64) -> Option<u64> {
+        limit.map(|x| x.saturating_sub(used))
+    }
+
+    fn over(limit: Option<u64>, used: u64, ratio: f64) -> bool {
+        match limit {
+            Some(x) => used as f64 >= x as f64 * ratio,
+            None => false,
+        }
+    }
+
+    // Response for an exhausted budget: 429 for requests, 402 for bytes.
+    pub fn exceeded(&self) -> Option<Response> {
+        // requests_used already includes the current request.
+        let previous_requests = self.requests_used.saturating_sub(1);
+        let msg = format!("Quota exceeded for `{}`", self.entry);
+        let e = if Self::over(self.requests_limit, previous_requests, 1.0) {
+            AppError::TooManyRequests(msg)
+        } else if Self::over(self.bytes_limit, self.bytes_used, 1.0) {
+            AppError::PaymentRequired(msg)
+        } else {
+            return None;
+        };
+        error!("{}", e);
+        let mut resp = Response::from(e);
+        resp.set_header("Retry-After", WINDOW_SECS.to_string());
+        self.apply_headers(&mut resp);
+        Some(resp)
+    }
+
+    // Records bytes processed by BigQuery against the bytes budget.
+    fn record_bytes(&mut self, bytes: u64) {
+        if self.bytes_limit.is_none() || bytes == 0 {
+            return;
+        }
+        let kib = bytes.div_ceil(1024).min(u32::MAX as u64) as u32;
+        let counter = RateCounter::open(QUOTA_RATE_COUNTER);
+        if let Err(e) = counter.increment(&format!("{}:kib", self.entry), kib) {
+            error!("Quota counter error: {:?}", e);
+        }
+        self.bytes_used += bytes;
+    }
+
+    pub fn apply_headers(&self, resp: &mut Response) {
+        let remaining = Self::remaining(self.requests_limit, self.requests_used);
+        let bytes_remaining = Self::remaining(self.bytes_limit, self.bytes_used);
+        if let Some(x) = remaining {
+            resp.set_header("X-Quota-Remaining", x.to_string());
+        }
+        if let Some(x) = bytes_remaining {
+            resp.set_header("X-Quota-Bytes-Remaining", x.to_string());
+        }
+        // Budgets are counted over a sliding window, so there is no fixed
+        // reset time; clients get the window's length.
+        if remaining.is_some() || bytes_remaining.is_some() {
+            resp.set_header("X-Quota-Reset", WINDOW_SECS.to_string());
+        }
+        if let Some(x) = self.warning() {
+            resp.set_header("Warning", format!("199 - \"{}\"", x));
+            add_warning(resp, &x);
+        }
+    }
+
+    // Set once SOFT_LIMIT_RATIO of a budget is consumed.
+    fn warning(&self) -> Option<String> {
+        if !Self::over(self.requests_limit, self.requests_used, SOFT_LIMIT_RATIO)
+            && !Self::over(self.bytes_limit, self.bytes_used, SOFT_LIMIT_RATIO)
+        {
+            return None;
+        }
+        Some(format!(
+            "More than {}% of the quota for `{}` is consumed",
+            (SOFT_LIMIT_RATIO * 100.0) as u64,
+            self.entry
+        ))
+    }
+}
+
+// Adds `warning` to a JSON object body. Row arrays, other formats and streamed
+// bodies only get the Warning header.
+fn add_warning(resp: &mut Response, warning: &str) {
+    let is_json = resp
+        .get_content_type()
+        .is_some_and(|x| x.essence_str() == mime::APPLICATION_JSON.essence_str());
+    if !is_json || is_streamed() {
+        return;
+    }
+    let body = resp.take_body_bytes();
+    match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(serde_json::Value::Object(mut x)) => {
+            x.insert("warning".to_string(), serde_json::Value::from(warning));
+            resp.set_body(serde_json::Value::Object(x).to_string());
+        }
+        _ => resp.set_body(body),
+    }
+}
+
+// Current budget state of the key's tier, or None when the key has no tier
+// with budgets.
+pub fn quota_state(tomlfile: &Config, key: &ApiKey) -> Option<QuotaState> {
+    let tier = tomlfile.tiers.as_ref()?.get(key.tier.as_ref()?)?;
+    let counter = RateCounter::open(QUOTA_RATE_COUNTER);
+    let requests_used = counter
+        .lookup_count(
+            &format!("{}:requests", key.name),
+            CounterDuration::SixtySecs,
+        )
+        .unwrap_or(0) as u64;
+    let bytes_used = counter
+        .lookup_count(&format!("{}:kib", key.name), CounterDuration::SixtySecs)
+        .unwrap_or(0) as u64
+        * 1024;
+    Some(QuotaState {
+        entry: key.name.to_string(),
+        requests_limit: tier.requests_per_minute,
+        requests_used,
+        bytes_limit: tier.bytes_per_minute,
+        bytes_used,
+    })
+}
+
+// Routing middleware for the routes that query BigQuery: counts the request
+// against the caller's tier, answers 429 or 402 once a budget is spent, and adds
+// the quota headers to the handler's response. Handlers report the bytes their
+// queries processed with `record_bytes_processed`.
+pub fn metered(req: &mut Request, params: &Params, handler: Handler) -> Result<Response, Error> {
+    let tomlfile = load_config(req, params)?;
+    let api_key = authenticate(&tomlfile, req)?;
+    let quota = match check_quota(&tomlfile, api_key.as_ref()) {
+        Some(x) => x,
+        None => return handler(req, params),
+    };
+    if let Some(resp) = quota.exceeded() {
+        return Ok(resp);
+    }
+    CURRENT.with(|x| *x.borrow_mut() = Some(quota));
+    let result = handler(req, params);
+    let quota = CURRENT.with(|x| x.borrow_mut().take());
+    let mut resp = result?;
+    if let Some(q) = quota {
+        q.apply_headers(&mut resp);
+    }
+    Ok(resp)
+}
+
+// Records the `totalBytesProcessed` of a BigQuery response against the bytes
+// budget of the request being metered.
+pub fn record_bytes_processed(bqresp_json: &serde_json::Value) {
+    let bytes = bqresp_json["totalBytesProcessed"]
+        .as_str()
+        .unwrap_or("0")
+        .parse::<u64>()
+        .unwrap_or(0);
+    CURRENT.with(|x| {
+        if let Some(q) = x.borrow_mut().as_mut() {
+            q.record_bytes(bytes);
+        }
+    });
+}
+
+// Counts this request against the key's tier and returns the budget state.
+pub fn check_quota(tomlfile: &Config, api_key: Option<&ApiKey>) -> Option<QuotaState> {
+    let key = api_key?;
+    quota_state(tomlfile, key)?;
+    let counter = RateCounter::open(QUOTA_RATE_COUNTER);
+    if let Err(e) = counter.increment(&format!("{}:requests", key.name), 1) {
+        error!("Quota counter error: {:?}", e);
+    }
+    quota_state(tomlfile, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(requests_used: u64, bytes_used: u64) -> QuotaState {
+        QuotaState {
+            entry: "free".to_string(),
+            requests_limit: Some(10),
+            requests_used,
+            bytes_limit: Some(1000),
+            bytes_used,
+        }
+    }
+
+    #[test]
+    fn remaining_saturates() {
+        assert_eq!(QuotaState::remaining(Some(10), 3), Some(7));
+        assert_eq!(QuotaState::remaining(Some(10), 12), Some(0));
+        assert_eq!(QuotaState::remaining(None, 3), None);
+    }
+
+    #[test]
+    fn over_compares_against_the_share_of_the_limit() {
+        assert!(!QuotaState::over(Some(10), 7, SOFT_LIMIT_RATIO));
+        assert!(QuotaState::over(Some(10), 8, SOFT_LIMIT_RATIO));
+        assert!(!QuotaState::over(Some(10), 9, 1.0));
+        assert!(QuotaState::over(Some(10), 10, 1.0));
+        assert!(!QuotaState::over(None, u64::MAX, 1.0));
+    }
+
+    #[test]
+    fn warns_past_the_soft_limit_of_either_budget() {
+        assert_eq!(state(7, 799).warning(), None);
+        let warning = Some("More than 80% of the quota for `free` is consumed".to_string());
+        assert_eq!(state(8, 0).warning(), warning);
+        assert_eq!(state(0, 800).warning(), warning);
+    }
+}
@@ -0,0 +1,57 @@
+use crate::auth::authenticate;
+use crate::config::Config;
+use crate::error::AppError;
+use fastly::erl::{CounterDuration, RateCounter};
+use fastly::http::Method;
+use fastly::{Request, Response};
+use log::error;
+
+pub const RATE_LIMIT_RATE_COUNTER: &str = "rate_limit";
+// Budgets are counted over ERL's sliding sixty second window.
+const WINDOW_SECS: u64 = 60;
+
+// Per-minute request budgets from `[rate_limit]`, one for GET and HEAD and one
+// for POST and the other writes. Callers are counted by API key name, or by
+// client IP when they present none. `require_api_key` has already rejected
+// bad keys by the time this runs.
+fn client_id(tomlfile: &Config, req: &Request) -> String {
+    match authenticate(tomlfile, req) {
+        Ok(Some(x)) => format!("key:{}", x.name),
+        _ => match req.get_client_ip_addr() {
+            Some(x) => format!("ip:{}", x),
+            None => "ip:unknown".to_string(),
+        },
+    }
+}
+
+// Routing middleware: counts the request and returns a 429 with Retry-After
+// once the caller is over its budget for the method.
+pub fn check_rate_limit(tomlfile: &Config, req: &Request) -> Option<Response> {
+    let rate_limit = tomlfile.rate_limit.as_ref()?;
+    let (kind, limit) = if matches!(*req.get_method(), Method::GET | Method::HEAD) {
+        ("get", rate_limit.get_per_minute?)
+    } else {
+        ("post", rate_limit.post_per_minute?)
+    };
+    let entry = format!("{}:{}", client_id(tomlfile, req), kind);
+    let counter = RateCounter::open(RATE_LIMIT_RATE_COUNTER);
+    if let Err(e) = counter.increment(&entry, 1) {
+        error!("Rate limit counter error: {:?}", e);
+        return None;
+    }
+    let used = match counter.lookup_count(&entry, CounterDuration::SixtySecs) {
+        Ok(x) => x as u64,
+        Err(e) => {
+            error!("Rate limit counter error: {:?}", e);
+            return None;
+        }
+    };
+    if used <= limit {
+        return None;
+    }
+    let msg = format!("Rate limit exceeded for `{}`", entry);
+    error!("{}", msg);
+    let mut resp = Response::from(AppError::TooManyRequests(msg));
+    resp.set_header("Retry-After", WINDOW_SECS.to_string());
+    Some(resp)
+}
@@ -0,0 +1,75 @@
+use crate::gcp::random_uuid;
+use fastly::Request;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::cell::RefCell;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+// Every request gets an id: the client's X-Request-Id when it sent a usable
+// one, otherwise a new UUID. It prefixes every log line, labels the BigQuery
+// jobs the request runs as `request_id` and is echoed in the response, so a
+// failing query can be followed from the client through the logs to
+// BigQuery's audit logs.
+thread_local! {
+    static CURRENT: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+// Ids end up in log lines and job labels, so only short, plain ones are taken.
+fn usable(value: &str) -> bool {
+    !value.is_empty()
+        && value.len() <= MAX_REQUEST_ID_LEN
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+// Takes the request's id from its X-Request-Id or makes a new one.
+pub fn assign(req: &Request) {
+    let id = match req.get_header_str(REQUEST_ID_HEADER) {
+        Some(x) if usable(x) => x.to_string(),
+        _ => random_uuid(),
+    };
+    CURRENT.with(|x| *x.borrow_mut() = id);
+}
+
+pub fn current() -> String {
+    CURRENT.with(|x| x.borrow().clone())
+}
+
+// The Fastly logger, with the request id in front of each line.
+struct RequestLogger(log_fastly::Logger);
+
+impl Log for RequestLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.log(
+            &Record::builder()
+                .args(format_args!("[{}] {}", current(), record.args()))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.0.flush()
+    }
+}
+
+// Sends log lines of at most `level` to `endpoint`, like
+// `log_fastly::init_simple`.
+pub fn init_logging(endpoint: &str, level: LevelFilter) {
+    let logger = log_fastly::Logger::builder()
+        .default_endpoint(endpoint)
+        .max_level(level)
+        .build()
+        .expect("log_fastly logger build failed");
+    log::set_boxed_logger(Box::new(RequestLogger(logger))).expect("logger already set");
+    log::set_max_level(level);
+}
@@ -0,0 +1,251 @@
+use crate::error::AppError;
+use fastly::http::{header, Method};
+use fastly::{Error, Request, Response};
+
+// Method and path dispatch for `main`. A pattern is `/`-separated segments:
+// literals, `:name` parameters matching one non-empty segment, and a final
+// `*name` matching the rest of the path, which may be empty, so `/files/*path`
+// also matches `/files`. Routes are tried
+// in the order they were added. HEAD requests run GET routes. A path only
+// routes of other methods match gets 405, with their methods in Allow.
+pub type Handler = fn(&mut Request, &Params) -> Result<Response, Error>;
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+}
+
+// Path parameters of the matched route, and its pattern.
+#[derive(Default, Debug)]
+pub struct Params {
+    route: String,
+    values: Vec<(String, String)>,
+}
+
+impl Params {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(x, _)| x == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern
+        .trim_start_matches('/')
+        .split('/')
+        .map(|x| {
+            if let Some(name) = x.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = x.strip_prefix('*') {
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Literal(x.to_string())
+            }
+        })
+        .collect()
+}
+
+impl Route {
+    fn matches(&self, path: &str) -> Option<Params> {
+        let mut parts = path.trim_start_matches('/').split('/');
+        let mut params = Vec::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(x) => {
+                    if parts.next()? != x {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let value = parts.next().filter(|x| !x.is_empty())?;
+                    params.push((name.clone(), value.to_string()));
+                }
+                Segment::Rest(name) => {
+                    let rest: Vec<&str> = parts.by_ref().collect();
+                    params.push((name.clone(), rest.join("/")));
+                }
+            }
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Params {
+            route: self.pattern.clone(),
+            values: params,
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(mut self, method: Method, pattern: &str, handler: Handler) -> Self {
+        self.routes.push(Route {
+            method,
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            handler,
+        });
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: Handler) -> Self {
+        self.route(Method::GET, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: Handler) -> Self {
+        self.route(Method::POST, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: Handler) -> Self {
+        self.route(Method::PUT, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: Handler) -> Self {
+        self.route(Method::PATCH, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: Handler) -> Self {
+        self.route(Method::DELETE, pattern, handler)
+    }
+
+    // The method and pattern of every route, in order. The OpenAPI document is
+    // built from these.
+    pub fn routes(&self) -> impl Iterator<Item = (&Method, &str)> {
+        self.routes.iter().map(|x| (&x.method, x.pattern.as_str()))
+    }
+
+    // The first route for `method` matching `path`, or the methods of the
+    // routes that match it otherwise, for Allow.
+    fn find(&self, method: &Method, path: &str) -> Result<(Handler, Params), Vec<&str>> {
+        let method = match *method {
+            Method::HEAD => &Method::GET,
+            ref x => x,
+        };
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = route.matches(path) {
+                if route.method == *method {
+                    return Ok((route.handler, params));
+                }
+                if !allowed.contains(&route.method.as_str()) {
+                    allowed.push(route.method.as_str());
+                }
+                if route.method == Method::GET && !allowed.contains(&"HEAD") {
+                    allowed.push("HEAD");
+                }
+            }
+        }
+        Err(allowed)
+    }
+
+    // Runs the handler of the first route matching the request. A path no
+    // route matches gets 404, one only other methods' routes match 405.
+    pub fn dispatch(&self, req: &mut Request) -> Result<Response, Error> {
+        let allowed = match self.find(req.get_method(), req.get_path()) {
+            Ok((handler, params)) => return handler(req, &params),
+            Err(x) => x,
+        };
+        if allowed.is_empty() {
+            let msg = format!("No route for {}", req.get_path());
+            return Ok(Response::from(AppError::NotFound(msg)));
+        }
+        let msg = format!("{} is not allowed on {}", req.get_method(), req.get_path());
+        Ok(Response::from(AppError::MethodNotAllowed(msg))
+            .with_header(header::ALLOW, allowed.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(_: &mut Request, _: &Params) -> Result<Response, Error> {
+        unreachable!()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/t/:name", handler)
+            .post("/t/:name", handler)
+            .delete("/t/:name", handler)
+            .post("/t/:name/upsert", handler)
+            .get("/bq/:project/:dataset/:table/schema", handler)
+            .get("/files/*path", handler)
+    }
+
+    fn find(method: Method, path: &str) -> Result<Params, Vec<String>> {
+        match router().find(&method, path) {
+            Ok((_, params)) => Ok(params),
+            Err(x) => Err(x.into_iter().map(str::to_string).collect()),
+        }
+    }
+
+    #[test]
+    fn matches_params() {
+        let params = find(Method::GET, "/bq/p/d/t/schema").unwrap();
+        assert_eq!(params.route(), "/bq/:project/:dataset/:table/schema");
+        assert_eq!(params.get("project"), Some("p"));
+        assert_eq!(params.get("dataset"), Some("d"));
+        assert_eq!(params.get("table"), Some("t"));
+        assert_eq!(params.get("other"), None);
+    }
+
+    #[test]
+    fn params_must_be_non_empty_and_paths_complete() {
+        for path in ["/t/", "/t", "/t/a/schema", "/x/a"] {
+            assert_eq!(find(Method::GET, path).unwrap_err(), Vec::<String>::new());
+        }
+    }
+
+    #[test]
+    fn rest_matches_remaining_segments() {
+        let params = find(Method::GET, "/files/a/b").unwrap();
+        assert_eq!(params.get("path"), Some("a/b"));
+        let params = find(Method::GET, "/files").unwrap();
+        assert_eq!(params.get("path"), Some(""));
+    }
+
+    #[test]
+    fn picks_the_route_for_the_method() {
+        let params = find(Method::POST, "/t/a/upsert").unwrap();
+        assert_eq!(params.route(), "/t/:name/upsert");
+        assert_eq!(params.get("name"), Some("a"));
+    }
+
+    #[test]
+    fn head_runs_get_routes() {
+        let params = find(Method::HEAD, "/t/a").unwrap();
+        assert_eq!(params.route(), "/t/:name");
+    }
+
+    #[test]
+    fn other_methods_are_listed_for_allow() {
+        assert_eq!(
+            find(Method::PUT, "/t/a").unwrap_err(),
+            ["GET", "HEAD", "POST", "DELETE"]
+        );
+        assert_eq!(find(Method::GET, "/t/a/upsert").unwrap_err(), ["POST"]);
+    }
+}
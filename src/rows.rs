@@ -0,0 +1,529 @@
+use crate::csv;
+use crate::geography::wkt_to_geojson;
+use anyhow::anyhow;
+use fastly::http::StatusCode;
+use fastly::{Body, Error, Response};
+use serde::ser::{Error as SerError, SerializeMap, SerializeSeq, SerializeStruct};
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::Write;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+// Accept profile selecting the compact (columnar, dictionary encoded) layout.
+pub const COMPACT_PROFILE: &str = "compact";
+pub const CSV_CONTENT_TYPE: &str = "text/csv";
+pub const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// Layouts rows can be rendered in, negotiated from the Accept header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Compact,
+    Csv,
+    Ndjson,
+}
+
+impl OutputFormat {
+    // The first media range naming CSV or NDJSON wins; JSON (plain or compact)
+    // is the default.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        for range in accept.unwrap_or("").split(',') {
+            let media_type = range.split(';').next().unwrap_or("").trim();
+            if media_type.eq_ignore_ascii_case(CSV_CONTENT_TYPE) {
+                return OutputFormat::Csv;
+            }
+            if media_type.eq_ignore_ascii_case(NDJSON_CONTENT_TYPE)
+                || media_type.eq_ignore_ascii_case("application/ndjson")
+            {
+                return OutputFormat::Ndjson;
+            }
+        }
+        if wants_compact(accept) {
+            OutputFormat::Compact
+        } else {
+            OutputFormat::Json
+        }
+    }
+
+    pub fn content_type(&self) -> String {
+        match self {
+            OutputFormat::Json => "application/json".to_string(),
+            OutputFormat::Compact => format!("application/json; profile=\"{}\"", COMPACT_PROFILE),
+            OutputFormat::Csv => format!("{}; charset=utf-8; header=present", CSV_CONTENT_TYPE),
+            OutputFormat::Ndjson => NDJSON_CONTENT_TYPE.to_string(),
+        }
+    }
+}
+
+// Serializes BigQuery's tabledata layout (`schema.fields` + `rows[].f[].v`) directly
+// as a JSON array of objects, so rows can be written into the response body
+// without building an intermediate Vec<serde_json::Value>.
+pub struct BqRows<'a> {
+    pub fields: &'a [serde_json::Value],
+    pub rows: &'a [serde_json::Value],
+}
+
+struct BqRow<'a> {
+    fields: &'a [serde_json::Value],
+    row: &'a serde_json::Value,
+}
+
+// Columnar layout: `{"columns": [..], "dictionaries": {..}, "data": {..}}`.
+// Scalar STRING columns are written as indices into a per-column dictionary of
+// distinct values (null stays null), other columns as plain value arrays.
+pub struct BqColumnar<'a> {
+    pub fields: &'a [serde_json::Value],
+    pub rows: &'a [serde_json::Value],
+}
+
+// A decoded cell. RECORD values keep their subfields in schema order.
+enum Cell<'a> {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(Cow<'a, str>),
+    Json(serde_json::Value),
+    Array(Vec<Cell<'a>>),
+    Record(Vec<(&'a str, Cell<'a>)>),
+}
+
+impl<'a> Serialize for Cell<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Cell::Null => serializer.serialize_none(),
+            Cell::Integer(x) => serializer.serialize_i64(*x),
+            Cell::Float(x) => serializer.serialize_f64(*x),
+            Cell::Boolean(x) => serializer.serialize_bool(*x),
+            Cell::String(x) => serializer.serialize_str(x),
+            Cell::Json(x) => x.serialize(serializer),
+            Cell::Array(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Cell::Record(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (name, cell) in entries {
+                    map.serialize_entry(name, cell)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+fn decode_cell<'a>(
+    field: &'a serde_json::Value,
+    row: &'a serde_json::Value,
+    i: usize,
+) -> Result<Cell<'a>, String> {
+    decode_value(field, &row["f"][i]["v"])
+}
+
+// REPEATED values arrive as `[{"v": ..}, ..]`, RECORD values as
+// `{"f": [{"v": ..}, ..]}` following the field's `fields`.
+fn decode_value<'a>(
+    field: &'a serde_json::Value,
+    value: &'a serde_json::Value,
+) -> Result<Cell<'a>, String> {
+    if field["mode"] == "REPEATED" {
+        let items = match value.as_array() {
+            Some(x) => x,
+            None => return Ok(Cell::Array(Vec::new())),
+        };
+        let cells = items
+            .iter()
+            .map(|item| decode_scalar(field, &item["v"]))
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Cell::Array(cells));
+    }
+    decode_scalar(field, value)
+}
+
+fn decode_scalar<'a>(
+    field: &'a serde_json::Value,
+    value: &'a serde_json::Value,
+) -> Result<Cell<'a>, String> {
+    if value.is_null() {
+        return Ok(Cell::Null);
+    }
+    match field["type"].as_str() {
+        Some("RECORD") | Some("STRUCT") => {
+            let subfields: &[serde_json::Value] = match field["fields"].as_array() {
+                Some(x) => x,
+                None => &[],
+            };
+            let mut entries = Vec::with_capacity(subfields.len());
+            for (i, subfield) in subfields.iter().enumerate() {
+                let name = subfield["name"].as_str().unwrap_or("");
+                entries.push((name, decode_value(subfield, &value["f"][i]["v"])?));
+            }
+            Ok(Cell::Record(entries))
+        }
+        Some("INTEGER") | Some("INT64") => {
+            let number = value
+                .as_str()
+                .unwrap_or("0")
+                .parse::<i64>()
+                .map_err(|e| e.to_string())?;
+            Ok(Cell::Integer(number))
+        }
+        Some("FLOAT") | Some("FLOAT64") => {
+            let number = value
+                .as_str()
+                .unwrap_or("0")
+                .parse::<f64>()
+                .map_err(|e| e.to_string())?;
+            Ok(Cell::Float(number))
+        }
+        Some("BOOLEAN") | Some("BOOL") => Ok(Cell::Boolean(value.as_str() == Some("true"))),
+        Some("TIMESTAMP") => {
+            // Epoch seconds as a float string, e.g. "1.6832448E9".
+            let seconds = value
+                .as_str()
+                .unwrap_or("0")
+                .parse::<f64>()
+                .map_err(|e| e.to_string())?;
+            let micros = (seconds * 1_000_000.0).round() as i128;
+            let timestamp = OffsetDateTime::from_unix_timestamp_nanos(micros * 1_000)
+                .map_err(|e| e.to_string())?
+                .format(&Rfc3339)
+                .map_err(|e| e.to_string())?;
+            Ok(Cell::String(Cow::Owned(timestamp)))
+        }
+        Some("JSON") => {
+            let json = serde_json::from_str(value.as_str().unwrap_or("null"))
+                .map_err(|e| e.to_string())?;
+            Ok(Cell::Json(json))
+        }
+        Some(GEOJSON_TYPE) => Ok(Cell::Json(wkt_to_geojson(value.as_str().unwrap_or(""))?)),
+        // DATE, DATETIME and TIME are already ISO 8601, BYTES already base64 and
+        // NUMERIC/BIGNUMERIC stay strings so no precision is lost.
+        _ => {
+            let text = value.as_str().unwrap_or("");
+            match field["name"].as_str() {
+                Some("update") => {
+                    let decoded = urlencoding::decode(text).map_err(|e| e.to_string())?;
+                    Ok(Cell::String(Cow::Owned(decoded)))
+                }
+                _ => Ok(Cell::String(Cow::Borrowed(text))),
+            }
+        }
+    }
+}
+
+// Stand-in type for GEOGRAPHY fields rendered as GeoJSON objects rather than
+// BigQuery's WKT text.
+const GEOJSON_TYPE: &str = "GEOJSON";
+
+// `fields` with GEOGRAPHY fields, including those inside RECORDs, switched to
+// GeoJSON output.
+pub fn geojson_fields(fields: &[serde_json::Value]) -> Vec<serde_json::Value> {
+    fields
+        .iter()
+        .map(|field| {
+            let mut field = field.clone();
+            if field["type"] == "GEOGRAPHY" {
+                field["type"] = serde_json::Value::from(GEOJSON_TYPE);
+            }
+            if let Some(subfields) = field["fields"].as_array() {
+                field["fields"] = serde_json::Value::from(geojson_fields(subfields));
+            }
+            field
+        })
+        .collect()
+}
+
+// CSV text of a cell: empty for NULL, nested values as JSON.
+fn csv_cell(cell: &Cell) -> Result<String, String> {
+    match cell {
+        Cell::Null => Ok(String::new()),
+        Cell::String(x) => Ok(x.to_string()),
+        Cell::Integer(x) => Ok(x.to_string()),
+        Cell::Float(x) => Ok(x.to_string()),
+        Cell::Boolean(x) => Ok(x.to_string()),
+        _ => serde_json::to_string(cell).map_err(|e| e.to_string()),
+    }
+}
+
+// Writes a header row with the column names, then one record per row.
+pub fn write_csv<W: Write>(
+    out: &mut W,
+    fields: &[serde_json::Value],
+    rows: &[serde_json::Value],
+) -> Result<(), String> {
+    write_csv_header(out, fields)?;
+    write_csv_rows(out, fields, rows)
+}
+
+fn write_csv_header<W: Write>(out: &mut W, fields: &[serde_json::Value]) -> Result<(), String> {
+    let names: Vec<&str> = fields
+        .iter()
+        .map(|field| field["name"].as_str().unwrap_or(""))
+        .collect();
+    let mut record = String::new();
+    csv::write_record(&mut record, &names);
+    out.write_all(record.as_bytes()).map_err(|e| e.to_string())
+}
+
+fn write_csv_rows<W: Write>(
+    out: &mut W,
+    fields: &[serde_json::Value],
+    rows: &[serde_json::Value],
+) -> Result<(), String> {
+    let mut record = String::new();
+    for row in rows {
+        let cells = fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| decode_cell(field, row, i).and_then(|cell| csv_cell(&cell)))
+            .collect::<Result<Vec<_>, _>>()?;
+        record.clear();
+        csv::write_record(&mut record, &cells);
+        out.write_all(record.as_bytes())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Writes one JSON object per line.
+pub fn write_ndjson<W: Write>(
+    out: &mut W,
+    fields: &[serde_json::Value],
+    rows: &[serde_json::Value],
+) -> Result<(), String> {
+    for row in rows {
+        serde_json::to_writer(&mut *out, &BqRow { fields, row }).map_err(|e| e.to_string())?;
+        out.write_all(b"\n").map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+// Writes the rows of a result a page at a time, as pages of
+// jobs.getQueryResults arrive: JSON stays one array across every page and CSV
+// gets a single header row. The compact layout needs all rows up front and
+// cannot be written this way.
+pub struct RowStream {
+    format: OutputFormat,
+    started: bool,
+    rows_written: u64,
+}
+
+impl RowStream {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            started: false,
+            rows_written: 0,
+        }
+    }
+
+    pub fn rows_written(&self) -> u64 {
+        self.rows_written
+    }
+
+    pub fn write_page<W: Write>(
+        &mut self,
+        out: &mut W,
+        fields: &[serde_json::Value],
+        rows: &[serde_json::Value],
+    ) -> Result<(), String> {
+        match self.format {
+            OutputFormat::Json | OutputFormat::Compact => {
+                for row in rows {
+                    let separator: &[u8] = if self.rows_written == 0 { b"[" } else { b"," };
+                    out.write_all(separator).map_err(|e| e.to_string())?;
+                    serde_json::to_writer(&mut *out, &BqRow { fields, row })
+                        .map_err(|e| e.to_string())?;
+                    self.rows_written += 1;
+                }
+            }
+            OutputFormat::Csv => {
+                if !self.started {
+                    write_csv_header(out, fields)?;
+                }
+                write_csv_rows(out, fields, rows)?;
+                self.rows_written += rows.len() as u64;
+            }
+            OutputFormat::Ndjson => {
+                write_ndjson(out, fields, rows)?;
+                self.rows_written += rows.len() as u64;
+            }
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    // Closes the JSON array; the other formats need no trailer.
+    pub fn finish<W: Write>(&mut self, out: &mut W) -> Result<(), String> {
+        if !matches!(self.format, OutputFormat::Json | OutputFormat::Compact) {
+            return Ok(());
+        }
+        let trailer: &[u8] = if self.rows_written == 0 { b"[]" } else { b"]" };
+        out.write_all(trailer).map_err(|e| e.to_string())
+    }
+}
+
+// CSV text of a JSON value: empty for null, strings as they are, anything
+// else as JSON.
+fn csv_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(x) => x.clone(),
+        x => x.to_string(),
+    }
+}
+
+// Writes `records`, JSON objects such as the entries of a listing, as NDJSON
+// or as CSV with a header row of every key the objects hold. JSON formats get
+// the records as one array.
+pub fn write_records<W: Write>(
+    out: &mut W,
+    format: OutputFormat,
+    records: &[serde_json::Value],
+) -> Result<(), String> {
+    match format {
+        OutputFormat::Json | OutputFormat::Compact => {
+            serde_json::to_writer(&mut *out, records).map_err(|e| e.to_string())
+        }
+        OutputFormat::Ndjson => {
+            for record in records {
+                serde_json::to_writer(&mut *out, record).map_err(|e| e.to_string())?;
+                out.write_all(b"\n").map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }
+        OutputFormat::Csv => {
+            let mut names: Vec<&str> = Vec::new();
+            for record in records {
+                for name in record.as_object().into_iter().flat_map(|x| x.keys()) {
+                    if !names.contains(&name.as_str()) {
+                        names.push(name);
+                    }
+                }
+            }
+            let mut line = String::new();
+            csv::write_record(&mut line, &names);
+            for record in records {
+                let cells: Vec<String> =
+                    names.iter().map(|name| csv_value(&record[*name])).collect();
+                csv::write_record(&mut line, &cells);
+            }
+            out.write_all(line.as_bytes()).map_err(|e| e.to_string())
+        }
+    }
+}
+
+// 200 with `body` rendered in `format`, labelled with its Content-Type. The
+// body depends on the Accept header, so caches must vary on it.
+pub fn format_response(format: OutputFormat, body: Body) -> Response {
+    Response::from_status(StatusCode::OK)
+        .with_header("Content-Type", format.content_type())
+        .with_header("Vary", "Accept")
+        .with_body(body)
+}
+
+// Response of a JSON route that lists records, in the format negotiated from
+// `accept`: plain JSON gets all of `envelope`, NDJSON and CSV only its
+// `records`. The compact profile only applies to query rows.
+pub fn records_response(
+    accept: Option<&str>,
+    envelope: &serde_json::Value,
+    records: &[serde_json::Value],
+) -> Result<Response, Error> {
+    let format = match OutputFormat::negotiate(accept) {
+        OutputFormat::Compact => OutputFormat::Json,
+        x => x,
+    };
+    let mut body = Body::new();
+    match format {
+        OutputFormat::Json | OutputFormat::Compact => serde_json::to_writer(&mut body, envelope)?,
+        x => write_records(&mut body, x, records).map_err(|e| anyhow!(e))?,
+    }
+    Ok(format_response(format, body))
+}
+
+// true when the Accept header asks for the compact profile, e.g.
+// `Accept: application/json; profile="compact"`.
+pub fn wants_compact(accept: Option<&str>) -> bool {
+    accept
+        .unwrap_or("")
+        .split(';')
+        .filter_map(|param| param.trim().strip_prefix("profile="))
+        .any(|profile| profile.trim_matches('"') == COMPACT_PROFILE)
+}
+
+impl<'a> Serialize for BqRows<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.rows.len()))?;
+        for row in self.rows {
+            seq.serialize_element(&BqRow {
+                fields: self.fields,
+                row,
+            })?;
+        }
+        seq.end()
+    }
+}
+
+impl<'a> Serialize for BqRow<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.fields.len()))?;
+        for (i, field) in self.fields.iter().enumerate() {
+            let name = field["name"].as_str().unwrap_or("");
+            let cell = decode_cell(field, self.row, i).map_err(S::Error::custom)?;
+            map.serialize_entry(name, &cell)?;
+        }
+        map.end()
+    }
+}
+
+impl<'a> Serialize for BqColumnar<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names: Vec<&str> = self
+            .fields
+            .iter()
+            .map(|field| field["name"].as_str().unwrap_or(""))
+            .collect();
+        let mut dictionaries: HashMap<&str, Vec<Cow<str>>> = HashMap::new();
+        let mut data: HashMap<&str, Vec<serde_json::Value>> = HashMap::new();
+        for (i, field) in self.fields.iter().enumerate() {
+            let mut column = Vec::with_capacity(self.rows.len());
+            if field["type"] == "STRING" && field["mode"] != "REPEATED" {
+                let mut dictionary: Vec<Cow<str>> = Vec::new();
+                let mut index: HashMap<Cow<str>, usize> = HashMap::new();
+                for row in self.rows {
+                    let value = match decode_cell(field, row, i).map_err(S::Error::custom)? {
+                        Cell::String(x) => x,
+                        _ => {
+                            column.push(serde_json::Value::Null);
+                            continue;
+                        }
+                    };
+                    let position = *index.entry(value.clone()).or_insert_with(|| {
+                        dictionary.push(value);
+                        dictionary.len() - 1
+                    });
+                    column.push(serde_json::Value::from(position));
+                }
+                dictionaries.insert(names[i], dictionary);
+            } else {
+                for row in self.rows {
+                    let cell = decode_cell(field, row, i).map_err(S::Error::custom)?;
+                    column.push(serde_json::to_value(&cell).map_err(S::Error::custom)?);
+                }
+            }
+            data.insert(names[i], column);
+        }
+        let mut state = serializer.serialize_struct("BqColumnar", 3)?;
+        state.serialize_field("columns", &names)?;
+        state.serialize_field("dictionaries", &dictionaries)?;
+        state.serialize_field("data", &data)?;
+        state.end()
+    }
+}
@@ -0,0 +1,77 @@
+use crate::config::{Config, SchemaFieldConfiguration};
+use crate::gcp::handle_bq_table_get_req;
+use anyhow::anyhow;
+use fastly::kv_store::{KVStore, KVStoreError};
+use fastly::Error;
+use log::error;
+use std::time::Duration;
+
+// How long a fetched schema is cached when `schema_cache_secs` is not set.
+const DEFAULT_SCHEMA_CACHE_SECS: u64 = 300;
+
+// With `[bigquery] fetch_schema = true` the columns inserts are validated
+// against come from the table itself, through tables.get. The fields are
+// cached in the `schema_cache` KV Store, so a schema change reaches the
+// service within `schema_cache_secs`.
+fn cache_key(tomlfile: &Config) -> String {
+    format!(
+        "schema/{}.{}",
+        tomlfile.bigquery.projectid, tomlfile.bigquery.dataset_tableid
+    )
+}
+
+fn open_cache(tomlfile: &Config) -> Option<KVStore> {
+    let name = tomlfile.bigquery.schema_cache.as_ref()?;
+    match KVStore::open(name) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("KV Store `{}` open error: {}", name, e);
+            None
+        }
+    }
+}
+
+fn fetch_schema(tomlfile: &Config) -> Result<String, Error> {
+    let table_json = handle_bq_table_get_req(tomlfile)?;
+    let fields = &table_json["schema"]["fields"];
+    if !fields.is_array() {
+        return Err(anyhow!(
+            "Table {} has no schema",
+            tomlfile.bigquery.dataset_tableid
+        ));
+    }
+    Ok(fields.to_string())
+}
+
+// The table's top-level schema fields, from the KV cache when it holds them.
+pub fn fetched_schema(tomlfile: &Config) -> Result<Vec<SchemaFieldConfiguration>, Error> {
+    let key = cache_key(tomlfile);
+    let store = open_cache(tomlfile);
+    if let Some(store) = &store {
+        match store.lookup(&key) {
+            Ok(mut x) => {
+                if let Ok(fields) = serde_json::from_slice(&x.take_body_bytes()) {
+                    return Ok(fields);
+                }
+            }
+            Err(KVStoreError::ItemNotFound) => {}
+            Err(e) => error!("Schema cache lookup error: {}", e),
+        }
+    }
+    let body = fetch_schema(tomlfile)?;
+    let fields = serde_json::from_str(&body)?;
+    if let Some(store) = store {
+        let ttl = tomlfile
+            .bigquery
+            .schema_cache_secs
+            .unwrap_or(DEFAULT_SCHEMA_CACHE_SECS);
+        if let Err(e) = store
+            .build_insert()
+            .time_to_live(Duration::from_secs(ttl))
+            .execute(&key, body)
+        {
+            error!("Schema cache insert error: {}", e);
+        }
+    }
+    Ok(fields)
+}
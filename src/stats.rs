@@ -0,0 +1,116 @@
+use crate::config::Config;
+use crate::error::AppError;
+use crate::request_id;
+use fastly::erl::{CounterDuration, RateCounter};
+use fastly::kv_store::{KVStore, KVStoreError};
+use log::error;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+// Operational state for GET /admin/status: cache hits and misses counted in
+// the `stats` ERL rate counter and read back over its sixty second window, and
+// the last RECENT_ERRORS server errors, newest first, in the `[admin]
+// kv_store`. The error list is rewritten on every 5xx without a generation
+// check, so concurrent errors may drop one another; it is a sample for
+// operators, not an audit log.
+pub const STATS_RATE_COUNTER: &str = "stats";
+const RECENT_ERRORS: usize = 20;
+const RECENT_ERRORS_KEY: &str = "status/recent_errors";
+
+pub const EDGE_CACHE: &str = "edge_cache";
+pub const TOKEN_CACHE: &str = "token_cache";
+
+fn count(cache: &str, outcome: &str) {
+    let counter = RateCounter::open(STATS_RATE_COUNTER);
+    if let Err(e) = counter.increment(&format!("{}:{}", cache, outcome), 1) {
+        error!("Stats counter error: {:?}", e);
+    }
+}
+
+pub fn cache_hit(cache: &str) {
+    count(cache, "hit");
+}
+
+pub fn cache_miss(cache: &str) {
+    count(cache, "miss");
+}
+
+// Hits, misses and the hit rate of `cache` over the last minute; the rate is
+// null when nothing was looked up.
+pub fn cache_stats(cache: &str) -> serde_json::Value {
+    let counter = RateCounter::open(STATS_RATE_COUNTER);
+    let lookup = |outcome: &str| {
+        let entry = format!("{}:{}", cache, outcome);
+        match counter.lookup_count(&entry, CounterDuration::SixtySecs) {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Stats counter error: {:?}", e);
+                0
+            }
+        }
+    };
+    let (hits, misses) = (lookup("hit"), lookup("miss"));
+    let lookups = hits + misses;
+    serde_json::json!({
+        "hits": hits,
+        "misses": misses,
+        "hit_rate": if lookups == 0 { None } else { Some(hits as f64 / lookups as f64) },
+    })
+}
+
+fn open_store(tomlfile: &Config) -> Option<KVStore> {
+    let name = tomlfile.admin.as_ref()?.kv_store.as_ref()?;
+    match KVStore::open(name) {
+        Ok(Some(x)) => Some(x),
+        Ok(None) => {
+            error!("KV Store `{}` is not linked to this service", name);
+            None
+        }
+        Err(e) => {
+            error!("KV Store `{}` open error: {}", name, e);
+            None
+        }
+    }
+}
+
+fn read_errors(store: &KVStore) -> Vec<serde_json::Value> {
+    match store.lookup(RECENT_ERRORS_KEY) {
+        Ok(mut x) => serde_json::from_slice(&x.take_body_bytes()).unwrap_or_default(),
+        Err(KVStoreError::ItemNotFound) => Vec::new(),
+        Err(e) => {
+            error!("Recent errors lookup error: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+// Adds a server error to the recent errors; client errors are not kept.
+pub fn record_error(e: &AppError) {
+    if !e.status().is_server_error() {
+        return;
+    }
+    let store = match open_store(&Config::load()) {
+        Some(x) => x,
+        None => return,
+    };
+    let mut errors = read_errors(&store);
+    errors.insert(
+        0,
+        serde_json::json!({
+            "time": OffsetDateTime::now_utc().format(&Rfc3339).ok(),
+            "request_id": request_id::current(),
+            "status": e.status().as_u16(),
+            "message": e.to_string(),
+        }),
+    );
+    errors.truncate(RECENT_ERRORS);
+    let body = serde_json::Value::from(errors).to_string();
+    if let Err(e) = store.insert(RECENT_ERRORS_KEY, body) {
+        error!("Recent errors insert error: {}", e);
+    }
+}
+
+// The recent server errors, or None without an `[admin] kv_store`.
+pub fn recent_errors(tomlfile: &Config) -> Option<Vec<serde_json::Value>> {
+    Some(read_errors(&open_store(tomlfile)?))
+}
@@ -0,0 +1,410 @@
+use crate::config::Config;
+use crate::storage_write::{
+    grpc_frame, grpc_status, put_bytes, put_uint, read_fields, read_varint, storage_backend,
+    WireValue, STORAGE_WRITE_HOST, UNIX_EPOCH_JULIAN_DAY,
+};
+use crate::token::bigquery_access_token;
+use anyhow::anyhow;
+use fastly::{Error, Request, Response};
+use log::{debug, error};
+use std::convert::TryInto;
+use std::io::{ErrorKind, Read, Write};
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+
+// BigQuery Storage Read API over gRPC. A read session with a single stream is
+// opened over a table, and the Avro row blocks of that stream are decoded and
+// written out one block at a time, so a large result set is never held as one
+// JSON value.
+const CREATE_READ_SESSION_PATH: &str =
+    "/google.cloud.bigquery.storage.v1.BigQueryRead/CreateReadSession";
+const READ_ROWS_PATH: &str = "/google.cloud.bigquery.storage.v1.BigQueryRead/ReadRows";
+// ReadSession.data_format AVRO.
+const DATA_FORMAT_AVRO: u64 = 1;
+
+// The subset of Avro that BigQuery uses for table rows. Logical types are
+// rendered the way the REST API renders the matching column type.
+enum AvroType {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Json,
+    Date,
+    TimeMicros,
+    TimestampMicros,
+    Decimal(u32),
+    Array(Box<AvroType>),
+    Record(Vec<(String, AvroType)>),
+    Union(Vec<AvroType>),
+}
+
+fn avro_primitive(name: &str) -> Result<AvroType, Error> {
+    match name {
+        "null" => Ok(AvroType::Null),
+        "boolean" => Ok(AvroType::Boolean),
+        "int" => Ok(AvroType::Int),
+        "long" => Ok(AvroType::Long),
+        "float" => Ok(AvroType::Float),
+        "double" => Ok(AvroType::Double),
+        "bytes" => Ok(AvroType::Bytes),
+        "string" => Ok(AvroType::String),
+        x => Err(anyhow!("unsupported Avro type `{}`", x)),
+    }
+}
+
+fn avro_type(schema: &serde_json::Value) -> Result<AvroType, Error> {
+    match schema {
+        serde_json::Value::String(name) => avro_primitive(name),
+        serde_json::Value::Array(branches) => Ok(AvroType::Union(
+            branches.iter().map(avro_type).collect::<Result<_, _>>()?,
+        )),
+        serde_json::Value::Object(o) => {
+            let logical_type = o.get("logicalType").and_then(|x| x.as_str());
+            match o.get("type") {
+                Some(serde_json::Value::String(kind)) => match (kind.as_str(), logical_type) {
+                    ("record", _) => {
+                        let mut fields = Vec::new();
+                        for field in o
+                            .get("fields")
+                            .and_then(|x| x.as_array())
+                            .unwrap_or(&vec![])
+                        {
+                            let name = field["name"]
+                                .as_str()
+                                .ok_or_else(|| anyhow!("Avro field without a name"))?;
+                            fields.push((name.to_string(), avro_type(&field["type"])?));
+                        }
+                        Ok(AvroType::Record(fields))
+                    }
+                    ("array", _) => Ok(AvroType::Array(Box::new(avro_type(&o["items"])?))),
+                    ("int", Some("date")) => Ok(AvroType::Date),
+                    ("long", Some("time-micros")) => Ok(AvroType::TimeMicros),
+                    ("long", Some("timestamp-micros")) => Ok(AvroType::TimestampMicros),
+                    ("bytes", Some("decimal")) => {
+                        Ok(AvroType::Decimal(o["scale"].as_u64().unwrap_or(0) as u32))
+                    }
+                    ("string", _) if o.get("sqlType").and_then(|x| x.as_str()) == Some("JSON") => {
+                        Ok(AvroType::Json)
+                    }
+                    (x, _) => avro_primitive(x),
+                },
+                Some(x) => avro_type(x),
+                None => Err(anyhow!("Avro schema without a type")),
+            }
+        }
+        x => Err(anyhow!("unsupported Avro schema {}", x)),
+    }
+}
+
+// Two's-complement big-endian unscaled value, as a decimal string such as
+// "-12.5". Works for BIGNUMERIC widths beyond i128.
+fn decimal_string(bytes: &[u8], scale: u32) -> String {
+    let negative = bytes.first().is_some_and(|x| x & 0x80 != 0);
+    let mut magnitude: Vec<u8> = bytes.to_vec();
+    if negative {
+        for byte in magnitude.iter_mut() {
+            *byte = !*byte;
+        }
+        for byte in magnitude.iter_mut().rev() {
+            let (sum, carry) = byte.overflowing_add(1);
+            *byte = sum;
+            if !carry {
+                break;
+            }
+        }
+    }
+    let mut digits = Vec::new();
+    while magnitude.iter().any(|x| *x != 0) {
+        let mut remainder = 0u32;
+        for byte in magnitude.iter_mut() {
+            let value = (remainder << 8) | *byte as u32;
+            *byte = (value / 10) as u8;
+            remainder = value % 10;
+        }
+        digits.push(b'0' + remainder as u8);
+    }
+    while digits.len() <= scale as usize {
+        digits.push(b'0');
+    }
+    digits.reverse();
+    let (integer, fraction) = digits.split_at(digits.len() - scale as usize);
+    let fraction = String::from_utf8_lossy(fraction);
+    let fraction = fraction.trim_end_matches('0');
+    format!(
+        "{}{}{}{}",
+        if negative { "-" } else { "" },
+        String::from_utf8_lossy(integer),
+        if fraction.is_empty() { "" } else { "." },
+        fraction
+    )
+}
+
+struct AvroReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> AvroReader<'a> {
+    // Zigzag-encoded int or long.
+    fn long(&mut self) -> Result<i64, Error> {
+        let n = read_varint(self.buf, &mut self.pos)?;
+        Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| anyhow!("truncated Avro row"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.long()?;
+        if len < 0 {
+            return Err(anyhow!("negative Avro length"));
+        }
+        self.take(len as usize)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        Ok(String::from_utf8_lossy(self.bytes()?).to_string())
+    }
+
+    fn value(&mut self, kind: &AvroType) -> Result<serde_json::Value, Error> {
+        Ok(match kind {
+            AvroType::Null => serde_json::Value::Null,
+            AvroType::Boolean => serde_json::Value::Bool(self.take(1)?[0] != 0),
+            AvroType::Int | AvroType::Long => serde_json::Value::from(self.long()?),
+            AvroType::Float => {
+                let bytes: [u8; 4] = self.take(4)?.try_into()?;
+                serde_json::Value::from(f32::from_le_bytes(bytes) as f64)
+            }
+            AvroType::Double => {
+                let bytes: [u8; 8] = self.take(8)?.try_into()?;
+                serde_json::Value::from(f64::from_le_bytes(bytes))
+            }
+            AvroType::Bytes => serde_json::Value::String(base64::encode(self.bytes()?)),
+            AvroType::String => serde_json::Value::String(self.string()?),
+            AvroType::Json => serde_json::from_str(&self.string()?)?,
+            AvroType::Date => {
+                let days = self.long()? as i32;
+                let date = Date::from_julian_day(UNIX_EPOCH_JULIAN_DAY + days)?;
+                serde_json::Value::String(date.format(format_description!("[year]-[month]-[day]"))?)
+            }
+            AvroType::TimeMicros => {
+                let micros = self.long()?;
+                let seconds = micros / 1_000_000;
+                let fraction = micros % 1_000_000;
+                let time = format!(
+                    "{:02}:{:02}:{:02}",
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                );
+                serde_json::Value::String(if fraction == 0 {
+                    time
+                } else {
+                    format!("{}.{:06}", time, fraction)
+                })
+            }
+            AvroType::TimestampMicros => {
+                let micros = self.long()? as i128;
+                let timestamp =
+                    OffsetDateTime::from_unix_timestamp_nanos(micros * 1_000)?.format(&Rfc3339)?;
+                serde_json::Value::String(timestamp)
+            }
+            AvroType::Decimal(scale) => {
+                serde_json::Value::String(decimal_string(self.bytes()?, *scale))
+            }
+            AvroType::Array(items) => {
+                let mut values = Vec::new();
+                loop {
+                    let mut count = self.long()?;
+                    if count == 0 {
+                        break;
+                    }
+                    if count < 0 {
+                        // Negative counts are followed by the block's byte size.
+                        count = -count;
+                        self.long()?;
+                    }
+                    for _ in 0..count {
+                        values.push(self.value(items)?);
+                    }
+                }
+                serde_json::Value::Array(values)
+            }
+            AvroType::Record(fields) => {
+                let mut record = serde_json::Map::new();
+                for (name, field) in fields {
+                    record.insert(name.to_string(), self.value(field)?);
+                }
+                serde_json::Value::Object(record)
+            }
+            AvroType::Union(branches) => {
+                let index = self.long()?;
+                let branch = branches
+                    .get(index as usize)
+                    .ok_or_else(|| anyhow!("Avro union index {} is out of range", index))?;
+                self.value(branch)?
+            }
+        })
+    }
+}
+
+fn storage_read_call(
+    access_token: &str,
+    path: &str,
+    routing: String,
+    frame: Vec<u8>,
+) -> Result<Response, Error> {
+    Ok(
+        Request::post(format!("https://{}{}", STORAGE_WRITE_HOST, path))
+            .with_header("Authorization", format!("Bearer {}", access_token))
+            .with_header("Content-Type", "application/grpc")
+            .with_header("TE", "trailers")
+            .with_header("x-goog-request-params", routing)
+            .with_body(frame)
+            .with_pass(true)
+            .send(storage_backend()?)?,
+    )
+}
+
+fn grpc_error(call: &str, code: u64, text: &str) -> Error {
+    let msg = format!("Storage Read {} error (gRPC code {}): {}", call, code, text);
+    error!("{}", msg);
+    anyhow!(msg)
+}
+
+// Opens an Avro read session with one stream over `table`, e.g.
+// `projects/p/datasets/d/tables/t`. Returns the stream and row schema, or None
+// when the table is empty and BigQuery hands out no streams.
+fn create_read_session(
+    tomlfile: &Config,
+    access_token: &str,
+    table: &str,
+) -> Result<Option<(String, AvroType)>, Error> {
+    let mut read_session = Vec::new();
+    put_uint(&mut read_session, 3, DATA_FORMAT_AVRO);
+    put_bytes(&mut read_session, 6, table.as_bytes());
+    let mut request = Vec::new();
+    put_bytes(
+        &mut request,
+        1,
+        format!("projects/{}", tomlfile.bigquery.projectid).as_bytes(),
+    );
+    put_bytes(&mut request, 2, &read_session);
+    put_uint(&mut request, 3, 1);
+    let mut resp = storage_read_call(
+        access_token,
+        CREATE_READ_SESSION_PATH,
+        format!("read_session.table={}", urlencoding::encode(table)),
+        grpc_frame(&request),
+    )?;
+    let mut body = resp.take_body();
+    let mut message = Vec::new();
+    body.read_to_end(&mut message)?;
+    if let Some((code, text)) = grpc_status(&resp, &mut body) {
+        if code != 0 {
+            return Err(grpc_error("CreateReadSession", code, &text));
+        }
+    }
+    let mut schema = None;
+    let mut stream = None;
+    for (field, value) in read_fields(message.get(5..).unwrap_or(&[]))? {
+        match (field, value) {
+            (4, WireValue::Bytes(avro_schema)) => {
+                for (schema_field, schema_value) in read_fields(avro_schema)? {
+                    if let (1, WireValue::Bytes(x)) = (schema_field, schema_value) {
+                        schema = Some(avro_type(&serde_json::from_slice(x)?)?);
+                    }
+                }
+            }
+            (10, WireValue::Bytes(read_stream)) if stream.is_none() => {
+                for (stream_field, stream_value) in read_fields(read_stream)? {
+                    if let (1, WireValue::Bytes(x)) = (stream_field, stream_value) {
+                        stream = Some(String::from_utf8_lossy(x).to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    match (stream, schema) {
+        (Some(stream), Some(schema)) => Ok(Some((stream, schema))),
+        (None, _) => Ok(None),
+        (Some(_), None) => Err(anyhow!("Storage Read session has no Avro schema")),
+    }
+}
+
+// Writes the rows of `table` to `out` as a JSON array and returns the number
+// of rows. Each ReadRows message is decoded and written before the next one is
+// read from the stream.
+pub fn write_table_rows<W: Write>(
+    tomlfile: &Config,
+    table: &str,
+    out: &mut W,
+) -> Result<u64, Error> {
+    debug!("Start BQ Storage Read");
+    let access_token = bigquery_access_token(tomlfile)?;
+    let (read_stream, schema) = match create_read_session(tomlfile, &access_token, table)? {
+        Some(x) => x,
+        None => {
+            out.write_all(b"[]")?;
+            return Ok(0);
+        }
+    };
+    let mut request = Vec::new();
+    put_bytes(&mut request, 1, read_stream.as_bytes());
+    let mut resp = storage_read_call(
+        &access_token,
+        READ_ROWS_PATH,
+        format!("read_stream={}", urlencoding::encode(&read_stream)),
+        grpc_frame(&request),
+    )?;
+    let mut body = resp.take_body();
+    let mut row_count = 0u64;
+    out.write_all(b"[")?;
+    loop {
+        let mut header = [0u8; 5];
+        match body.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let mut message = vec![0u8; len];
+        body.read_exact(&mut message)?;
+        for (field, value) in read_fields(&message)? {
+            if let (3, WireValue::Bytes(avro_rows)) = (field, value) {
+                for (rows_field, rows_value) in read_fields(avro_rows)? {
+                    if let (1, WireValue::Bytes(rows)) = (rows_field, rows_value) {
+                        let mut reader = AvroReader { buf: rows, pos: 0 };
+                        while reader.pos < rows.len() {
+                            if row_count > 0 {
+                                out.write_all(b",")?;
+                            }
+                            serde_json::to_writer(&mut *out, &reader.value(&schema)?)?;
+                            row_count += 1;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if let Some((code, text)) = grpc_status(&resp, &mut body) {
+        if code != 0 {
+            return Err(grpc_error("ReadRows", code, &text));
+        }
+    }
+    out.write_all(b"]")?;
+    Ok(row_count)
+}
@@ -0,0 +1,430 @@
+use crate::config::Config;
+use crate::gcp::dataset_and_table;
+use crate::geography::wkt_to_geojson;
+use crate::token::bigquery_access_token;
+use anyhow::anyhow;
+use fastly::backend::{Backend, BackendBuilder};
+use fastly::experimental::{BodyExt, GrpcBackend};
+use fastly::{Body, Error, Request, Response};
+use log::{debug, error};
+use std::io::Read;
+use time::macros::format_description;
+use time::Date;
+
+// BigQuery Storage Write API over gRPC. Rows are appended to the table's
+// `_default` stream, which commits immediately with at-least-once semantics,
+// so no stream creation or finalization is needed.
+pub const STORAGE_WRITE_BACKEND: &str = "bigquerystorage";
+pub(crate) const STORAGE_WRITE_HOST: &str = "bigquerystorage.googleapis.com";
+const APPEND_ROWS_PATH: &str = "/google.cloud.bigquery.storage.v1.BigQueryWrite/AppendRows";
+const MAX_ATTEMPTS: u32 = 3;
+// gRPC codes retried on the default stream: DEADLINE_EXCEEDED,
+// RESOURCE_EXHAUSTED, ABORTED, INTERNAL and UNAVAILABLE.
+const RETRYABLE_CODES: [u64; 5] = [4, 8, 10, 13, 14];
+// Julian day number of the Unix epoch, 1970-01-01.
+pub(crate) const UNIX_EPOCH_JULIAN_DAY: i32 = 2_440_588;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ColumnType {
+    String,
+    Int64,
+    Float64,
+    Bool,
+    Date,
+    // WKT text.
+    Geography,
+}
+
+impl ColumnType {
+    // The type named in a `[[bigquery.columns]]` entry, in BigQuery's spelling.
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "STRING" => Some(ColumnType::String),
+            "INT64" | "INTEGER" => Some(ColumnType::Int64),
+            "FLOAT64" | "FLOAT" => Some(ColumnType::Float64),
+            "BOOL" | "BOOLEAN" => Some(ColumnType::Bool),
+            "DATE" => Some(ColumnType::Date),
+            "GEOGRAPHY" => Some(ColumnType::Geography),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub name: String,
+    pub kind: ColumnType,
+    // Inserted rows must have a non-null value.
+    pub required: bool,
+}
+
+impl Column {
+    pub fn new(name: &str, kind: ColumnType, required: bool) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            required,
+        }
+    }
+
+    // GoogleSQL type of a query parameter compared against the column.
+    pub fn param_type(&self) -> &'static str {
+        match self.kind {
+            ColumnType::String => "STRING",
+            ColumnType::Int64 => "INT64",
+            ColumnType::Float64 => "FLOAT64",
+            ColumnType::Bool => "BOOL",
+            ColumnType::Date => "DATE",
+            ColumnType::Geography => "GEOGRAPHY",
+        }
+    }
+
+    // BigQuery's name for the column's type in a table schema.
+    pub fn bq_type(&self) -> &'static str {
+        match self.kind {
+            ColumnType::String => "STRING",
+            ColumnType::Int64 => "INTEGER",
+            ColumnType::Float64 => "FLOAT",
+            ColumnType::Bool => "BOOLEAN",
+            ColumnType::Date => "DATE",
+            ColumnType::Geography => "GEOGRAPHY",
+        }
+    }
+}
+
+// Minimal protobuf wire encoding, enough for AppendRowsRequest and the
+// Storage Read requests.
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub(crate) fn put_uint(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_double(buf: &mut Vec<u8>, field: u64, value: f64) {
+    put_varint(buf, (field << 3) | 1);
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(buf, (field << 3) | 2);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+// google.protobuf.DescriptorProto describing one row.
+fn row_descriptor(columns: &[Column]) -> Vec<u8> {
+    let mut descriptor = Vec::new();
+    put_bytes(&mut descriptor, 1, b"Row");
+    for (i, column) in columns.iter().enumerate() {
+        // FieldDescriptorProto: TYPE_STRING = 9, TYPE_INT64 = 3, TYPE_INT32 = 5,
+        // TYPE_DOUBLE = 1, TYPE_BOOL = 8.
+        let proto_type = match column.kind {
+            ColumnType::String | ColumnType::Geography => 9,
+            ColumnType::Int64 => 3,
+            ColumnType::Date => 5,
+            ColumnType::Float64 => 1,
+            ColumnType::Bool => 8,
+        };
+        let mut field = Vec::new();
+        put_bytes(&mut field, 1, column.name.as_bytes());
+        put_uint(&mut field, 3, i as u64 + 1);
+        put_uint(&mut field, 4, 1); // LABEL_OPTIONAL
+        put_uint(&mut field, 5, proto_type);
+        put_bytes(&mut descriptor, 2, &field);
+    }
+    descriptor
+}
+
+fn encode_row(columns: &[Column], row: &serde_json::Value) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    for (i, column) in columns.iter().enumerate() {
+        let value = &row[column.name.as_str()];
+        if value.is_null() {
+            continue;
+        }
+        let field = i as u64 + 1;
+        match column.kind {
+            ColumnType::String => {
+                let x = value
+                    .as_str()
+                    .ok_or_else(|| anyhow!("column `{}` must be a string", column.name))?;
+                put_bytes(&mut buf, field, x.as_bytes());
+            }
+            ColumnType::Int64 => {
+                let x = value
+                    .as_i64()
+                    .ok_or_else(|| anyhow!("column `{}` must be an integer", column.name))?;
+                put_uint(&mut buf, field, x as u64);
+            }
+            ColumnType::Float64 => {
+                let x = value
+                    .as_f64()
+                    .ok_or_else(|| anyhow!("column `{}` must be a number", column.name))?;
+                put_double(&mut buf, field, x);
+            }
+            ColumnType::Bool => {
+                let x = value
+                    .as_bool()
+                    .ok_or_else(|| anyhow!("column `{}` must be a boolean", column.name))?;
+                put_uint(&mut buf, field, x as u64);
+            }
+            ColumnType::Date => {
+                // DATE is sent as int32 days since the Unix epoch.
+                let x = value
+                    .as_str()
+                    .ok_or_else(|| anyhow!("column `{}` must be a date string", column.name))?;
+                let date = Date::parse(x, &format_description!("[year]-[month]-[day]"))?;
+                let days = date.to_julian_day() - UNIX_EPOCH_JULIAN_DAY;
+                put_uint(&mut buf, field, days as i64 as u64);
+            }
+            ColumnType::Geography => {
+                // GEOGRAPHY is sent as WKT text.
+                let x = value
+                    .as_str()
+                    .ok_or_else(|| anyhow!("column `{}` must be a WKT string", column.name))?;
+                wkt_to_geojson(x).map_err(|e| anyhow!("column `{}`: {}", column.name, e))?;
+                put_bytes(&mut buf, field, x.as_bytes());
+            }
+        }
+    }
+    Ok(buf)
+}
+
+fn append_rows_request(
+    write_stream: &str,
+    columns: &[Column],
+    rows: &[serde_json::Value],
+) -> Result<Vec<u8>, Error> {
+    let mut proto_schema = Vec::new();
+    put_bytes(&mut proto_schema, 1, &row_descriptor(columns));
+    let mut proto_rows = Vec::new();
+    for row in rows {
+        put_bytes(&mut proto_rows, 1, &encode_row(columns, row)?);
+    }
+    let mut proto_data = Vec::new();
+    put_bytes(&mut proto_data, 1, &proto_schema);
+    put_bytes(&mut proto_data, 2, &proto_rows);
+    let mut request = Vec::new();
+    put_bytes(&mut request, 1, write_stream.as_bytes());
+    put_bytes(&mut request, 4, &proto_data);
+    Ok(grpc_frame(&request))
+}
+
+// gRPC length-prefixed message: uncompressed flag + big-endian length.
+pub(crate) fn grpc_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(message.len() + 5);
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+pub(crate) fn read_varint(buf: &[u8], pos: &mut usize) -> Result<u64, Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf
+            .get(*pos)
+            .ok_or_else(|| anyhow!("truncated protobuf varint"))?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("protobuf varint is too long"))
+}
+
+pub(crate) enum WireValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+pub(crate) fn read_fields(buf: &[u8]) -> Result<Vec<(u64, WireValue<'_>)>, Error> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let key = read_varint(buf, &mut pos)?;
+        let value = match key & 7 {
+            0 => WireValue::Varint(read_varint(buf, &mut pos)?),
+            1 => {
+                pos += 8;
+                continue;
+            }
+            2 => {
+                let len = read_varint(buf, &mut pos)? as usize;
+                let bytes = buf
+                    .get(pos..pos + len)
+                    .ok_or_else(|| anyhow!("truncated protobuf field"))?;
+                pos += len;
+                WireValue::Bytes(bytes)
+            }
+            5 => {
+                pos += 4;
+                continue;
+            }
+            x => return Err(anyhow!("unsupported protobuf wire type {}", x)),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+// Decodes the AppendRowsResponse `error` (google.rpc.Status) and `row_errors`.
+fn append_rows_errors(message: &[u8]) -> Result<Option<(u64, String)>, Error> {
+    let mut row_errors: Vec<String> = Vec::new();
+    for (field, value) in read_fields(message)? {
+        match (field, value) {
+            (2, WireValue::Bytes(status)) => {
+                let mut code = 0;
+                let mut text = String::new();
+                for (status_field, status_value) in read_fields(status)? {
+                    match (status_field, status_value) {
+                        (1, WireValue::Varint(x)) => code = x,
+                        (2, WireValue::Bytes(x)) => text = String::from_utf8_lossy(x).to_string(),
+                        _ => {}
+                    }
+                }
+                return Ok(Some((code, text)));
+            }
+            (4, WireValue::Bytes(row_error)) => {
+                let mut index = 0;
+                let mut text = String::new();
+                for (row_field, row_value) in read_fields(row_error)? {
+                    match (row_field, row_value) {
+                        (1, WireValue::Varint(x)) => index = x,
+                        (3, WireValue::Bytes(x)) => text = String::from_utf8_lossy(x).to_string(),
+                        _ => {}
+                    }
+                }
+                row_errors.push(format!("row {}: {}", index, text));
+            }
+            _ => {}
+        }
+    }
+    if row_errors.is_empty() {
+        Ok(None)
+    } else {
+        // INVALID_ARGUMENT, never retried.
+        Ok(Some((3, row_errors.join("; "))))
+    }
+}
+
+pub(crate) fn storage_backend() -> Result<Backend, Error> {
+    if let Ok(backend) = Backend::from_name(STORAGE_WRITE_BACKEND) {
+        return Ok(backend);
+    }
+    Ok(
+        BackendBuilder::new(STORAGE_WRITE_BACKEND, format!("{}:443", STORAGE_WRITE_HOST))
+            .override_host(STORAGE_WRITE_HOST)
+            .enable_ssl()
+            .sni_hostname(STORAGE_WRITE_HOST)
+            .for_grpc(true)
+            .finish()?,
+    )
+}
+
+// One AppendRows call. Returns the gRPC status code and message on failure.
+fn send_append_rows(
+    access_token: &str,
+    write_stream: &str,
+    frame: &[u8],
+) -> Result<Result<(), (u64, String)>, Error> {
+    let mut resp = Request::post(format!(
+        "https://{}{}",
+        STORAGE_WRITE_HOST, APPEND_ROWS_PATH
+    ))
+    .with_header("Authorization", format!("Bearer {}", access_token))
+    .with_header("Content-Type", "application/grpc")
+    .with_header("TE", "trailers")
+    .with_header(
+        "x-goog-request-params",
+        format!("write_stream={}", urlencoding::encode(write_stream)),
+    )
+    .with_body(frame.to_vec())
+    .with_pass(true)
+    .send(storage_backend()?)?;
+    let mut body = resp.take_body();
+    let mut message = Vec::new();
+    body.read_to_end(&mut message)?;
+    if let Some((code, text)) = grpc_status(&resp, &mut body) {
+        if code != 0 {
+            return Ok(Err((code, text)));
+        }
+    }
+    if message.len() > 5 {
+        if let Some(error) = append_rows_errors(&message[5..])? {
+            return Ok(Err(error));
+        }
+    }
+    Ok(Ok(()))
+}
+
+// gRPC status code and message of a response whose body has been read.
+pub(crate) fn grpc_status(resp: &Response, body: &mut Body) -> Option<(u64, String)> {
+    // A trailers-only response carries grpc-status as a header.
+    let grpc_status = match resp.get_header_str("grpc-status") {
+        Some(x) => Some((
+            x.to_string(),
+            resp.get_header_str("grpc-message").map(str::to_string),
+        )),
+        None => match body.get_trailers() {
+            Ok(trailers) => trailers.get("grpc-status").map(|x| {
+                (
+                    x.to_str().unwrap_or("2").to_string(),
+                    trailers
+                        .get("grpc-message")
+                        .and_then(|m| m.to_str().ok())
+                        .map(str::to_string),
+                )
+            }),
+            Err(_) => None,
+        },
+    };
+    grpc_status.map(|(code, text)| (code.parse::<u64>().unwrap_or(2), text.unwrap_or_default()))
+}
+
+// Appends rows to the configured table and returns the number of rows written.
+// Transient gRPC failures are retried; the default stream may then write a
+// row twice, which is the API's documented at-least-once behaviour.
+pub fn append_rows(
+    tomlfile: &Config,
+    columns: &[Column],
+    rows: &[serde_json::Value],
+) -> Result<u64, Error> {
+    debug!("Start BQ Storage Write");
+    let (dataset_id, table_id) = dataset_and_table(tomlfile)?;
+    let write_stream = format!(
+        "projects/{}/datasets/{}/tables/{}/streams/_default",
+        tomlfile.bigquery.projectid, dataset_id, table_id
+    );
+    let access_token = bigquery_access_token(tomlfile)?;
+    let frame = append_rows_request(&write_stream, columns, rows)?;
+    let mut attempt = 1;
+    loop {
+        match send_append_rows(&access_token, &write_stream, &frame)? {
+            Ok(()) => return Ok(rows.len() as u64),
+            Err((code, text)) if RETRYABLE_CODES.contains(&code) && attempt < MAX_ATTEMPTS => {
+                error!(
+                    "Storage Write attempt {} failed with gRPC code {}: {}",
+                    attempt, code, text
+                );
+                attempt += 1;
+            }
+            Err((code, text)) => {
+                let msg = format!(
+                    "Storage Write AppendRows error (gRPC code {}): {}",
+                    code, text
+                );
+                error!("{}", msg);
+                return Err(anyhow!(msg));
+            }
+        }
+    }
+}
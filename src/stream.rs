@@ -0,0 +1,61 @@
+use fastly::http::body::StreamingBody;
+use fastly::{Error, Response};
+use log::error;
+use std::cell::{Cell, RefCell};
+
+// Large results are streamed: a handler returns the response head and leaves a
+// writer for its body here. `main` sends the head once the middleware has added
+// its headers, then runs the writer against the client connection, so rows go
+// out as they are read instead of the whole body being built first.
+pub type BodyWriter = Box<dyn FnOnce(&mut StreamingBody) -> Result<(), Error>>;
+
+thread_local! {
+    static PENDING: RefCell<Option<BodyWriter>> = RefCell::new(None);
+    static DISABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+// Whether handlers may stream their results. Responses that are collected
+// whole, like the operations of a batch, are built with streaming off.
+pub fn enabled() -> bool {
+    !DISABLED.with(Cell::get)
+}
+
+// Runs `f` with streaming off.
+pub fn buffered<T>(f: impl FnOnce() -> T) -> T {
+    DISABLED.with(|x| x.set(true));
+    let result = f();
+    DISABLED.with(|x| x.set(false));
+    result
+}
+
+// `resp` with its body to be written by `writer`.
+pub fn streamed(resp: Response, writer: BodyWriter) -> Response {
+    PENDING.with(|x| *x.borrow_mut() = Some(writer));
+    resp
+}
+
+// Whether the response on its way out has a streamed body, whose length is
+// not known and which cannot be buffered.
+pub fn is_streamed() -> bool {
+    PENDING.with(|x| x.borrow().is_some())
+}
+
+// Sends `resp` to the client, streaming its body when a handler left a writer.
+// The status has already gone out when a writer fails, so the body is aborted
+// and the client sees a truncated response rather than a clean end.
+pub fn send(resp: Response) -> Result<(), Error> {
+    let writer = match PENDING.with(|x| x.borrow_mut().take()) {
+        Some(x) => x,
+        None => {
+            resp.send_to_client();
+            return Ok(());
+        }
+    };
+    let mut body = resp.stream_to_client();
+    if let Err(e) = writer(&mut body) {
+        error!("Streamed body error: {}", e);
+        return Ok(());
+    }
+    body.finish()?;
+    Ok(())
+}
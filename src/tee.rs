@@ -0,0 +1,92 @@
+use crate::config::{Config, TeeConfiguration};
+use crate::request_id;
+use crate::token::gcp_access_token_request;
+use fastly::{Error, Request};
+use log::error;
+use time::OffsetDateTime;
+
+#[derive(serde::Serialize, Debug)]
+pub struct TeeRecord {
+    pub route: String,
+    pub query_fingerprint: String,
+    pub params: serde_json::Value,
+    pub row_count: u64,
+    pub requester: String,
+    pub job_reference: serde_json::Value,
+    pub timestamp: i64,
+    pub request_id: String,
+}
+
+impl TeeRecord {
+    pub fn new(
+        route: &str,
+        query: &str,
+        params: serde_json::Value,
+        row_count: u64,
+        req: &Request,
+        bqresp_json: &serde_json::Value,
+    ) -> Self {
+        let requester = match req.get_client_ip_addr() {
+            Some(ip) => ip.to_string(),
+            None => "unknown".to_string(),
+        };
+        Self {
+            route: route.to_string(),
+            query_fingerprint: hex::encode(hmac_sha256::Hash::hash(query.as_bytes())),
+            params,
+            row_count,
+            requester,
+            job_reference: bqresp_json["jobReference"].clone(),
+            timestamp: OffsetDateTime::now_utc().unix_timestamp(),
+            request_id: request_id::current(),
+        }
+    }
+}
+
+// Best effort: the tee never fails the client request, errors are only logged.
+pub fn tee_query(tomlfile: &Config, record: &TeeRecord) {
+    let tee = match &tomlfile.tee {
+        Some(x) if x.routes.iter().any(|r| r == &record.route) => x,
+        _ => return,
+    };
+    if let Err(e) = send_tee(tomlfile, tee, record) {
+        error!("Tee Error: {}", e);
+    }
+}
+
+fn send_tee(tomlfile: &Config, tee: &TeeConfiguration, record: &TeeRecord) -> Result<(), Error> {
+    let access_token = gcp_access_token_request(tomlfile, &[tee.scope.as_str()])?;
+    let record_str = serde_json::to_string(record)?;
+
+    // The pending requests are not awaited so the client response is not delayed.
+    if let Some(topic) = &tee.pubsub_topic {
+        let body = serde_json::json!({
+            "messages": [{ "data": base64::encode(&record_str) }]
+        });
+        Request::post(format!(
+            "https://pubsub.googleapis.com/v1/{}:publish",
+            topic
+        ))
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_body_json(&body)?
+        .with_pass(true)
+        .send_async("pubsub")?;
+    }
+    if let Some(bucket) = &tee.gcs_bucket {
+        let object_name = format!(
+            "tee/{}/{}-{}.json",
+            record.route, record.timestamp, record.query_fingerprint
+        );
+        Request::post(format!(
+            "https://storage.googleapis.com/upload/storage/v1/b/{}/o?uploadType=media&name={}",
+            bucket,
+            urlencoding::encode(&object_name)
+        ))
+        .with_header("Authorization", format!("Bearer {}", access_token))
+        .with_header("Content-Type", "application/json")
+        .with_body(record_str)
+        .with_pass(true)
+        .send_async("gcs")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,381 @@
+use crate::config::{Config, CredentialSourceConfiguration, ExternalAccountConfiguration};
+use crate::token_cache::{cached_remaining, cached_token};
+use anyhow::anyhow;
+use fastly::http::StatusCode;
+use fastly::secret_store::SecretStore;
+use fastly::{Error, Request};
+use jwt_simple::algorithms::{RS256KeyPair, RSAKeyPairLike};
+use jwt_simple::claims::{Claims, JWTClaims};
+use jwt_simple::prelude::Duration;
+use log::error;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const DEFAULT_STS_TOKEN_URL: &str = "https://sts.googleapis.com/v1/token";
+const DEFAULT_STS_BACKEND: &str = "sts";
+const DEFAULT_IMPERSONATION_BACKEND: &str = "iamcredentials";
+const DEFAULT_SUBJECT_TOKEN_BACKEND: &str = "subject_token";
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+// STS tokens that are only used to impersonate a service account get the
+// broad scope; the impersonated token carries the requested one.
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+// Where access tokens come from: a service account's RSA key signs an
+// assertion exchanged at the `idp` backend, or an external account (workload
+// identity federation) trades a subject token from another identity provider
+// at Google's Security Token Service.
+enum Credentials<'a> {
+    ServiceAccount(ServiceAccount<'a>),
+    ExternalAccount(&'a ExternalAccountConfiguration),
+}
+
+struct ServiceAccount<'a> {
+    email: &'a str,
+    // (key id, PEM key) pairs, tried in order until Google accepts one.
+    keys: Vec<(Option<&'a str>, &'a str)>,
+    // User impersonated through domain-wide delegation.
+    subject: Option<&'a str>,
+}
+
+impl<'a> Credentials<'a> {
+    fn from_config(tomlfile: &'a Config) -> Self {
+        match &tomlfile.bigquery.external_account {
+            Some(x) => Credentials::ExternalAccount(x),
+            None => Credentials::ServiceAccount(ServiceAccount {
+                email: &tomlfile.bigquery.service_account_email,
+                keys: tomlfile.bigquery.service_account_keys(),
+                subject: tomlfile.bigquery.subject.as_deref(),
+            }),
+        }
+    }
+
+    // Identifies the account in token cache keys.
+    fn account(&self) -> String {
+        match self {
+            Credentials::ServiceAccount(x) => match x.subject {
+                Some(subject) => format!("{} as {}", x.email, subject),
+                None => x.email.to_string(),
+            },
+            Credentials::ExternalAccount(x) => x
+                .service_account_impersonation_url
+                .clone()
+                .unwrap_or_else(|| x.audience.clone()),
+        }
+    }
+
+    // A new access token for `scope` and its lifetime in seconds.
+    fn fetch(&self, tomlfile: &Config, scope: String) -> Result<(String, u64), Error> {
+        match self {
+            Credentials::ServiceAccount(x) => idp_access_token_request(tomlfile, x, scope),
+            Credentials::ExternalAccount(x) => external_access_token_request(x, scope),
+        }
+    }
+}
+
+//Service Account to get access token
+pub(crate) fn gcp_access_token_request(
+    tomlfile: &Config,
+    scopes: &[&str],
+) -> Result<String, Error> {
+    // Google takes several scopes as one space-delimited string.
+    let scope_value = scopes.join(" ");
+    let credentials = Credentials::from_config(tomlfile);
+    if let Credentials::ServiceAccount(x) = &credentials {
+        // Google only accepts a self-signed JWT for the account itself, so
+        // delegated access still goes through the exchange.
+        if tomlfile.gcp.self_signed_jwt.unwrap_or(false) && x.subject.is_none() {
+            return self_signed_jwt(tomlfile, x, scope_value);
+        }
+    }
+    cached_token(tomlfile, &credentials.account(), &scope_key(scopes), || {
+        credentials.fetch(tomlfile, scope_value.clone())
+    })
+}
+
+// Cache entries are keyed on the set of scopes, whatever order the caller
+// lists it in.
+fn scope_key(scopes: &[&str]) -> String {
+    let mut scope_set = scopes.to_vec();
+    scope_set.sort_unstable();
+    scope_set.dedup();
+    scope_set.join(" ")
+}
+
+// Seconds left on the cached token for the BigQuery calls, for GET /status.
+// None when the token cache holds no valid one.
+pub(crate) fn cached_bigquery_token_remaining(tomlfile: &Config) -> Option<i64> {
+    let credentials = Credentials::from_config(tomlfile);
+    cached_remaining(
+        tomlfile,
+        &credentials.account(),
+        &scope_key(&tomlfile.bigquery.scopes()),
+    )
+}
+
+// Token for the BigQuery calls: the caller's own in `oauth_passthrough` mode,
+// otherwise one for the configured account and `[bigquery]` scopes.
+pub(crate) fn bigquery_access_token(tomlfile: &Config) -> Result<String, Error> {
+    if let Some(x) = &tomlfile.bigquery.user_access_token {
+        return Ok(x.clone());
+    }
+    gcp_access_token_request(tomlfile, &tomlfile.bigquery.scopes())
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Scope {
+    scope: String,
+}
+
+// Signs `claims` with `key`, naming its key id in the `kid` header.
+fn sign_jwt(
+    tomlfile: &Config,
+    (key_id, key): (Option<&str>, &str),
+    claims: JWTClaims<Scope>,
+) -> Result<String, Error> {
+    if tomlfile.gcp.alg != "RS256" {
+        return Err(anyhow!("Unsupported JWT alg: {}", tomlfile.gcp.alg));
+    }
+    let mut key_pair = RS256KeyPair::from_pem(key)?;
+    if let Some(x) = key_id {
+        key_pair = key_pair.with_key_id(x);
+    }
+    key_pair.sign(claims)
+}
+
+// A JWT signed by the service account that Google APIs accept directly as a
+// Bearer token. With a `scope` claim and no `aud` it is good for every API the
+// kit calls (BigQuery, the Storage APIs, Pub/Sub and GCS), so no IdP round
+// trip is needed.
+fn self_signed_jwt(
+    tomlfile: &Config,
+    account: &ServiceAccount,
+    scope_value: String,
+) -> Result<String, Error> {
+    let scope = Scope { scope: scope_value };
+    let claims = Claims::with_custom_claims(scope, Duration::from_secs(3600))
+        .with_issuer(account.email)
+        .with_subject(account.email);
+    let key = account
+        .keys
+        .first()
+        .ok_or_else(|| anyhow!("No service account key available"))?;
+    sign_jwt(tomlfile, *key, claims)
+}
+
+// Exchanges a signed JWT for an access token and its lifetime in seconds.
+// When Google rejects the assertion, e.g. because its key was deleted during
+// a rotation, the next key is tried.
+fn idp_access_token_request(
+    tomlfile: &Config,
+    account: &ServiceAccount,
+    scope_value: String,
+) -> Result<(String, u64), Error> {
+    // create jwt
+    let scope = Scope { scope: scope_value };
+    let mut claims = Claims::with_custom_claims(scope, Duration::from_secs(3600))
+        .with_issuer(account.email)
+        .with_audience(&tomlfile.gcp.aud);
+    if let Some(x) = account.subject {
+        claims = claims.with_subject(x);
+    }
+    let mut rejected = None;
+    for key in &account.keys {
+        let jwt = sign_jwt(tomlfile, *key, claims.clone())?;
+        match exchange_assertion(tomlfile, jwt)? {
+            Ok(x) => return Ok(x),
+            Err(e) => {
+                error!("Assertion signed with key {:?} rejected: {}", key.0, e);
+                rejected = Some(e);
+            }
+        }
+    }
+    let msg = match rejected {
+        Some(x) => format!("Error Access Token!: {}", x),
+        None => "No service account key available".to_string(),
+    };
+    error!("{}", msg);
+    Err(anyhow!(msg))
+}
+
+// Trades a signed assertion at the `idp` backend. The inner error is Google
+// rejecting the assertion (HTTP 400 or 401), which another key may fix.
+fn exchange_assertion(
+    tomlfile: &Config,
+    jwt: String,
+) -> Result<Result<(String, u64), String>, Error> {
+    // get access token
+    #[derive(serde::Serialize, Default, Debug)]
+    struct Form {
+        grant_type: String,
+        assertion: String,
+    }
+    let form = Form {
+        grant_type: tomlfile.gcp.grant_type.to_string(),
+        assertion: jwt,
+    };
+    let mut resp = match Request::post(tomlfile.gcp.aud.to_string())
+        .with_body_form(&form)?
+        .send("idp")
+    {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Request to Google IDP Error: {}", e);
+            error!("{}", msg);
+            return Err(anyhow!(msg));
+        }
+    };
+    let status = resp.get_status();
+    if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+        return Ok(Err(resp.take_body_str()));
+    }
+    if !status.is_success() {
+        let resp_str = resp.take_body_str();
+        let msg = format!("Error Access Token!: {}", resp_str);
+        error!("{}", msg);
+        return Err(anyhow!(msg));
+    }
+    let resp_value = resp.take_body_json::<serde_json::Value>()?;
+    let access_token = match resp_value["access_token"].as_str() {
+        Some(x) => x.to_string(),
+        None => {
+            let msg = "Can NOT get gcp access token";
+            error!("{}", msg);
+            return Err(anyhow!(msg));
+        }
+    };
+    let expires_in = resp_value["expires_in"].as_u64().unwrap_or(3600);
+
+    Ok(Ok((access_token, expires_in)))
+}
+
+// The external identity provider's token, from a URL behind a Fastly backend
+// or from a Secret Store. File and AWS credential sources need a host
+// filesystem or metadata server, which Compute does not have.
+fn subject_token(source: &CredentialSourceConfiguration) -> Result<String, Error> {
+    if let Some(name) = &source.secret_store {
+        let secret_name = source
+            .secret
+            .as_deref()
+            .ok_or_else(|| anyhow!("credential_source.secret is required with secret_store"))?;
+        let token = SecretStore::open(name)?
+            .try_get(secret_name)?
+            .ok_or_else(|| anyhow!("Secret {} not found in {}", secret_name, name))?
+            .try_plaintext()?;
+        return Ok(String::from_utf8(token.to_vec())?.trim().to_string());
+    }
+    let url = source
+        .url
+        .as_deref()
+        .ok_or_else(|| anyhow!("credential_source needs a url or a secret_store"))?;
+    let mut req = Request::get(url);
+    for (name, value) in source.headers.iter().flatten() {
+        req.set_header(name.as_str(), value.as_str());
+    }
+    let backend = source
+        .backend
+        .as_deref()
+        .unwrap_or(DEFAULT_SUBJECT_TOKEN_BACKEND);
+    let mut resp = req.send(backend)?;
+    if !resp.get_status().is_success() {
+        let msg = format!("Subject token request error: {}", resp.take_body_str());
+        error!("{}", msg);
+        return Err(anyhow!(msg));
+    }
+    let body = resp.take_body_str();
+    match &source.format {
+        Some(x) if x.kind == "json" => {
+            let field = x.subject_token_field_name.as_deref().ok_or_else(|| {
+                anyhow!("credential_source.format needs subject_token_field_name")
+            })?;
+            let value: serde_json::Value = serde_json::from_str(&body)?;
+            value[field]
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| anyhow!("Subject token response has no `{}`", field))
+        }
+        _ => Ok(body.trim().to_string()),
+    }
+}
+
+// Trades the subject token at STS and, when the configuration names a service
+// account to impersonate, trades the STS token for one of that account's.
+fn external_access_token_request(
+    account: &ExternalAccountConfiguration,
+    scope_value: String,
+) -> Result<(String, u64), Error> {
+    #[derive(serde::Serialize, Debug)]
+    struct Form<'a> {
+        grant_type: &'a str,
+        audience: &'a str,
+        scope: &'a str,
+        requested_token_type: &'a str,
+        subject_token_type: &'a str,
+        subject_token: String,
+    }
+    let form = Form {
+        grant_type: TOKEN_EXCHANGE_GRANT_TYPE,
+        audience: &account.audience,
+        scope: match account.service_account_impersonation_url {
+            Some(_) => CLOUD_PLATFORM_SCOPE,
+            None => &scope_value,
+        },
+        requested_token_type: ACCESS_TOKEN_TYPE,
+        subject_token_type: &account.subject_token_type,
+        subject_token: subject_token(&account.credential_source)?,
+    };
+    let token_url = account
+        .token_url
+        .as_deref()
+        .unwrap_or(DEFAULT_STS_TOKEN_URL);
+    let backend = account
+        .sts_backend
+        .as_deref()
+        .unwrap_or(DEFAULT_STS_BACKEND);
+    let mut resp = Request::post(token_url)
+        .with_body_form(&form)?
+        .send(backend)?;
+    if !resp.get_status().is_success() {
+        let msg = format!("STS token exchange error: {}", resp.take_body_str());
+        error!("{}", msg);
+        return Err(anyhow!(msg));
+    }
+    let resp_value = resp.take_body_json::<serde_json::Value>()?;
+    let sts_token = resp_value["access_token"]
+        .as_str()
+        .ok_or_else(|| anyhow!("STS response has no access_token"))?;
+    let expires_in = resp_value["expires_in"].as_u64().unwrap_or(3600);
+    let url = match &account.service_account_impersonation_url {
+        Some(x) => x,
+        None => return Ok((sts_token.to_string(), expires_in)),
+    };
+
+    let backend = account
+        .impersonation_backend
+        .as_deref()
+        .unwrap_or(DEFAULT_IMPERSONATION_BACKEND);
+    let mut resp = Request::post(url)
+        .with_header("Authorization", format!("Bearer {}", sts_token))
+        .with_body_json(&serde_json::json!({
+            "scope": scope_value.split_whitespace().collect::<Vec<_>>()
+        }))?
+        .send(backend)?;
+    if !resp.get_status().is_success() {
+        let msg = format!(
+            "Service account impersonation error: {}",
+            resp.take_body_str()
+        );
+        error!("{}", msg);
+        return Err(anyhow!(msg));
+    }
+    let resp_value = resp.take_body_json::<serde_json::Value>()?;
+    let access_token = resp_value["accessToken"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Impersonation response has no accessToken"))?;
+    let expires_in = resp_value["expireTime"]
+        .as_str()
+        .and_then(|x| OffsetDateTime::parse(x, &Rfc3339).ok())
+        .map(|x| (x - OffsetDateTime::now_utc()).whole_seconds().max(0) as u64)
+        .unwrap_or(3600);
+    Ok((access_token.to_string(), expires_in))
+}
@@ -0,0 +1,318 @@
+use crate::config::Config;
+use crate::stats;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use fastly::kv_store::{InsertMode, KVStore, KVStoreError};
+use fastly::secret_store::SecretStore;
+use fastly::Error;
+use log::error;
+use rand::RngCore;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+// Tokens are refreshed once less than this many seconds of their lifetime
+// remain, unless `[token_cache] refresh_margin_secs` says otherwise.
+const DEFAULT_REFRESH_MARGIN_SECS: u64 = 300;
+// A refresh lock outlives a stuck or crashed holder by at most this long.
+const LOCK_TTL_SECS: u64 = 10;
+// Without a usable token, requests that lose the lock poll for the winner's
+// token this often, for up to `[token_cache] lock_wait_ms` (default 2000).
+const LOCK_POLL_MS: u64 = 100;
+const DEFAULT_LOCK_WAIT_MS: u64 = 2000;
+const NONCE_LEN: usize = 12;
+
+// Access tokens cached in the KV Store named by `[token_cache]`, so every
+// instance in every POP shares them. Entries are keyed on a hash of the account
+// and scope and expire with the token. The cache is best effort: KV errors are
+// logged and the token is fetched from the IdP instead.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct CachedToken {
+    access_token: String,
+    // Unix time the token expires at.
+    expires_at: i64,
+}
+
+impl CachedToken {
+    fn remaining(&self) -> i64 {
+        self.expires_at - OffsetDateTime::now_utc().unix_timestamp()
+    }
+}
+
+fn cache_key(account: &str, scope: &str) -> String {
+    let source = format!("{} {}", account, scope);
+    format!(
+        "token/{}",
+        hex::encode(hmac_sha256::Hash::hash(source.as_bytes()))
+    )
+}
+
+fn open_cache(tomlfile: &Config) -> Option<KVStore> {
+    let name = &tomlfile.token_cache.as_ref()?.kv_store;
+    match KVStore::open(name) {
+        Ok(Some(x)) => Some(x),
+        Ok(None) => {
+            error!("KV Store `{}` is not linked to this service", name);
+            None
+        }
+        Err(e) => {
+            error!("KV Store `{}` open error: {}", name, e);
+            None
+        }
+    }
+}
+
+fn refresh_margin(tomlfile: &Config) -> i64 {
+    tomlfile
+        .token_cache
+        .as_ref()
+        .and_then(|x| x.refresh_margin_secs)
+        .unwrap_or(DEFAULT_REFRESH_MARGIN_SECS) as i64
+}
+
+// With `[token_cache] encryption_key_secret`, entries are envelope encrypted:
+// each token is sealed with AES-256-GCM under a fresh data key, which is in
+// turn sealed under the key from the Secret Store. Both are bound to the cache
+// key, so a KV dump yields no usable tokens and entries can't be swapped.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct SealedToken {
+    // Base64 of nonce || ciphertext.
+    wrapped_key: String,
+    ciphertext: String,
+}
+
+// The 256-bit key entries are sealed under, if encryption is configured. It is
+// stored hex encoded, e.g. from `openssl rand -hex 32`.
+fn encryption_key(tomlfile: &Config) -> Result<Option<[u8; 32]>, String> {
+    let cache = match &tomlfile.token_cache {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let secret_name = match &cache.encryption_key_secret {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let store_name = cache
+        .secret_store
+        .as_deref()
+        .ok_or("encryption_key_secret needs a secret_store")?;
+    let secret = SecretStore::open(store_name)
+        .map_err(|e| format!("Opening Secret Store {} failed: {}", store_name, e))?
+        .try_get(secret_name)
+        .map_err(|e| format!("Reading secret {} failed: {}", secret_name, e))?
+        .ok_or_else(|| format!("Secret {} not found", secret_name))?;
+    let plaintext = secret
+        .try_plaintext()
+        .map_err(|e| format!("Decrypting secret {} failed: {}", secret_name, e))?;
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(String::from_utf8_lossy(&plaintext).trim(), &mut key)
+        .map_err(|_| format!("Secret {} is not 32 hex-encoded bytes", secret_name))?;
+    Ok(Some(key))
+}
+
+fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .ok()?;
+    Some([&nonce[..], &ciphertext].concat())
+}
+
+fn unseal(key: &[u8], sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .ok()
+}
+
+fn encrypt_entry(master_key: &[u8; 32], key: &str, value: &[u8]) -> Option<String> {
+    let mut data_key = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut data_key);
+    let sealed = SealedToken {
+        wrapped_key: base64::encode(seal(master_key, &data_key, key.as_bytes())?),
+        ciphertext: base64::encode(seal(&data_key, value, key.as_bytes())?),
+    };
+    serde_json::to_string(&sealed).ok()
+}
+
+fn decrypt_entry(master_key: &[u8; 32], key: &str, value: &[u8]) -> Option<Vec<u8>> {
+    let sealed: SealedToken = serde_json::from_slice(value).ok()?;
+    let wrapped_key = base64::decode(sealed.wrapped_key).ok()?;
+    let data_key = unseal(master_key, &wrapped_key, key.as_bytes())?;
+    if data_key.len() != 32 {
+        return None;
+    }
+    let ciphertext = base64::decode(sealed.ciphertext).ok()?;
+    unseal(&data_key, &ciphertext, key.as_bytes())
+}
+
+fn lookup_token(store: &KVStore, key: &str, master_key: Option<&[u8; 32]>) -> Option<CachedToken> {
+    let value = match store.lookup(key) {
+        Ok(mut x) => x.take_body_bytes(),
+        Err(KVStoreError::ItemNotFound) => return None,
+        Err(e) => {
+            error!("Token cache lookup error: {}", e);
+            return None;
+        }
+    };
+    let value = match master_key {
+        Some(x) => decrypt_entry(x, key, &value)?,
+        None => value,
+    };
+    serde_json::from_slice(&value).ok()
+}
+
+// Caches `token` for `expires_in` seconds, as reported by the IdP.
+fn store_token(
+    store: &KVStore,
+    key: &str,
+    master_key: Option<&[u8; 32]>,
+    token: &str,
+    expires_in: u64,
+) {
+    let cached = CachedToken {
+        access_token: token.to_string(),
+        expires_at: OffsetDateTime::now_utc().unix_timestamp() + expires_in as i64,
+    };
+    let value = match serde_json::to_string(&cached) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Token cache encode error: {}", e);
+            return;
+        }
+    };
+    let value = match master_key {
+        Some(x) => match encrypt_entry(x, key, value.as_bytes()) {
+            Some(x) => x,
+            None => {
+                error!("Token cache encryption failed");
+                return;
+            }
+        },
+        None => value,
+    };
+    if let Err(e) = store
+        .build_insert()
+        .time_to_live(Duration::from_secs(expires_in))
+        .execute(key, value)
+    {
+        error!("Token cache insert error: {}", e);
+    }
+}
+
+// Takes the refresh lock for `key`. Only one request holds it at a time; a KV
+// error counts as holding it, so a broken lock never blocks refreshes.
+fn acquire_lock(store: &KVStore, key: &str) -> bool {
+    match store
+        .build_insert()
+        .mode(InsertMode::Add)
+        .time_to_live(Duration::from_secs(LOCK_TTL_SECS))
+        .execute(&format!("lock/{}", key), "")
+    {
+        Ok(()) => true,
+        Err(KVStoreError::ItemPreconditionFailed) => false,
+        Err(e) => {
+            error!("Token cache lock error: {}", e);
+            true
+        }
+    }
+}
+
+fn release_lock(store: &KVStore, key: &str) {
+    if let Err(e) = store.delete(&format!("lock/{}", key)) {
+        error!("Token cache unlock error: {}", e);
+    }
+}
+
+// Seconds left on the cached token of `account` for `scope`, without fetching
+// or refreshing it. None when no valid token is cached.
+pub fn cached_remaining(tomlfile: &Config, account: &str, scope: &str) -> Option<i64> {
+    let store = open_cache(tomlfile)?;
+    let master_key = encryption_key(tomlfile).ok()?;
+    let token = lookup_token(&store, &cache_key(account, scope), master_key.as_ref())?;
+    Some(token.remaining()).filter(|x| *x > 0)
+}
+
+// The cached token of `account` for `scope`, or a new one from `fetch`, which
+// returns the token and its lifetime in seconds. Only the request holding the
+// refresh lock calls `fetch`; the others keep using the old token while it is
+// still valid, or wait briefly for the new one.
+pub fn cached_token(
+    tomlfile: &Config,
+    account: &str,
+    scope: &str,
+    fetch: impl FnOnce() -> Result<(String, u64), Error>,
+) -> Result<String, Error> {
+    let store = match open_cache(tomlfile) {
+        Some(x) => x,
+        None => return Ok(fetch()?.0),
+    };
+    // Without its encryption key the cache is skipped rather than written in
+    // plaintext.
+    let master_key = match encryption_key(tomlfile) {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Token cache encryption key error: {}", e);
+            return Ok(fetch()?.0);
+        }
+    };
+    let master_key = master_key.as_ref();
+    let key = cache_key(account, scope);
+    let margin = refresh_margin(tomlfile);
+    let cached = lookup_token(&store, &key, master_key);
+    if let Some(x) = &cached {
+        if x.remaining() >= margin {
+            stats::cache_hit(stats::TOKEN_CACHE);
+            return Ok(x.access_token.clone());
+        }
+    }
+    if acquire_lock(&store, &key) {
+        let fetched = fetch();
+        if let Ok((token, expires_in)) = &fetched {
+            store_token(&store, &key, master_key, token, *expires_in);
+        }
+        release_lock(&store, &key);
+        stats::cache_miss(stats::TOKEN_CACHE);
+        return Ok(fetched?.0);
+    }
+    // Another request is refreshing; the old token is good until it expires.
+    if let Some(x) = cached {
+        if x.remaining() > 0 {
+            stats::cache_hit(stats::TOKEN_CACHE);
+            return Ok(x.access_token);
+        }
+    }
+    let wait_ms = tomlfile
+        .token_cache
+        .as_ref()
+        .and_then(|x| x.lock_wait_ms)
+        .unwrap_or(DEFAULT_LOCK_WAIT_MS);
+    for _ in 0..wait_ms / LOCK_POLL_MS {
+        std::thread::sleep(Duration::from_millis(LOCK_POLL_MS));
+        if let Some(x) = lookup_token(&store, &key, master_key) {
+            if x.remaining() > 0 {
+                stats::cache_hit(stats::TOKEN_CACHE);
+                return Ok(x.access_token);
+            }
+        }
+    }
+    stats::cache_miss(stats::TOKEN_CACHE);
+    Ok(fetch()?.0)
+}
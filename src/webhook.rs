@@ -0,0 +1,182 @@
+use crate::config::{Config, WebhookConfiguration};
+use crate::error::AppError;
+use anyhow::anyhow;
+use fastly::http::Method;
+use fastly::secret_store::SecretStore;
+use fastly::{Error, Request};
+use time::OffsetDateTime;
+
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature";
+const DEFAULT_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+const DEFAULT_TOLERANCE_SECS: i64 = 300;
+
+// Producers sign a POST with the shared secret from the `[webhook]` Secret
+// Store: `X-Signature: sha256=<hex HMAC-SHA256 of "{timestamp}.{body}">`,
+// with the Unix time in `X-Signature-Timestamp`. Signatures older or newer
+// than `tolerance_secs` are rejected, so a captured request can't be replayed
+// later.
+fn shared_secret(webhook: &WebhookConfiguration) -> Result<Vec<u8>, Error> {
+    let secret = SecretStore::open(&webhook.secret_store)?
+        .try_get(&webhook.secret)?
+        .ok_or_else(|| anyhow!("Secret {} not found", webhook.secret))?;
+    Ok(secret.try_plaintext()?.to_vec())
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn check_signature(
+    webhook: &WebhookConfiguration,
+    secret: &[u8],
+    signature: &str,
+    timestamp: &str,
+    body: &[u8],
+) -> Result<(), String> {
+    let signed_at: i64 = timestamp
+        .parse()
+        .map_err(|_| "Signature timestamp is not a Unix time".to_string())?;
+    let tolerance = webhook
+        .tolerance_secs
+        .map(|x| x as i64)
+        .unwrap_or(DEFAULT_TOLERANCE_SECS);
+    if (OffsetDateTime::now_utc().unix_timestamp() - signed_at).abs() > tolerance {
+        return Err("Signature timestamp is outside the tolerance".to_string());
+    }
+    let presented = signature
+        .strip_prefix("sha256=")
+        .and_then(|x| hex::decode(x).ok())
+        .ok_or_else(|| "Signature is not sha256=<hex>".to_string())?;
+    let mut message = format!("{}.", timestamp).into_bytes();
+    message.extend_from_slice(body);
+    let expected = hmac_sha256::HMAC::mac(&message, secret);
+    if !constant_time_eq(&presented, &expected) {
+        return Err("Signature does not match".to_string());
+    }
+    Ok(())
+}
+
+// Routing middleware for POSTs when `[webhook]` is configured. Returns whether
+// the request carried a valid signature; a bad one gets 401, as does a missing
+// one with `required = true`. The body is read and put back for the handler.
+pub fn verify_signature(tomlfile: &Config, req: &mut Request) -> Result<bool, Error> {
+    let webhook = match &tomlfile.webhook {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+    if req.get_method() != Method::POST {
+        return Ok(false);
+    }
+    let signature_header = webhook
+        .signature_header
+        .as_deref()
+        .unwrap_or(DEFAULT_SIGNATURE_HEADER);
+    let timestamp_header = webhook
+        .timestamp_header
+        .as_deref()
+        .unwrap_or(DEFAULT_TIMESTAMP_HEADER);
+    let signature = match req.get_header_str(signature_header) {
+        Some(x) => x.to_string(),
+        None if webhook.required.unwrap_or(false) => {
+            let msg = format!("Missing {} header", signature_header);
+            return Err(AppError::Unauthorized(msg).into());
+        }
+        None => return Ok(false),
+    };
+    let timestamp = match req.get_header_str(timestamp_header) {
+        Some(x) => x.to_string(),
+        None => {
+            let msg = format!("Missing {} header", timestamp_header);
+            return Err(AppError::Unauthorized(msg).into());
+        }
+    };
+    let secret = match shared_secret(webhook) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Webhook secret error: {}", e);
+            return Err(AppError::Config(msg).into());
+        }
+    };
+    let body = req.take_body_bytes();
+    if let Err(e) = check_signature(webhook, &secret, &signature, &timestamp, &body) {
+        return Err(AppError::Unauthorized(e.to_string()).into());
+    }
+    req.set_body(body);
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    fn webhook() -> WebhookConfiguration {
+        WebhookConfiguration {
+            secret_store: String::new(),
+            secret: String::new(),
+            required: None,
+            tolerance_secs: None,
+            signature_header: None,
+            timestamp_header: None,
+        }
+    }
+
+    fn sign(timestamp: &str, body: &[u8]) -> String {
+        let mut message = format!("{}.", timestamp).into_bytes();
+        message.extend_from_slice(body);
+        format!(
+            "sha256={}",
+            hex::encode(hmac_sha256::HMAC::mac(&message, SECRET))
+        )
+    }
+
+    fn now_offset(secs: i64) -> String {
+        (OffsetDateTime::now_utc().unix_timestamp() + secs).to_string()
+    }
+
+    #[test]
+    fn accepts_signatures_inside_the_window() {
+        for offset in [0, -290, 290] {
+            let timestamp = now_offset(offset);
+            let signature = sign(&timestamp, b"{}");
+            assert_eq!(
+                check_signature(&webhook(), SECRET, &signature, &timestamp, b"{}"),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_signatures_outside_the_window() {
+        for offset in [-310, 310] {
+            let timestamp = now_offset(offset);
+            let signature = sign(&timestamp, b"{}");
+            assert!(check_signature(&webhook(), SECRET, &signature, &timestamp, b"{}").is_err());
+        }
+        let webhook = WebhookConfiguration {
+            tolerance_secs: Some(10),
+            ..webhook()
+        };
+        let timestamp = now_offset(-60);
+        let signature = sign(&timestamp, b"{}");
+        assert!(check_signature(&webhook, SECRET, &signature, &timestamp, b"{}").is_err());
+    }
+
+    #[test]
+    fn rejects_other_bodies_and_malformed_signatures() {
+        let timestamp = now_offset(0);
+        let signature = sign(&timestamp, b"{}");
+        assert!(check_signature(&webhook(), SECRET, &signature, &timestamp, b"[]").is_err());
+        assert!(check_signature(&webhook(), b"other", &signature, &timestamp, b"{}").is_err());
+        assert!(check_signature(&webhook(), SECRET, "md5=00", &timestamp, b"{}").is_err());
+        assert!(check_signature(&webhook(), SECRET, &signature, "yesterday", b"{}").is_err());
+    }
+
+    #[test]
+    fn compares_in_constant_time() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}